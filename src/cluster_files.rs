@@ -0,0 +1,433 @@
+use aws_sdk_ec2::types;
+use aws_sdk_ec2::Client;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::shutdown;
+
+/// A single running node of a cluster, as seen through `describe_instances`.
+#[derive(Debug, Clone)]
+pub struct ClusterNode {
+    pub index: usize,
+    pub instance_id: String,
+    pub instance_type: String,
+    pub private_ip: String,
+    pub public_ip: Option<String>,
+    pub ipv6: Option<String>,
+    pub state: String,
+}
+
+impl ClusterNode {
+    /// The SSH host alias used for this node in the generated files (e.g. `node0`).
+    pub fn alias(&self) -> String {
+        format!("node{}", self.index)
+    }
+}
+
+/// Settings used when rendering the SSH config fragment for a cluster.
+#[derive(Debug, Clone)]
+pub struct SshSettings {
+    pub user: String,
+    pub identity_file: Option<PathBuf>,
+}
+
+/// Find all pending/running instances carrying the given project tag.
+///
+/// Nodes are ordered by their `node-index` tag (set by `create_cluster`) and then by private IPv4
+/// address, and numbered from zero, so the same cluster always produces the same `node0..nodeN`
+/// aliases. Instances without a private IP yet are skipped.
+///
+/// # Arguments
+/// * `aws_client` - The AWS client to use for the operation.
+/// * `project_tag` - Value of the `project` tag identifying the cluster.
+///
+/// # Returns
+/// * The nodes of the cluster, or errors.
+pub async fn describe_cluster_nodes(
+    aws_client: &Client,
+    project_tag: &str,
+) -> Result<Vec<ClusterNode>, Box<dyn std::error::Error>> {
+    let mut nodes = Vec::new();
+    let mut next_token: Option<String> = None;
+
+    loop {
+        let out = aws_client
+            .describe_instances()
+            .filters(
+                types::Filter::builder()
+                    .name("tag:project")
+                    .values(project_tag)
+                    .build(),
+            )
+            .filters(
+                types::Filter::builder()
+                    .name("instance-state-name")
+                    .values("pending")
+                    .values("running")
+                    .build(),
+            )
+            .set_next_token(next_token)
+            .send()
+            .await?;
+
+        for reservation in out.reservations() {
+            for instance in reservation.instances() {
+                let private_ip = match instance.private_ip_address() {
+                    Some(ip) => ip.to_string(),
+                    None => continue,
+                };
+
+                let launch_index = instance
+                    .tags()
+                    .iter()
+                    .find(|t| t.key() == Some(crate::sdk_wrapper::NODE_INDEX_TAG))
+                    .and_then(|t| t.value())
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(usize::MAX);
+
+                nodes.push(ClusterNode {
+                    index: launch_index,
+                    instance_id: instance.instance_id().unwrap_or_default().to_string(),
+                    instance_type: instance
+                        .instance_type()
+                        .map(|t| t.as_str().to_string())
+                        .unwrap_or_default(),
+                    private_ip,
+                    public_ip: instance.public_ip_address().map(|ip| ip.to_string()),
+                    ipv6: instance.ipv6_address().map(|ip| ip.to_string()),
+                    state: instance
+                        .state()
+                        .and_then(|s| s.name())
+                        .map(|s| s.as_str().to_string())
+                        .unwrap_or_default(),
+                });
+            }
+        }
+
+        next_token = out.next_token().map(|t| t.to_string());
+        if next_token.is_none() {
+            break;
+        }
+    }
+
+    // Sort by launch position if `create_cluster` recorded one, then numerically by private IP,
+    // so numbering is stable across calls
+    nodes.sort_by_key(|n| (n.index, n.private_ip.parse::<Ipv4Addr>().ok()));
+    for (i, node) in nodes.iter_mut().enumerate() {
+        node.index = i;
+    }
+
+    Ok(nodes)
+}
+
+/// Poll EC2 until at least `expected` nodes of the cluster are in the `running` state.
+///
+/// Public IPs are only assigned once an instance leaves `pending`, so the files generated right
+/// after `run_instances` returns would otherwise be missing addresses.
+pub async fn wait_for_cluster_nodes(
+    aws_client: &Client,
+    project_tag: &str,
+    expected: usize,
+    timeout: tokio::time::Duration,
+) -> Result<Vec<ClusterNode>, Box<dyn std::error::Error>> {
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        let nodes = describe_cluster_nodes(aws_client, project_tag).await?;
+        let ready = nodes.len() >= expected && nodes.iter().all(|n| n.state == "running");
+        if ready {
+            return Ok(nodes);
+        }
+
+        if tokio::time::Instant::now() >= deadline {
+            return Err(format!(
+                "Timed out waiting for {} node(s) of cluster {:?} to come up (saw {})",
+                expected,
+                project_tag,
+                nodes.len()
+            )
+            .into());
+        }
+
+        info!(
+            "Waiting for cluster {:?}: {}/{} node(s) visible...",
+            project_tag,
+            nodes.len(),
+            expected
+        );
+        shutdown::sleep(tokio::time::Duration::from_secs(5)).await?;
+    }
+}
+
+/// Work out how many MPI slots each node of the given instance type should get.
+///
+/// GPU instances get one slot per GPU (one rank per GPU is what NCCL runs want). Instances without
+/// GPUs fall back to the number of physical cores, matching what `user_data.sh` does on the nodes.
+pub async fn slots_per_node(
+    aws_client: &Client,
+    instance_type: &types::InstanceType,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let out = aws_client
+        .describe_instance_types()
+        .instance_types(instance_type.clone())
+        .send()
+        .await?;
+
+    let info = match out.instance_types().first() {
+        Some(info) => info,
+        None => {
+            return Err(format!(
+                "No instance type info returned for {}",
+                instance_type.as_str()
+            )
+            .into())
+        }
+    };
+
+    let gpus: i32 = info
+        .gpu_info()
+        .map(|gpu| gpu.gpus().iter().filter_map(|d| d.count()).sum())
+        .unwrap_or(0);
+    if gpus > 0 {
+        return Ok(gpus.try_into()?);
+    }
+
+    let cores = info
+        .v_cpu_info()
+        .and_then(|v| v.default_cores())
+        .unwrap_or(1);
+    Ok(cores.max(1).try_into()?)
+}
+
+/// Render an MPI hostfile (`<ip> slots=<n> # <instance type>` per line) using private IPs, with
+/// each node's slots at the same position in `slots`. The instance type is noted because nodes
+/// may have been launched as a fallback type.
+pub fn render_hostfile(nodes: &[ClusterNode], slots: &[u32]) -> String {
+    nodes
+        .iter()
+        .zip(slots)
+        .map(|(n, slots)| format!("{} slots={} # {}\n", n.private_ip, slots, n.instance_type))
+        .collect()
+}
+
+/// Render an OpenMPI `--host` style list (`ip:slots,ip:slots,...`).
+pub fn render_node_list(nodes: &[ClusterNode], slots: &[u32]) -> String {
+    let hosts: Vec<String> = nodes
+        .iter()
+        .zip(slots)
+        .map(|(n, slots)| format!("{}:{}", n.private_ip, slots))
+        .collect();
+    format!("{}\n", hosts.join(","))
+}
+
+/// Render a Slurm-style compressed node list over the SSH aliases (e.g. `node[0-15]`).
+pub fn render_slurm_node_list(nodes: &[ClusterNode]) -> String {
+    match nodes.len() {
+        0 => "\n".to_string(),
+        1 => format!("{}\n", nodes[0].alias()),
+        n => format!("node[0-{}]\n", n - 1),
+    }
+}
+
+/// Render an `~/.ssh/config` fragment with `node0..nodeN` host aliases.
+///
+/// Nodes with a public IPv4 address are reached directly. The others are reached through `node0`
+/// using `ProxyJump`, since the head node always has a public address, unless no node has a public
+/// IPv4 address (an IPv6-only cluster), in which case they are reached directly over IPv6.
+pub fn render_ssh_config(cluster_name: &str, nodes: &[ClusterNode], ssh: &SshSettings) -> String {
+    let mut out = format!("# Generated by aws_manager for cluster: {}\n", cluster_name);
+    let ipv6_only = nodes.iter().all(|n| n.public_ip.is_none());
+
+    for node in nodes {
+        out.push_str(&format!(
+            "\n# {} ({})\nHost {}\n",
+            node.instance_id,
+            node.instance_type,
+            node.alias()
+        ));
+        let ipv6 = node.ipv6.as_ref().filter(|_| ipv6_only);
+        match node.public_ip.as_ref().or(ipv6) {
+            Some(ip) => out.push_str(&format!("    HostName {}\n", ip)),
+            None => {
+                out.push_str(&format!("    HostName {}\n", node.private_ip));
+                if node.index != 0 {
+                    out.push_str("    ProxyJump node0\n");
+                }
+            }
+        }
+        out.push_str(&format!("    User {}\n", ssh.user));
+        if let Some(identity_file) = &ssh.identity_file {
+            out.push_str(&format!("    IdentityFile {}\n", identity_file.display()));
+        }
+        out.push_str("    StrictHostKeyChecking no\n");
+        out.push_str("    UserKnownHostsFile /dev/null\n");
+        out.push_str("    LogLevel ERROR\n");
+    }
+
+    out
+}
+
+/// Directory holding the generated files for a cluster (`<out_dir>/<cluster_name>`).
+pub fn cluster_dir(out_dir: &Path, cluster_name: &str) -> PathBuf {
+    out_dir.join(cluster_name)
+}
+
+/// Write the hostfile, node lists and SSH config for a cluster into `<out_dir>/<cluster_name>/`.
+///
+/// # Returns
+/// * The paths of the files that were written, or errors.
+pub fn write_cluster_files(
+    out_dir: &Path,
+    cluster_name: &str,
+    nodes: &[ClusterNode],
+    slots: &[u32],
+    ssh: &SshSettings,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let dir = cluster_dir(out_dir, cluster_name);
+    fs::create_dir_all(&dir)?;
+
+    let files = [
+        ("hostfile", render_hostfile(nodes, slots)),
+        ("nodelist", render_node_list(nodes, slots)),
+        ("slurm_nodelist", render_slurm_node_list(nodes)),
+        ("ssh_config", render_ssh_config(cluster_name, nodes, ssh)),
+    ];
+
+    let mut written = Vec::new();
+    for (name, contents) in files {
+        let path = dir.join(name);
+        fs::write(&path, contents)?;
+        written.push(path);
+    }
+
+    Ok(written)
+}
+
+/// Path of the generated SSH config for a cluster, usable with `ssh -F`.
+pub fn ssh_config_path(out_dir: &Path, cluster_name: &str) -> PathBuf {
+    cluster_dir(out_dir, cluster_name).join("ssh_config")
+}
+
+/// Look up the nodes of a cluster and write its generated files in one go.
+///
+/// # Returns
+/// * The nodes of the cluster and the paths of the files that were written, or errors.
+pub async fn generate_cluster_files(
+    aws_client: &Client,
+    cluster_name: &str,
+    out_dir: &Path,
+    ssh: &SshSettings,
+) -> Result<(Vec<ClusterNode>, Vec<PathBuf>), Box<dyn std::error::Error>> {
+    let nodes = describe_cluster_nodes(aws_client, cluster_name).await?;
+    if nodes.is_empty() {
+        return Err(format!("No running instances found for cluster {:?}", cluster_name).into());
+    }
+
+    // Nodes of different node groups can have different instance types, so look each type up once
+    let mut slots_by_type: Vec<(&str, u32)> = Vec::new();
+    let mut slots = Vec::new();
+    for node in &nodes {
+        let node_slots = match slots_by_type.iter().find(|(t, _)| *t == node.instance_type) {
+            Some((_, node_slots)) => *node_slots,
+            None => {
+                let instance_type = types::InstanceType::from(node.instance_type.as_str());
+                let node_slots = slots_per_node(aws_client, &instance_type).await?;
+                slots_by_type.push((&node.instance_type, node_slots));
+                node_slots
+            }
+        };
+        slots.push(node_slots);
+    }
+
+    let written = write_cluster_files(out_dir, cluster_name, &nodes, &slots, ssh)?;
+    Ok((nodes, written))
+}
+
+#[cfg(test)]
+fn test_nodes() -> Vec<ClusterNode> {
+    vec![
+        ClusterNode {
+            index: 0,
+            instance_id: "i-0".to_string(),
+            instance_type: "g5.2xlarge".to_string(),
+            private_ip: "10.0.0.4".to_string(),
+            public_ip: Some("54.1.2.3".to_string()),
+            ipv6: None,
+            state: "running".to_string(),
+        },
+        ClusterNode {
+            index: 1,
+            instance_id: "i-1".to_string(),
+            instance_type: "g5.4xlarge".to_string(),
+            private_ip: "10.0.0.12".to_string(),
+            public_ip: None,
+            ipv6: None,
+            state: "running".to_string(),
+        },
+    ]
+}
+
+#[test]
+fn test_render_hostfile_and_node_lists() {
+    let nodes = test_nodes();
+
+    assert_eq!(
+        render_hostfile(&nodes, &[4, 4]),
+        "10.0.0.4 slots=4 # g5.2xlarge\n10.0.0.12 slots=4 # g5.4xlarge\n"
+    );
+    assert_eq!(
+        render_node_list(&nodes, &[4, 4]),
+        "10.0.0.4:4,10.0.0.12:4\n"
+    );
+    assert_eq!(
+        render_hostfile(&nodes, &[2, 8]),
+        "10.0.0.4 slots=2 # g5.2xlarge\n10.0.0.12 slots=8 # g5.4xlarge\n"
+    );
+    assert_eq!(render_slurm_node_list(&nodes), "node[0-1]\n");
+    assert_eq!(render_slurm_node_list(&nodes[..1]), "node0\n");
+}
+
+#[test]
+fn test_render_ssh_config_uses_proxy_jump_for_private_nodes() {
+    let ssh = SshSettings {
+        user: "ec2-user".to_string(),
+        identity_file: Some(PathBuf::from("~/.ssh/key.pem")),
+    };
+    let config = render_ssh_config("test", &test_nodes(), &ssh);
+
+    assert!(config.contains("Host node0\n    HostName 54.1.2.3\n    User ec2-user\n"));
+    assert!(config.contains("Host node1\n    HostName 10.0.0.12\n    ProxyJump node0\n"));
+    assert!(config.contains("    IdentityFile ~/.ssh/key.pem\n"));
+}
+
+#[test]
+fn test_render_ssh_config_reaches_ipv6_only_nodes_directly() {
+    let ssh = SshSettings {
+        user: "ec2-user".to_string(),
+        identity_file: None,
+    };
+    let mut nodes = test_nodes();
+    nodes[0].public_ip = None;
+    nodes[0].ipv6 = Some("2600:1f14:abc:de00::10".to_string());
+    let config = render_ssh_config("test", &nodes, &ssh);
+
+    assert!(config.contains("Host node0\n    HostName 2600:1f14:abc:de00::10\n    User ec2-user\n"));
+    assert!(config.contains("Host node1\n    HostName 10.0.0.12\n    ProxyJump node0\n"));
+}
+
+#[test]
+fn test_render_ssh_config_jumps_to_dual_stack_workers() {
+    let ssh = SshSettings {
+        user: "ec2-user".to_string(),
+        identity_file: None,
+    };
+    let mut nodes = test_nodes();
+    nodes[0].ipv6 = Some("2600:1f14:abc:de00::10".to_string());
+    nodes[1].ipv6 = Some("2600:1f14:abc:de00::11".to_string());
+    let config = render_ssh_config("test", &nodes, &ssh);
+
+    assert!(config.contains("Host node0\n    HostName 54.1.2.3\n"));
+    assert!(config.contains("Host node1\n    HostName 10.0.0.12\n    ProxyJump node0\n"));
+}
@@ -0,0 +1,491 @@
+use aws_sdk_ec2::types;
+use aws_sdk_ec2::Client;
+use std::io::{IsTerminal, Read, Write};
+use std::net::Ipv4Addr;
+use std::path::Path;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::{IntoAlternateScreen, ToAlternateScreen, ToMainScreen};
+use termion::{clear, color, cursor, style};
+use tokio::io::unix::AsyncFd;
+
+use crate::cluster_files::{self, SshSettings};
+use crate::pricing::{self, PriceTable};
+use crate::sdk_wrapper;
+use crate::status;
+
+/// States shown on the dashboard; terminated instances drop off it.
+const SHOWN_STATES: [&str; 5] = ["pending", "running", "stopping", "stopped", "shutting-down"];
+
+/// One node of a cluster as last described, i.e. one row of the dashboard.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeStatus {
+    /// Launch position from the `node-index` tag, if `create_cluster` recorded one.
+    pub index: Option<usize>,
+    pub instance_id: String,
+    pub instance_type: String,
+    pub state: String,
+    pub availability_zone: String,
+    pub private_ip: Option<String>,
+    pub public_ip: Option<String>,
+    pub ipv6: Option<String>,
+    /// Instance and system status checks (e.g. `ok/ok`), or `-` while they aren't reported.
+    pub checks: String,
+    /// Hours since launch, while not stopped.
+    pub uptime_hours: Option<f64>,
+    /// On-demand price per hour, if known.
+    pub hourly: Option<f64>,
+}
+
+impl NodeStatus {
+    /// What the node has cost since launch at on-demand prices.
+    pub fn cost(&self) -> Option<f64> {
+        Some(self.hourly? * self.uptime_hours?)
+    }
+}
+
+/// Fill in the hourly price of each node from `prices`, if given.
+pub fn set_prices(nodes: &mut [NodeStatus], prices: Option<&PriceTable>) {
+    for node in nodes {
+        node.hourly = prices.and_then(|p| p.instance_hourly.get(&node.instance_type).copied());
+    }
+}
+
+/// Describe every non-terminated instance of a cluster, with its status checks (prices are filled
+/// in with `set_prices`).
+///
+/// Nodes are ordered like `cluster_files::describe_cluster_nodes` orders them, so the rows line up
+/// with the `node0..nodeN` aliases of the generated files while the cluster is running.
+pub async fn describe_node_status(
+    aws_client: &Client,
+    project_tag: &str,
+) -> Result<Vec<NodeStatus>, Box<dyn std::error::Error>> {
+    let mut nodes = Vec::new();
+    let mut next_token: Option<String> = None;
+
+    loop {
+        let out = aws_client
+            .describe_instances()
+            .filters(
+                types::Filter::builder()
+                    .name("tag:project")
+                    .values(project_tag)
+                    .build(),
+            )
+            .filters(
+                types::Filter::builder()
+                    .name("instance-state-name")
+                    .set_values(Some(SHOWN_STATES.iter().map(|s| s.to_string()).collect()))
+                    .build(),
+            )
+            .set_next_token(next_token)
+            .send()
+            .await?;
+
+        for instance in out.reservations().iter().flat_map(|r| r.instances()) {
+            let instance_type = instance
+                .instance_type()
+                .map(|t| t.as_str().to_string())
+                .unwrap_or_default();
+            let state = instance
+                .state()
+                .and_then(|s| s.name())
+                .map(|s| s.as_str().to_string())
+                .unwrap_or_default();
+            // A stopped instance isn't billed, and its launch time is that of its last start
+            let uptime_hours = match state.as_str() {
+                "stopped" => None,
+                _ => instance.launch_time().map(pricing::hours_since),
+            };
+            nodes.push(NodeStatus {
+                index: instance
+                    .tags()
+                    .iter()
+                    .find(|t| t.key() == Some(sdk_wrapper::NODE_INDEX_TAG))
+                    .and_then(|t| t.value())
+                    .and_then(|v| v.parse().ok()),
+                instance_id: instance.instance_id().unwrap_or_default().to_string(),
+                hourly: None,
+                instance_type,
+                state,
+                availability_zone: instance
+                    .placement()
+                    .and_then(|p| p.availability_zone())
+                    .unwrap_or_default()
+                    .to_string(),
+                private_ip: instance.private_ip_address().map(|ip| ip.to_string()),
+                public_ip: instance.public_ip_address().map(|ip| ip.to_string()),
+                ipv6: instance.ipv6_address().map(|ip| ip.to_string()),
+                checks: "-".to_string(),
+                uptime_hours,
+            });
+        }
+
+        next_token = out.next_token().map(|t| t.to_string());
+        if next_token.is_none() {
+            break;
+        }
+    }
+
+    // Status checks can be asked for at most 100 instances at a time
+    let ids: Vec<String> = nodes.iter().map(|n| n.instance_id.clone()).collect();
+    for chunk in ids.chunks(100) {
+        let out = aws_client
+            .describe_instance_status()
+            .set_instance_ids(Some(chunk.to_vec()))
+            .include_all_instances(true)
+            .send()
+            .await?;
+        for status in out.instance_statuses() {
+            let check = |summary: Option<&types::InstanceStatusSummary>| {
+                summary
+                    .and_then(|s| s.status())
+                    .map(|s| s.as_str().to_string())
+                    .unwrap_or_else(|| "-".to_string())
+            };
+            let checks = format!(
+                "{}/{}",
+                check(status.instance_status()),
+                check(status.system_status())
+            );
+            if let Some(node) = nodes
+                .iter_mut()
+                .find(|n| Some(n.instance_id.as_str()) == status.instance_id())
+            {
+                node.checks = checks;
+            }
+        }
+    }
+
+    nodes.sort_by_key(|n| {
+        (
+            n.index.unwrap_or(usize::MAX),
+            n.private_ip
+                .as_ref()
+                .and_then(|ip| ip.parse::<Ipv4Addr>().ok()),
+            n.instance_id.clone(),
+        )
+    });
+    Ok(nodes)
+}
+
+/// Format an uptime as e.g. `3d 04h`, `2h 05m` or `7m`.
+pub fn format_uptime(hours: f64) -> String {
+    let minutes = (hours * 60.0) as u64;
+    match (minutes / (24 * 60), minutes / 60 % 24, minutes % 60) {
+        (0, 0, m) => format!("{}m", m),
+        (0, h, m) => format!("{}h {:02}m", h, m),
+        (d, h, _) => format!("{}d {:02}h", d, h),
+    }
+}
+
+fn format_row(position: usize, node: &NodeStatus) -> String {
+    format!(
+        "{:<7} {:<20} {:<14} {:<14} {:<11} {:<15} {:<15} {:<18} {:>8} {:>9}",
+        format!("node{}", position),
+        node.instance_id,
+        node.instance_type,
+        node.state,
+        node.availability_zone,
+        node.private_ip.as_deref().unwrap_or("-"),
+        node.public_ip
+            .as_deref()
+            .or(node.ipv6.as_deref())
+            .unwrap_or("-"),
+        node.checks,
+        node.uptime_hours
+            .map(format_uptime)
+            .unwrap_or_else(|| "-".to_string()),
+        node.cost()
+            .map(|c| format!("${:.2}", c))
+            .unwrap_or_else(|| "-".to_string()),
+    )
+}
+
+/// The plain-text lines of the dashboard: a header, the column names and one row per node.
+pub fn render_lines(project_tag: &str, nodes: &[NodeStatus]) -> Vec<String> {
+    let running = nodes.iter().filter(|n| n.state == "running").count();
+    let hourly: f64 = nodes
+        .iter()
+        .filter(|n| n.state == "running")
+        .filter_map(|n| n.hourly)
+        .sum();
+    let spent: f64 = nodes.iter().filter_map(NodeStatus::cost).sum();
+
+    let mut lines = vec![
+        format!(
+            "Cluster {}: {} node(s), {} running, ${:.2}/h, ${:.2} so far",
+            project_tag,
+            nodes.len(),
+            running,
+            hourly,
+            spent
+        ),
+        String::new(),
+        format!(
+            "{:<7} {:<20} {:<14} {:<14} {:<11} {:<15} {:<15} {:<18} {:>8} {:>9}",
+            "NODE",
+            "INSTANCE",
+            "TYPE",
+            "STATE",
+            "AZ",
+            "PRIVATE IP",
+            "PUBLIC IP",
+            "CHECKS",
+            "UPTIME",
+            "COST"
+        ),
+    ];
+    lines.extend(
+        nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| format_row(i, node)),
+    );
+    lines
+}
+
+fn state_colour(state: &str) -> &'static dyn color::Color {
+    match state {
+        "running" => &color::Green,
+        "stopped" => &color::Red,
+        _ => &color::Yellow,
+    }
+}
+
+/// Number of lines before the first node row in `render_lines`.
+const HEADER_LINES: usize = 3;
+
+const KEY_HELP: &str = "up/down select   s ssh   t terminate   r refresh   q quit";
+
+fn draw(
+    out: &mut impl Write,
+    project_tag: &str,
+    nodes: &[NodeStatus],
+    selected: usize,
+    message: &str,
+) -> std::io::Result<()> {
+    let (width, height) = termion::terminal_size().unwrap_or((120, 40));
+    let fit = |line: &str| line.chars().take(width as usize).collect::<String>();
+
+    write!(out, "{}{}", clear::All, cursor::Goto(1, 1))?;
+    let lines = render_lines(project_tag, nodes);
+    let shown = (height as usize).saturating_sub(3);
+    for (i, line) in lines.iter().take(shown).enumerate() {
+        let line = fit(line);
+        if i < HEADER_LINES {
+            write!(out, "{}{}{}\r\n", style::Bold, line, style::Reset)?;
+            continue;
+        }
+        let node = &nodes[i - HEADER_LINES];
+        let highlight = if i - HEADER_LINES == selected {
+            format!("{}", style::Invert)
+        } else {
+            String::new()
+        };
+        write!(
+            out,
+            "{}{}{}{}\r\n",
+            color::Fg(state_colour(&node.state)),
+            highlight,
+            line,
+            style::Reset
+        )?;
+    }
+
+    write!(
+        out,
+        "{}{}{}",
+        cursor::Goto(1, height.saturating_sub(1).max(1)),
+        fit(message),
+        cursor::Goto(1, height)
+    )?;
+    write!(out, "{}{}{}", style::Faint, fit(KEY_HELP), style::Reset)?;
+    out.flush()
+}
+
+/// Open an interactive SSH session to a node, using the freshly regenerated cluster files.
+async fn ssh_to_node(
+    aws_client: &Client,
+    project_tag: &str,
+    out_dir: &Path,
+    ssh: &SshSettings,
+    instance_id: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let (nodes, _) =
+        cluster_files::generate_cluster_files(aws_client, project_tag, out_dir, ssh).await?;
+    let alias = match nodes.iter().find(|n| n.instance_id == instance_id) {
+        Some(node) => node.alias(),
+        None => return Err(format!("{} is not up yet", instance_id).into()),
+    };
+
+    let status = tokio::process::Command::new("ssh")
+        .arg("-F")
+        .arg(cluster_files::ssh_config_path(out_dir, project_tag))
+        .arg("-t")
+        .arg(&alias)
+        .status()
+        .await?;
+    Ok(format!(
+        "SSH session to {} ({}) ended: {}",
+        alias, instance_id, status
+    ))
+}
+
+/// Show a full-screen, live view of a cluster's nodes until `q` is pressed.
+///
+/// The view is refreshed from `describe_instances` every `interval`. The selected node can be
+/// SSHed into (`s` or Enter) or terminated (`t`, then `y` to confirm).
+pub async fn watch(
+    aws_client: &Client,
+    project_tag: &str,
+    out_dir: &Path,
+    ssh: &SshSettings,
+    interval: tokio::time::Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+        return Err("`watch` needs to run in a terminal".into());
+    }
+
+    // Costs are shown as "-" if prices can't be had. Prices are only loaded for the instance types
+    // there are at the start, before the terminal is taken over
+    let first = describe_node_status(aws_client, project_tag).await?;
+    let prices = status::try_load_prices(aws_client, &first).await;
+
+    let mut stdin = AsyncFd::new(std::io::stdin())?;
+    let mut screen =
+        cursor::HideCursor::from(std::io::stdout().into_raw_mode()?.into_alternate_screen()?);
+    let mut ticker = tokio::time::interval(interval);
+    let mut nodes: Vec<NodeStatus> = Vec::new();
+    let mut selected = 0;
+    let mut message = String::new();
+    let mut confirm_terminate: Option<String> = None;
+    let mut buf = vec![0u8; 8192];
+
+    'dashboard: loop {
+        let mut keys = Vec::new();
+        tokio::select! {
+            _ = ticker.tick() => {
+                match describe_node_status(aws_client, project_tag).await {
+                    Ok(described) => {
+                        nodes = described;
+                        set_prices(&mut nodes, prices.as_ref());
+                    }
+                    Err(e) => message = format!("Refresh failed: {}", e),
+                }
+            }
+            guard = stdin.readable() => {
+                let mut guard = guard?;
+                // A read at least as large as stdin's buffer goes straight to the terminal, so
+                // nothing is left buffered where readiness wouldn't report it
+                let n = guard.get_inner().lock().read(&mut buf)?;
+                guard.clear_ready();
+                if n == 0 {
+                    break;
+                }
+                keys.extend((&buf[..n]).keys().filter_map(|k| k.ok()));
+            }
+        }
+        selected = selected.min(nodes.len().saturating_sub(1));
+
+        for key in keys {
+            let current = nodes.get(selected).map(|n| n.instance_id.clone());
+
+            if let Some(instance_id) = confirm_terminate.take() {
+                message = if key == Key::Char('y') {
+                    match sdk_wrapper::terminate_instances(aws_client, vec![instance_id.clone()])
+                        .await
+                    {
+                        Ok(()) => format!("Terminating {}", instance_id),
+                        Err(e) => format!("Failed to terminate {}: {}", instance_id, e),
+                    }
+                } else {
+                    "Not terminating".to_string()
+                };
+                ticker.reset_immediately();
+                continue;
+            }
+
+            match key {
+                Key::Char('q') | Key::Esc | Key::Ctrl('c') => break 'dashboard,
+                Key::Up | Key::Char('k') => selected = selected.saturating_sub(1),
+                Key::Down | Key::Char('j') => {
+                    selected = (selected + 1).min(nodes.len().saturating_sub(1))
+                }
+                Key::Char('r') => ticker.reset_immediately(),
+                Key::Char('t') => {
+                    if let Some(instance_id) = current {
+                        message = format!("Terminate {}? Press y to confirm", instance_id);
+                        confirm_terminate = Some(instance_id);
+                    }
+                }
+                Key::Char('s') | Key::Char('\n') | Key::Char('\r') => {
+                    let instance_id = match current {
+                        Some(instance_id) => instance_id,
+                        None => continue,
+                    };
+                    write!(screen, "{}{}", ToMainScreen, cursor::Show)?;
+                    screen.flush()?;
+                    screen.suspend_raw_mode()?;
+
+                    message = match ssh_to_node(aws_client, project_tag, out_dir, ssh, &instance_id)
+                        .await
+                    {
+                        Ok(message) => message,
+                        Err(e) => format!("Could not SSH to {}: {}", instance_id, e),
+                    };
+
+                    screen.activate_raw_mode()?;
+                    write!(screen, "{}{}", ToAlternateScreen, cursor::Hide)?;
+                    // ssh read from the terminal too, so start over with a fresh readiness state
+                    stdin = AsyncFd::new(stdin.into_inner())?;
+                    ticker.reset_immediately();
+                }
+                _ => {}
+            }
+        }
+
+        draw(&mut screen, project_tag, &nodes, selected, &message)?;
+    }
+
+    write!(screen, "{}", cursor::Show)?;
+    screen.flush()?;
+    Ok(())
+}
+
+#[test]
+fn test_render_lines() {
+    let node = |index, state: &str, hourly| NodeStatus {
+        index: Some(index),
+        instance_id: format!("i-{}", index),
+        instance_type: "g5.2xlarge".to_string(),
+        state: state.to_string(),
+        availability_zone: "us-west-2a".to_string(),
+        private_ip: Some(format!("10.0.0.{}", index + 4)),
+        public_ip: None,
+        ipv6: None,
+        checks: "ok/ok".to_string(),
+        uptime_hours: Some(2.5),
+        hourly,
+    };
+    let nodes = vec![node(0, "running", Some(1.212)), node(1, "stopped", None)];
+    let lines = render_lines("test", &nodes);
+
+    assert_eq!(
+        lines[0],
+        "Cluster test: 2 node(s), 1 running, $1.21/h, $3.03 so far"
+    );
+    assert_eq!(lines.len(), HEADER_LINES + 2);
+    assert!(lines[3].starts_with("node0   i-0 "));
+    assert!(lines[3].contains(" 10.0.0.4 "));
+    assert!(lines[3].ends_with("2h 30m     $3.03"));
+    assert!(lines[4].ends_with("2h 30m         -"));
+}
+
+#[test]
+fn test_format_uptime() {
+    assert_eq!(format_uptime(0.1), "6m");
+    assert_eq!(format_uptime(2.5), "2h 30m");
+    assert_eq!(format_uptime(50.0), "2d 02h");
+}
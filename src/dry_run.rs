@@ -0,0 +1,108 @@
+use aws_sdk_ec2::error::{ProvideErrorMetadata, SdkError};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tracing::{error, info};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NEXT_PLACEHOLDER: AtomicU64 = AtomicU64::new(0);
+
+/// Turn dry-run mode on for the rest of the process.
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Whether mutating EC2 calls are sent with `dry_run(true)`.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// A stand-in ID (e.g. `vpc-dryrun3`) for a resource a dry-run call would have created, so the
+/// calls that depend on it can still be planned.
+pub fn placeholder(prefix: &str) -> String {
+    format!(
+        "{}-dryrun{}",
+        prefix,
+        NEXT_PLACEHOLDER.fetch_add(1, Ordering::SeqCst)
+    )
+}
+
+pub fn is_placeholder(id: &str) -> bool {
+    id.contains("-dryrun")
+}
+
+/// Whether an error from a dry-run call means the real call would have gone through.
+///
+/// EC2 answers `DryRunOperation` when the call is allowed. Calls referring to placeholders can also
+/// fail with `*.NotFound` / `*.Malformed` (`*.Unknown` for placement groups), which only means the
+/// earlier step they depend on didn't really happen.
+pub fn would_succeed(code: Option<&str>, ids: &[&str]) -> bool {
+    match code {
+        Some("DryRunOperation") => true,
+        Some(code)
+            if code.ends_with(".NotFound")
+                || code.ends_with(".Malformed")
+                || code.ends_with(".Unknown") =>
+        {
+            ids.iter().any(|id| is_placeholder(id))
+        }
+        _ => false,
+    }
+}
+
+/// Send a mutating call, built with `.dry_run(dry_run::enabled())`, and print it as a step of the
+/// plan when in dry-run mode.
+///
+/// # Arguments
+/// * `step` - Description of the call for the plan, e.g. `CreateSubnet 10.0.1.0/24 in us-west-2b`.
+/// * `ids` - IDs the call refers to, so failures caused by placeholders can be told apart.
+/// * `call` - The call itself.
+///
+/// # Returns
+/// * `Some(output)` when the call was really made, `None` when a dry run says it would succeed, or
+///   the error from EC2.
+pub async fn send<T, E>(
+    step: &str,
+    ids: &[&str],
+    call: impl std::future::Future<Output = Result<T, SdkError<E>>>,
+) -> Result<Option<T>, SdkError<E>>
+where
+    E: ProvideErrorMetadata,
+{
+    match call.await {
+        Ok(out) => Ok(Some(out)),
+        Err(e) if enabled() && would_succeed(e.code(), ids) => {
+            info!(dry_run = true, "{}", step);
+            Ok(None)
+        }
+        Err(e) => {
+            if enabled() {
+                error!(
+                    dry_run = true,
+                    code = e.code().unwrap_or("unknown error"),
+                    "{} would fail",
+                    step
+                );
+            }
+            Err(e)
+        }
+    }
+}
+
+#[test]
+fn test_would_succeed() {
+    let vpc = placeholder("vpc");
+    assert!(is_placeholder(&vpc));
+    assert!(!is_placeholder("vpc-0a1b2c3d"));
+
+    assert!(would_succeed(Some("DryRunOperation"), &[]));
+    assert!(would_succeed(Some("InvalidVpcID.NotFound"), &[&vpc]));
+    assert!(would_succeed(
+        Some("InvalidPlacementGroup.Unknown"),
+        &[&placeholder("pg")]
+    ));
+    assert!(!would_succeed(
+        Some("InvalidSubnetID.NotFound"),
+        &["subnet-005f41c66eb78bc89"]
+    ));
+    assert!(!would_succeed(Some("UnauthorizedOperation"), &[&vpc]));
+    assert!(!would_succeed(None, &[]));
+}
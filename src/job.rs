@@ -0,0 +1,328 @@
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use tracing::{error, info, warn};
+
+use crate::cluster_files::{self, ClusterNode, SshSettings};
+use crate::dry_run;
+use crate::preflight;
+use crate::pricing;
+use crate::remote;
+use crate::sdk_wrapper::{self, ClusterCleanup, VpcCleanup};
+use crate::shutdown;
+use crate::spec::RunSpec;
+use crate::teardown;
+use crate::transfer;
+use crate::user_data;
+
+/// Which nodes a job command runs on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunOn {
+    #[default]
+    Head,
+    All,
+}
+
+/// The job to run on a cluster (the `[job]` table of a spec file).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobSpec {
+    /// Shell command to run over SSH.
+    pub command: String,
+    #[serde(default)]
+    pub on: RunOn,
+    /// Remote paths to download from the nodes the command ran on once it finishes.
+    #[serde(default)]
+    pub results: Vec<String>,
+    /// Local directory under which a timestamped directory is created for the results.
+    #[serde(default = "default_results_dir")]
+    pub results_dir: PathBuf,
+    /// Command that must succeed on every node before the job starts.
+    #[serde(default = "default_ready_command")]
+    pub ready_command: String,
+    #[serde(default = "default_ready_timeout_secs")]
+    pub ready_timeout_secs: u64,
+}
+
+fn default_results_dir() -> PathBuf {
+    PathBuf::from("results")
+}
+
+fn default_ready_command() -> String {
+    "cloud-init status --wait > /dev/null".to_string()
+}
+
+fn default_ready_timeout_secs() -> u64 {
+    1800
+}
+
+/// Resources created for a job so far, torn down once the job is over no matter how it ended.
+#[derive(Debug, Default)]
+struct JobResources {
+    vpc: Option<VpcCleanup>,
+    cluster: Option<ClusterCleanup>,
+}
+
+/// Await `fut`, giving up early if an interrupt has been received.
+async fn interruptible<T>(
+    fut: impl std::future::Future<Output = Result<T, Box<dyn std::error::Error>>>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    tokio::select! {
+        res = fut => res,
+        _ = shutdown::wait() => Err("Interrupted".into()),
+    }
+}
+
+/// A filesystem-friendly UTC timestamp, e.g. `2024-03-14T15-09-26Z`.
+fn timestamp() -> String {
+    let now = aws_sdk_ec2::primitives::DateTime::from(std::time::SystemTime::now());
+    now.fmt(aws_sdk_ec2::primitives::DateTimeFormat::DateTime)
+        .unwrap_or_else(|_| now.secs().to_string())
+        .replace(':', "-")
+}
+
+/// Run a job: create a VPC and cluster, wait for the nodes, run the command, download the results
+/// and tear everything down again.
+///
+/// Teardown always happens, including when a step fails or the run is interrupted (see
+/// `shutdown::install_handler`). An interrupt during resource creation takes effect once the
+/// current create call returns, so that nothing is created without being recorded for teardown.
+pub async fn run(
+    aws_client: &aws_sdk_ec2::Client,
+    spec: &RunSpec,
+    out_dir: &Path,
+    ssh: &SshSettings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut resources = JobResources::default();
+    let outcome = run_steps(aws_client, spec, out_dir, ssh, &mut resources).await;
+    if let Err(e) = &outcome {
+        error!("Job failed: {}", e);
+    }
+
+    let teardown = teardown(aws_client, resources, spec.cluster.project_tag()).await;
+
+    outcome?;
+    teardown
+}
+
+async fn run_steps(
+    aws_client: &aws_sdk_ec2::Client,
+    spec: &RunSpec,
+    out_dir: &Path,
+    ssh: &SshSettings,
+    resources: &mut JobResources,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let cluster = &spec.cluster;
+    let job = &spec.job;
+    let project_tag = cluster.project_tag();
+
+    // Load the user data up front so a bad template fails before anything is created
+    let user_data_templates = user_data::load_templates(&cluster.user_data)?;
+    let user_data = user_data::combine_parts(&user_data_templates);
+    let group_user_data = cluster.load_group_user_data()?;
+
+    // Make sure the whole launch fits in the account's quotas before creating anything
+    preflight::check_quotas(
+        aws_client,
+        &preflight::Requirements {
+            instances: cluster.instance_counts(),
+            vpcs: 1,
+            internet_gateways: 1,
+            security_groups: 2,
+            elastic_ips: cluster.head_elastic_ip() as u32,
+        },
+    )
+    .await?;
+
+    // Show what the cluster will cost; not knowing the price shouldn't stop the launch though
+    if let Err(e) = pricing::print_estimate(aws_client, cluster).await {
+        warn!("Could not estimate the cost of the cluster: {}", e);
+    }
+
+    // Create the VPC
+    shutdown::check()?;
+    let expires_at = match cluster.ttl() {
+        Some(ttl) => Some(sdk_wrapper::expires_at(ttl)?),
+        None => None,
+    };
+    let (vpc_id, vpc_cleanup) = sdk_wrapper::create_vpc(
+        aws_client,
+        &cluster.name,
+        project_tag,
+        expires_at.as_deref(),
+    )
+    .await?;
+    resources.vpc = Some(vpc_cleanup.clone());
+    info!("Created VPC {} for job", vpc_id);
+
+    let subnet_id = match vpc_cleanup.subnet_id_for_az(&cluster.availability_zone) {
+        Some(id) => id,
+        None => {
+            return Err(format!(
+                "VPC has no subnet in availability zone {}",
+                cluster.availability_zone
+            )
+            .into())
+        }
+    };
+    let security_group_id = match vpc_cleanup.security_group_id() {
+        Some(id) => id,
+        None => return Err("VPC has no security group".into()),
+    };
+
+    // Create the cluster
+    shutdown::check()?;
+    let template = cluster.cluster_template(
+        subnet_id,
+        security_group_id,
+        Some(&user_data),
+        &group_user_data,
+    );
+    resources.cluster = Some(sdk_wrapper::create_cluster(aws_client, &template).await?);
+
+    // Nothing is running to wait for or run the job on, so go straight to the teardown plan
+    if dry_run::enabled() {
+        return Ok(());
+    }
+
+    // Wait until every node that launched is running and passes the readiness check (the launch
+    // policy may have gone ahead without some)
+    shutdown::check()?;
+    let launched = resources
+        .cluster
+        .as_ref()
+        .map_or(0, |cluster| cluster.instance_ids.len());
+    let nodes = interruptible(wait_until_ready(aws_client, spec, launched, out_dir, ssh)).await?;
+    let ssh_config = cluster_files::ssh_config_path(out_dir, project_tag);
+    let targets: Vec<ClusterNode> = match job.on {
+        RunOn::Head => nodes.into_iter().filter(|n| n.index == 0).collect(),
+        RunOn::All => nodes,
+    };
+
+    // Run the job
+    info!("Running job: {}", job.command);
+    let command = vec![job.command.clone()];
+    let results = interruptible(async {
+        Ok(remote::exec_on_nodes(&ssh_config, &targets, &command, targets.len()).await)
+    })
+    .await?;
+    let job_ok = remote::print_summary(&results);
+
+    // Download the results, even if the job failed (they often say why)
+    if !job.results.is_empty() {
+        let results_dir = job
+            .results_dir
+            .join(format!("{}-{}", cluster.name, timestamp()));
+        for remote_path in &job.results {
+            info!("Downloading {} into {}", remote_path, results_dir.display());
+            let pulled = interruptible(transfer::pull(
+                &ssh_config,
+                &targets,
+                remote_path,
+                &results_dir,
+                targets.len(),
+            ))
+            .await?;
+            remote::print_summary(&pulled);
+        }
+    }
+
+    if !job_ok {
+        return Err("Job command failed on some nodes".into());
+    }
+    Ok(())
+}
+
+/// Wait for `num_nodes` nodes to be running and reachable, and for `ready_command` to pass on each.
+async fn wait_until_ready(
+    aws_client: &aws_sdk_ec2::Client,
+    spec: &RunSpec,
+    num_nodes: usize,
+    out_dir: &Path,
+    ssh: &SshSettings,
+) -> Result<Vec<ClusterNode>, Box<dyn std::error::Error>> {
+    let project_tag = spec.cluster.project_tag();
+    let timeout = tokio::time::Duration::from_secs(spec.job.ready_timeout_secs);
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    cluster_files::wait_for_cluster_nodes(aws_client, project_tag, num_nodes, timeout).await?;
+    let (nodes, _) =
+        cluster_files::generate_cluster_files(aws_client, project_tag, out_dir, ssh).await?;
+    let ssh_config = cluster_files::ssh_config_path(out_dir, project_tag);
+
+    let command = vec![spec.job.ready_command.clone()];
+    loop {
+        let results = remote::exec_on_nodes(&ssh_config, &nodes, &command, nodes.len()).await;
+        if results.iter().all(|r| r.success()) {
+            info!("All {} node(s) are ready", nodes.len());
+            return Ok(nodes);
+        }
+
+        if tokio::time::Instant::now() >= deadline {
+            remote::print_summary(&results);
+            return Err("Timed out waiting for the nodes to become ready".into());
+        }
+        info!("Waiting for nodes to become ready...");
+        shutdown::sleep(tokio::time::Duration::from_secs(10)).await?;
+    }
+}
+
+/// Tear down whatever was created for the job, cluster first and then the VPC it lives in, and
+/// check that nothing tagged with the job's project is left.
+async fn teardown(
+    aws_client: &aws_sdk_ec2::Client,
+    resources: JobResources,
+    project_tag: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Try every part even if an earlier one failed, and report all of it at the end
+    let mut report = sdk_wrapper::CleanupReport::default();
+    if let Some(cluster_cleanup) = resources.cluster {
+        if let Err(e) = sdk_wrapper::cleanup_cluster(aws_client, cluster_cleanup.clone()).await {
+            error!(
+                "Failed to tear down cluster ({}). Still up: {:?}",
+                e, cluster_cleanup
+            );
+            report.add_error("cluster", e.as_ref());
+        }
+    }
+
+    if let Some(vpc_cleanup) = resources.vpc {
+        if let Err(e) = sdk_wrapper::cleanup_vpc(aws_client, vpc_cleanup.clone()).await {
+            error!(
+                "Failed to tear down VPC ({}). Still up: {:?}",
+                e, vpc_cleanup
+            );
+            report.add_error("VPC", e.as_ref());
+        }
+    }
+
+    // Catch anything that was created without being recorded
+    let inventory = teardown::Inventory {
+        project_tag: Some(project_tag.to_string()),
+        ..Default::default()
+    };
+    teardown::verify(aws_client, &inventory, &mut report).await;
+    report.into_result()?;
+
+    Ok(())
+}
+
+#[test]
+fn test_job_spec_defaults() {
+    let job: JobSpec = toml::from_str(r#"command = "nvidia-smi""#).unwrap();
+
+    assert_eq!(job.on, RunOn::Head);
+    assert_eq!(job.results_dir, PathBuf::from("results"));
+    assert!(job.results.is_empty());
+
+    let job: JobSpec = toml::from_str("command = \"hostname\"\non = \"all\"").unwrap();
+    assert_eq!(job.on, RunOn::All);
+}
+
+#[test]
+fn test_timestamp_is_path_friendly() {
+    let ts = timestamp();
+
+    assert!(!ts.contains(':'));
+    assert!(ts.ends_with('Z'));
+}
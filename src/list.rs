@@ -0,0 +1,429 @@
+use aws_sdk_ec2::config::Region;
+use aws_sdk_ec2::types;
+use aws_sdk_ec2::Client;
+use futures::stream::{self, StreamExt};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use tracing::{debug, warn};
+
+use crate::dashboard;
+use crate::output::SCHEMA_VERSION;
+use crate::plan;
+use crate::pricing;
+use crate::sdk_wrapper::EXPIRES_AT_TAG;
+
+/// How many regions are scanned at once.
+const PARALLEL_REGIONS: usize = 8;
+
+/// Kinds of resources `list` groups into clusters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    Instance,
+    Volume,
+    Vpc,
+    PlacementGroup,
+    ElasticIp,
+}
+
+/// A resource found in a region, tagged with the cluster it belongs to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resource {
+    pub cluster: String,
+    pub kind: ResourceKind,
+    pub instance_type: Option<String>,
+    pub running: bool,
+    /// Hours since the resource was created (or last launched), if EC2 says.
+    pub age_hours: Option<f64>,
+    pub expires_at: Option<String>,
+}
+
+/// One cluster in one region, as shown by `list`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ClusterSummary {
+    pub cluster: String,
+    pub region: String,
+    pub instances: usize,
+    pub running_instances: usize,
+    /// Number of instances of each type.
+    pub instance_types: BTreeMap<String, usize>,
+    pub volumes: usize,
+    pub vpcs: usize,
+    pub placement_groups: usize,
+    pub elastic_ips: usize,
+    /// Hours since the oldest instance or volume was created.
+    pub age_hours: Option<f64>,
+    /// Earliest `expires-at` tag on any of the resources.
+    pub expires_at: Option<String>,
+    /// Whether `apply` recorded a state file for the cluster in the output directory.
+    pub recorded: bool,
+}
+
+/// Output of `list`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClusterList {
+    pub schema_version: u32,
+    pub regions: Vec<String>,
+    pub clusters: Vec<ClusterSummary>,
+}
+
+impl ClusterList {
+    pub fn text(&self) -> Vec<String> {
+        let header = format!(
+            "{:<24} {:<15} {:>9} {:>7} {:>4} {:>6} {:>4} {:>8}  {:<20} TYPES",
+            "CLUSTER", "REGION", "INSTANCES", "VOLUMES", "VPCS", "GROUPS", "EIPS", "AGE", "EXPIRES"
+        );
+        let mut lines = vec![header];
+        for cluster in &self.clusters {
+            let types: Vec<String> = cluster
+                .instance_types
+                .iter()
+                .map(|(t, n)| format!("{} x {}", n, t))
+                .collect();
+            lines.push(format!(
+                "{:<24} {:<15} {:>9} {:>7} {:>4} {:>6} {:>4} {:>8}  {:<20} {}",
+                format!(
+                    "{}{}",
+                    cluster.cluster,
+                    if cluster.recorded { "" } else { " *" }
+                ),
+                cluster.region,
+                format!("{}/{}", cluster.running_instances, cluster.instances),
+                cluster.volumes,
+                cluster.vpcs,
+                cluster.placement_groups,
+                cluster.elastic_ips,
+                cluster
+                    .age_hours
+                    .map(dashboard::format_uptime)
+                    .unwrap_or_else(|| "-".to_string()),
+                cluster.expires_at.as_deref().unwrap_or("-"),
+                types.join(", ")
+            ));
+        }
+        lines.push(String::new());
+        lines.push(format!(
+            "{} cluster(s) in {} region(s); instances are running/total, * = no state file",
+            self.clusters.len(),
+            self.regions.len()
+        ));
+        lines
+    }
+}
+
+/// The cluster a resource belongs to: its `project` tag, or failing that its `Name` tag.
+pub fn cluster_of(tags: &[types::Tag]) -> Option<String> {
+    let tag = |key: &str| {
+        tags.iter()
+            .find(|t| t.key() == Some(key))
+            .and_then(|t| t.value())
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_string())
+    };
+    tag("project").or_else(|| tag("Name"))
+}
+
+fn expires_at_of(tags: &[types::Tag]) -> Option<String> {
+    tags.iter()
+        .find(|t| t.key() == Some(EXPIRES_AT_TAG))
+        .and_then(|t| t.value())
+        .map(|v| v.to_string())
+}
+
+/// Group the resources found in a region into per-cluster summaries, ordered by cluster name.
+pub fn summarize(region: &str, resources: &[Resource]) -> Vec<ClusterSummary> {
+    let mut clusters: BTreeMap<&str, ClusterSummary> = BTreeMap::new();
+    for resource in resources {
+        let summary = clusters
+            .entry(&resource.cluster)
+            .or_insert_with(|| ClusterSummary {
+                cluster: resource.cluster.clone(),
+                region: region.to_string(),
+                ..Default::default()
+            });
+        match resource.kind {
+            ResourceKind::Instance => {
+                summary.instances += 1;
+                if resource.running {
+                    summary.running_instances += 1;
+                }
+                if let Some(instance_type) = &resource.instance_type {
+                    *summary
+                        .instance_types
+                        .entry(instance_type.clone())
+                        .or_default() += 1;
+                }
+            }
+            ResourceKind::Volume => summary.volumes += 1,
+            ResourceKind::Vpc => summary.vpcs += 1,
+            ResourceKind::PlacementGroup => summary.placement_groups += 1,
+            ResourceKind::ElasticIp => summary.elastic_ips += 1,
+        }
+        if let Some(age) = resource.age_hours {
+            summary.age_hours = Some(summary.age_hours.map_or(age, |a| a.max(age)));
+        }
+        if let Some(expires_at) = &resource.expires_at {
+            if summary.expires_at.as_ref().is_none_or(|e| expires_at < e) {
+                summary.expires_at = Some(expires_at.clone());
+            }
+        }
+    }
+    clusters.into_values().collect()
+}
+
+/// Find every tagged instance, volume, VPC and placement group in the client's region.
+async fn region_resources(
+    aws_client: &Client,
+) -> Result<Vec<Resource>, Box<dyn std::error::Error>> {
+    let mut resources = Vec::new();
+
+    let mut next_token: Option<String> = None;
+    loop {
+        let out = aws_client
+            .describe_instances()
+            .filters(
+                types::Filter::builder()
+                    .name("instance-state-name")
+                    .values("pending")
+                    .values("running")
+                    .values("stopping")
+                    .values("stopped")
+                    .values("shutting-down")
+                    .build(),
+            )
+            .set_next_token(next_token)
+            .send()
+            .await?;
+        for instance in out.reservations().iter().flat_map(|r| r.instances()) {
+            if let Some(cluster) = cluster_of(instance.tags()) {
+                resources.push(Resource {
+                    cluster,
+                    kind: ResourceKind::Instance,
+                    instance_type: instance.instance_type().map(|t| t.as_str().to_string()),
+                    running: instance.state().and_then(|s| s.name())
+                        == Some(&types::InstanceStateName::Running),
+                    age_hours: instance.launch_time().map(pricing::hours_since),
+                    expires_at: expires_at_of(instance.tags()),
+                });
+            }
+        }
+        next_token = out.next_token().map(|t| t.to_string());
+        if next_token.is_none() {
+            break;
+        }
+    }
+
+    let mut next_token: Option<String> = None;
+    loop {
+        let out = aws_client
+            .describe_volumes()
+            .set_next_token(next_token)
+            .send()
+            .await?;
+        for volume in out.volumes() {
+            if let Some(cluster) = cluster_of(volume.tags()) {
+                resources.push(Resource {
+                    cluster,
+                    kind: ResourceKind::Volume,
+                    instance_type: None,
+                    running: false,
+                    age_hours: volume.create_time().map(pricing::hours_since),
+                    expires_at: expires_at_of(volume.tags()),
+                });
+            }
+        }
+        next_token = out.next_token().map(|t| t.to_string());
+        if next_token.is_none() {
+            break;
+        }
+    }
+
+    let vpcs = aws_client.describe_vpcs().send().await?;
+    for vpc in vpcs.vpcs() {
+        if let Some(cluster) = cluster_of(vpc.tags()) {
+            resources.push(Resource {
+                cluster,
+                kind: ResourceKind::Vpc,
+                instance_type: None,
+                running: false,
+                age_hours: None,
+                expires_at: expires_at_of(vpc.tags()),
+            });
+        }
+    }
+
+    let groups = aws_client.describe_placement_groups().send().await?;
+    for group in groups.placement_groups() {
+        if let Some(cluster) = cluster_of(group.tags()) {
+            resources.push(Resource {
+                cluster,
+                kind: ResourceKind::PlacementGroup,
+                instance_type: None,
+                running: false,
+                age_hours: None,
+                expires_at: expires_at_of(group.tags()),
+            });
+        }
+    }
+
+    let addresses = aws_client.describe_addresses().send().await?;
+    for address in addresses.addresses() {
+        if let Some(cluster) = cluster_of(address.tags()) {
+            resources.push(Resource {
+                cluster,
+                kind: ResourceKind::ElasticIp,
+                instance_type: None,
+                running: false,
+                age_hours: None,
+                expires_at: expires_at_of(address.tags()),
+            });
+        }
+    }
+
+    Ok(resources)
+}
+
+/// Names of the clusters with a state file under `out_dir` (see `plan::state_path`).
+fn recorded_clusters(out_dir: &Path) -> Vec<String> {
+    let entries = match fs::read_dir(out_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str().map(|s| s.to_string()))
+        .filter(|name| plan::state_path(out_dir, name).is_file())
+        .collect();
+    names.sort();
+    names
+}
+
+/// The regions to scan: the given ones, every region enabled for the account, or the client's.
+async fn regions_to_scan(
+    aws_client: &Client,
+    regions: &[String],
+    all_regions: bool,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    if all_regions {
+        let out = aws_client.describe_regions().send().await?;
+        let mut names: Vec<String> = out
+            .regions()
+            .iter()
+            .filter_map(|r| r.region_name())
+            .map(|r| r.to_string())
+            .collect();
+        names.sort();
+        return Ok(names);
+    }
+    if !regions.is_empty() {
+        return Ok(regions.to_vec());
+    }
+    Ok(vec![pricing::client_region(aws_client)?])
+}
+
+/// List the clusters in the given regions (see `regions_to_scan`), scanning regions concurrently.
+///
+/// Clusters with a state file under `out_dir` but nothing left in EC2 are listed too, under the
+/// client's region, so stale state stands out as much as forgotten resources do.
+pub async fn list_clusters(
+    aws_client: &Client,
+    regions: &[String],
+    all_regions: bool,
+    out_dir: &Path,
+) -> Result<ClusterList, Box<dyn std::error::Error>> {
+    let regions = regions_to_scan(aws_client, regions, all_regions).await?;
+
+    let scans: Vec<(String, Result<Vec<Resource>, String>)> =
+        stream::iter(regions.iter().map(|region| async move {
+            debug!("Scanning {}", region);
+            let config = aws_client
+                .config()
+                .to_builder()
+                .region(Region::new(region.clone()))
+                .build();
+            let found = region_resources(&Client::from_conf(config))
+                .await
+                .map_err(|e| e.to_string());
+            (region.clone(), found)
+        }))
+        .buffer_unordered(PARALLEL_REGIONS)
+        .collect()
+        .await;
+
+    let mut clusters = Vec::new();
+    for (region, found) in scans {
+        match found {
+            Ok(resources) => clusters.extend(summarize(&region, &resources)),
+            Err(e) => warn!("Could not scan {}: {}", region, e),
+        }
+    }
+
+    let home_region = pricing::client_region(aws_client).unwrap_or_default();
+    for name in recorded_clusters(out_dir) {
+        match clusters.iter_mut().find(|c| c.cluster == name) {
+            Some(cluster) => cluster.recorded = true,
+            None => clusters.push(ClusterSummary {
+                cluster: name,
+                region: home_region.clone(),
+                recorded: true,
+                ..Default::default()
+            }),
+        }
+    }
+    clusters.sort_by(|a, b| (&a.cluster, &a.region).cmp(&(&b.cluster, &b.region)));
+
+    Ok(ClusterList {
+        schema_version: SCHEMA_VERSION,
+        regions,
+        clusters,
+    })
+}
+
+#[test]
+fn test_summarize_groups_by_cluster() {
+    let resource = |cluster: &str, kind, instance_type: Option<&str>, age| Resource {
+        cluster: cluster.to_string(),
+        kind,
+        instance_type: instance_type.map(|t| t.to_string()),
+        running: instance_type.is_some(),
+        age_hours: age,
+        expires_at: None,
+    };
+    let resources = vec![
+        resource("b", ResourceKind::Vpc, None, None),
+        resource("a", ResourceKind::Instance, Some("g5.2xlarge"), Some(1.0)),
+        resource("a", ResourceKind::Instance, Some("g5.2xlarge"), Some(3.0)),
+        resource("a", ResourceKind::Instance, Some("c6i.xlarge"), Some(2.0)),
+        resource("a", ResourceKind::Volume, None, Some(4.0)),
+        resource("a", ResourceKind::PlacementGroup, None, None),
+    ];
+    let clusters = summarize("us-west-2", &resources);
+
+    assert_eq!(clusters.len(), 2);
+    assert_eq!(clusters[0].cluster, "a");
+    assert_eq!(clusters[0].instances, 3);
+    assert_eq!(clusters[0].running_instances, 3);
+    assert_eq!(clusters[0].instance_types["g5.2xlarge"], 2);
+    assert_eq!(clusters[0].volumes, 1);
+    assert_eq!(clusters[0].placement_groups, 1);
+    assert_eq!(clusters[0].age_hours, Some(4.0));
+    assert_eq!(clusters[1].cluster, "b");
+    assert_eq!(clusters[1].vpcs, 1);
+    assert_eq!(clusters[1].age_hours, None);
+}
+
+#[test]
+fn test_cluster_of_prefers_project_tag() {
+    let tag = |k: &str, v: &str| types::Tag::builder().key(k).value(v).build();
+
+    assert_eq!(
+        cluster_of(&[tag("Name", "vpc-name"), tag("project", "exp")]),
+        Some("exp".to_string())
+    );
+    assert_eq!(
+        cluster_of(&[tag("Name", "vpc-name")]),
+        Some("vpc-name".to_string())
+    );
+    assert_eq!(cluster_of(&[tag("project", "")]), None);
+}
@@ -0,0 +1,84 @@
+use std::io::IsTerminal;
+use tracing_subscriber::EnvFilter;
+
+/// Environment variable holding filter directives, e.g. `aws_manager::sdk_wrapper=debug,info`.
+pub const LOG_ENV: &str = "AWS_MANAGER_LOG";
+
+/// Target of the messages logged while tearing resources down, so they can be filtered on their
+/// own (e.g. `AWS_MANAGER_LOG=aws_manager::cleanup=warn`).
+pub const CLEANUP_TARGET: &str = "aws_manager::cleanup";
+
+/// Target of the messages about EC2 calls being retried (and given up on after retries), e.g.
+/// `AWS_MANAGER_LOG=aws_manager::retry=off,info` to hide them.
+pub const RETRY_TARGET: &str = "aws_manager::retry";
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines, coloured when stdout is a terminal
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// The filter directives to log with.
+///
+/// `level` (from `--log-level`) applies to this crate only, so turning it up doesn't also bring in
+/// the AWS SDK's own debug output; `AWS_MANAGER_LOG` takes full directives and wins over it.
+/// Without either, this crate logs at `info` and everything else at `warn`.
+pub fn directives(level: Option<&str>, env: Option<&str>) -> String {
+    match (env, level) {
+        (Some(env), _) if !env.trim().is_empty() => env.to_string(),
+        (_, Some(level)) => format!("warn,aws_manager={}", level),
+        _ => "warn,aws_manager=info".to_string(),
+    }
+}
+
+/// Install the global logger. Nothing is logged until this is called.
+///
+/// Logs go to stdout, or to stderr with `to_stderr` (when stdout carries JSON results).
+pub fn init(
+    level: Option<&str>,
+    format: LogFormat,
+    to_stderr: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let env = std::env::var(LOG_ENV).ok();
+    let filter = EnvFilter::try_new(directives(level, env.as_deref()))?;
+    let ansi = if to_stderr {
+        std::io::stderr().is_terminal()
+    } else {
+        std::io::stdout().is_terminal()
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(move || -> Box<dyn std::io::Write> {
+            if to_stderr {
+                Box::new(std::io::stderr())
+            } else {
+                Box::new(std::io::stdout())
+            }
+        });
+
+    match format {
+        LogFormat::Text => builder.with_ansi(ansi).try_init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(false)
+            .try_init(),
+    }
+    .map_err(|e| format!("Failed to set up logging: {}", e).into())
+}
+
+#[test]
+fn test_directives() {
+    assert_eq!(directives(None, None), "warn,aws_manager=info");
+    assert_eq!(directives(Some("debug"), None), "warn,aws_manager=debug");
+    assert_eq!(
+        directives(Some("debug"), Some("aws_manager::plan=trace")),
+        "aws_manager::plan=trace"
+    );
+    assert_eq!(directives(None, Some(" ")), "warn,aws_manager=info");
+}
@@ -1,68 +1,584 @@
-use base64::prelude::*;
+use clap::{Args, Parser, Subcommand};
+#[cfg(test)]
 use std::collections::HashMap;
-use std::fs;
-use std::path::Path;
+use std::path::PathBuf;
+use tracing::{info, warn};
 
+mod cluster_files;
+mod dashboard;
+mod dry_run;
+mod job;
+mod list;
+mod logging;
+mod output;
+mod plan;
+mod preflight;
+mod pricing;
+mod reap;
+mod remote;
+mod retry;
 mod sdk_wrapper;
+mod shutdown;
+mod spec;
+mod status;
+mod teardown;
+mod transfer;
+mod user_data;
+
+#[derive(Parser, Debug)]
+#[command(about = "Launch and manage experiment clusters on AWS EC2")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    files: ClusterFileArgs,
+
+    #[command(flatten)]
+    output: output::OutputArgs,
+
+    #[command(flatten)]
+    retry: retry::RetryPolicy,
+
+    /// Validate every mutating EC2 call with `DryRun` and print the plan instead of creating or
+    /// deleting anything
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Log level for this tool's own messages (`error`, `warn`, `info`, `debug` or `trace`);
+    /// `AWS_MANAGER_LOG` takes full filter directives and overrides this
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+
+    /// Format of log lines
+    #[arg(long, global = true, value_enum, default_value_t = logging::LogFormat::Text)]
+    log_format: logging::LogFormat,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Hunt for capacity across AZs until an instance launches (the default)
+    Launch {
+        /// User-data template(s) to render per node; several are combined into a multi-part MIME
+        /// document (defaults to the built-in `user_data.sh`)
+        #[arg(long)]
+        user_data: Vec<PathBuf>,
+
+        /// Tag the instance to expire after this many minutes (see `reap`)
+        #[arg(long)]
+        ttl_minutes: Option<u64>,
+
+        /// Acceptable instance type(s), in order of preference; each AZ tries them all before
+        /// moving on (defaults to g5.2xlarge)
+        #[arg(long = "instance-type")]
+        instance_types: Vec<String>,
+    },
+    /// (Re)generate the MPI hostfile, node lists and SSH config for a running cluster
+    Hosts {
+        /// Cluster to generate files for (the value of its `project` tag)
+        cluster: String,
+    },
+    /// Run a command over SSH on all (or some) nodes of a cluster
+    Exec {
+        /// Cluster to run the command on (the value of its `project` tag)
+        cluster: String,
+
+        /// Nodes to run on, e.g. `0,2,4-7` (defaults to all nodes)
+        #[arg(long)]
+        nodes: Option<String>,
+
+        /// Maximum number of nodes to run on concurrently
+        #[arg(long, default_value_t = 32)]
+        parallel: usize,
+
+        /// Command to run on each node
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Copy a local file or directory to the same path on every node of a cluster
+    Push {
+        /// Cluster to copy to (the value of its `project` tag)
+        cluster: String,
+
+        /// Local file or directory to copy
+        local: PathBuf,
+
+        /// Destination path on the nodes; if it is an existing directory, the copy goes inside it
+        /// (like `cp -r`)
+        remote: String,
+
+        /// Nodes to copy to, e.g. `0,2,4-7` (defaults to all nodes)
+        #[arg(long)]
+        nodes: Option<String>,
+
+        /// Maximum number of concurrent copies
+        #[arg(long, default_value_t = 32)]
+        parallel: usize,
+
+        /// Upload once to node0 and copy from there to the other nodes (needs ssh-agent)
+        #[arg(long)]
+        fan_out: bool,
+
+        /// Delete whatever is at the destination on each node first, so that the copy replaces it
+        #[arg(long)]
+        replace: bool,
+    },
+    /// Create a cluster from a spec file, run a job on it, download the results and tear it down
+    Run {
+        /// TOML spec file with `[cluster]` and `[job]` tables
+        spec: PathBuf,
+    },
+    /// Copy a file or directory from every node of a cluster into `<local>/<nodeN>/`
+    Pull {
+        /// Cluster to copy from (the value of its `project` tag)
+        cluster: String,
+
+        /// Path on the nodes to copy
+        remote: String,
+
+        /// Local directory to copy into
+        local: PathBuf,
+
+        /// Nodes to copy from, e.g. `0,2,4-7` (defaults to all nodes)
+        #[arg(long)]
+        nodes: Option<String>,
+
+        /// Maximum number of concurrent copies
+        #[arg(long, default_value_t = 32)]
+        parallel: usize,
+    },
+    /// Show what `apply` would add, change and destroy to bring a cluster in line with its spec
+    Plan {
+        /// TOML spec file with a `[cluster]` table
+        spec: PathBuf,
+    },
+    /// Carry out the plan last made by `plan` for a spec, if nothing changed since
+    Apply {
+        /// TOML spec file with a `[cluster]` table
+        spec: PathBuf,
+    },
+    /// Tear down every instance, volume and VPC whose `expires-at` tag is in the past
+    Reap,
+    /// Empty and delete a VPC: terminate its instances, delete its NAT gateways, endpoints and
+    /// interfaces, release its Elastic IPs, then remove the VPC and its network. VPCs without a
+    /// `project` tag were not made by this tool and are refused unless `--force` is given
+    DeleteVpc {
+        /// ID of the VPC, e.g. `vpc-0a1b2c3d`
+        vpc_id: String,
+
+        /// Delete the VPC even if it has no `project` tag
+        #[arg(long)]
+        force: bool,
+    },
+    /// List the clusters (by `project` or `Name` tag, and state files) and what they consist of
+    List {
+        /// Regions to scan, comma-separated (defaults to the configured region)
+        #[arg(long, value_delimiter = ',')]
+        regions: Vec<String>,
+
+        /// Scan every region enabled for the account
+        #[arg(long, conflicts_with = "regions")]
+        all_regions: bool,
+    },
+    /// Show the nodes of a cluster with their state, addresses, status checks and cost
+    Status {
+        /// Cluster to show (the value of its `project` tag)
+        cluster: String,
+    },
+    /// Show the nodes, VPCs and volumes of a cluster
+    Describe {
+        /// Cluster to describe (the value of its `project` tag)
+        cluster: String,
+    },
+    /// Show what a running cluster has cost so far at on-demand prices
+    Cost {
+        /// Cluster to report on (the value of its `project` tag)
+        cluster: String,
+    },
+    /// Show a live, full-screen view of a cluster's nodes, with keys to SSH into or terminate them
+    Watch {
+        /// Cluster to watch (the value of its `project` tag)
+        cluster: String,
+
+        /// Seconds between refreshes
+        #[arg(long, default_value_t = 5)]
+        interval: u64,
+    },
+}
+
+#[derive(Args, Debug, Clone)]
+struct ClusterFileArgs {
+    /// Directory to write per-cluster files (hostfile, nodelist, ssh_config) into
+    #[arg(long, global = true, default_value = "clusters")]
+    out_dir: PathBuf,
+
+    /// User to log into the nodes as
+    #[arg(long, global = true, default_value = "ec2-user")]
+    ssh_user: String,
+
+    /// Private key to log into the nodes with (defaults to `~/.ssh/<key name>.pem`)
+    #[arg(long, global = true)]
+    identity_file: Option<PathBuf>,
+}
+
+impl ClusterFileArgs {
+    fn ssh_settings(&self) -> cluster_files::SshSettings {
+        cluster_files::SshSettings {
+            user: self.ssh_user.clone(),
+            identity_file: Some(
+                self.identity_file.clone().unwrap_or_else(|| {
+                    PathBuf::from(format!("~/.ssh/{}.pem", sdk_wrapper::KEY_NAME))
+                }),
+            ),
+        }
+    }
+}
 
 #[allow(deprecated)]
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    logging::init(
+        cli.log_level.as_deref(),
+        cli.log_format,
+        cli.output.is_json(),
+    )?;
+    if cli.dry_run {
+        dry_run::enable();
+    }
+    retry::configure(cli.retry);
+
     // Set up AWS environment and client
     let shared_config = aws_config::load_from_env().await;
-    let client: aws_sdk_ec2::Client = aws_sdk_ec2::Client::new(&shared_config);
-
-    // Get user data from the script file and encode
-    let user_data: String = {
-        let user_data_path = Path::new("./src/user_data.sh");
-
-        match fs::read_to_string(user_data_path) {
-            Ok(data) => BASE64_STANDARD.encode(data.as_bytes()),
-            Err(err) => {
-                panic!(
-                    "Failed to read user data file at path {:#?}: {:#?}",
-                    user_data_path, err
-                )
+    let client: aws_sdk_ec2::Client = retry::client(&shared_config);
+
+    // Tear down whatever was created so far on Ctrl-C / SIGTERM instead of leaking it
+    shutdown::install_handler();
+
+    let files = cli.files;
+    let command = cli.command.unwrap_or(Command::Launch {
+        user_data: vec![],
+        ttl_minutes: None,
+        instance_types: vec![],
+    });
+    let result = run_command(&client, command, &files, &cli.output).await;
+
+    if shutdown::requested() {
+        shutdown::cleanup_tracked(&client).await?;
+        return Err("Interrupted".into());
+    }
+    result
+}
+
+async fn run_command(
+    client: &aws_sdk_ec2::Client,
+    command: Command,
+    files: &ClusterFileArgs,
+    output: &output::OutputArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Launch {
+            user_data,
+            ttl_minutes,
+            instance_types,
+        } => launch(client, &user_data, ttl_minutes, &instance_types, files).await,
+        Command::Hosts { cluster } => {
+            let (_, written) = cluster_files::generate_cluster_files(
+                client,
+                &cluster,
+                &files.out_dir,
+                &files.ssh_settings(),
+            )
+            .await?;
+            for path in written {
+                info!("Wrote {}", path.display());
+            }
+            Ok(())
+        }
+        Command::Exec {
+            cluster,
+            nodes,
+            parallel,
+            command,
+        } => {
+            let (selected, ssh_config) =
+                prepare_nodes(client, &cluster, nodes.as_deref(), files).await?;
+            let results = remote::exec_on_nodes(&ssh_config, &selected, &command, parallel).await;
+            if !remote::print_summary(&results) {
+                return Err(
+                    format!("Command failed on some nodes of cluster {:?}", cluster).into(),
+                );
             }
+            Ok(())
         }
+        Command::Push {
+            cluster,
+            local,
+            remote,
+            nodes,
+            parallel,
+            fan_out,
+            replace,
+        } => {
+            let (selected, ssh_config) =
+                prepare_nodes(client, &cluster, nodes.as_deref(), files).await?;
+            let results = transfer::push(
+                &ssh_config,
+                &selected,
+                &local,
+                &remote,
+                parallel,
+                fan_out,
+                replace,
+            )
+            .await?;
+            if !remote::print_summary(&results) {
+                return Err(format!("Push failed on some nodes of cluster {:?}", cluster).into());
+            }
+            Ok(())
+        }
+        Command::Run { spec } => {
+            let spec = spec::load_run_spec(&spec)?;
+            job::run(client, &spec, &files.out_dir, &files.ssh_settings()).await
+        }
+        Command::Pull {
+            cluster,
+            remote,
+            local,
+            nodes,
+            parallel,
+        } => {
+            let (selected, ssh_config) =
+                prepare_nodes(client, &cluster, nodes.as_deref(), files).await?;
+            let results = transfer::pull(&ssh_config, &selected, &remote, &local, parallel).await?;
+            if !remote::print_summary(&results) {
+                return Err(format!("Pull failed on some nodes of cluster {:?}", cluster).into());
+            }
+            Ok(())
+        }
+        Command::Plan { spec } => plan::plan(client, &spec, &files.out_dir).await,
+        Command::Apply { spec } => plan::apply(client, &spec, &files.out_dir).await,
+        Command::Reap => reap::reap(client).await,
+        Command::DeleteVpc { vpc_id, force } => {
+            match sdk_wrapper::vpc_project(client, &vpc_id).await? {
+                Some(project) => info!(vpc_id, project, "Deleting VPC"),
+                None if force => warn!(vpc_id, "Deleting a VPC without a project tag"),
+                None => {
+                    return Err(format!(
+                        "VPC {} has no project tag, so it was not created by this tool; pass \
+                         --force to delete it anyway",
+                        vpc_id
+                    )
+                    .into())
+                }
+            }
+            let vpc = sdk_wrapper::discover_vpc(client, &vpc_id).await?;
+            match sdk_wrapper::cleanup_vpc(client, vpc).await {
+                Ok(report) => output.print(&report, sdk_wrapper::CleanupReport::text),
+                Err(e) => {
+                    if let Some(report) = e.downcast_ref::<sdk_wrapper::CleanupReport>() {
+                        output.print(report, sdk_wrapper::CleanupReport::text)?;
+                    }
+                    Err(e)
+                }
+            }
+        }
+        Command::List {
+            regions,
+            all_regions,
+        } => {
+            let list = list::list_clusters(client, &regions, all_regions, &files.out_dir).await?;
+            output.print(&list, list::ClusterList::text)
+        }
+        Command::Status { cluster } => {
+            let status = status::cluster_status(client, &cluster).await?;
+            output.print(&status, status::ClusterStatus::text)
+        }
+        Command::Describe { cluster } => {
+            let description = status::describe_cluster(client, &cluster, &files.out_dir).await?;
+            output.print(&description, status::ClusterDescription::text)
+        }
+        Command::Cost { cluster } => {
+            let report = pricing::cost_report(client, &cluster).await?;
+            output.print(&report, pricing::CostReport::text)
+        }
+        Command::Watch { cluster, interval } => {
+            dashboard::watch(
+                client,
+                &cluster,
+                &files.out_dir,
+                &files.ssh_settings(),
+                tokio::time::Duration::from_secs(interval.max(1)),
+            )
+            .await
+        }
+    }
+}
+
+/// Refresh the generated files for a cluster and pick out the selected nodes.
+///
+/// # Returns
+/// * The selected nodes and the path of the cluster's SSH config, or errors.
+async fn prepare_nodes(
+    client: &aws_sdk_ec2::Client,
+    cluster: &str,
+    selection: Option<&str>,
+    files: &ClusterFileArgs,
+) -> Result<(Vec<cluster_files::ClusterNode>, PathBuf), Box<dyn std::error::Error>> {
+    // Regenerate the SSH config so it reflects the cluster as it is right now
+    let (all_nodes, _) = cluster_files::generate_cluster_files(
+        client,
+        cluster,
+        &files.out_dir,
+        &files.ssh_settings(),
+    )
+    .await?;
+    let selected = remote::select_nodes(&all_nodes, selection)?;
+
+    Ok((
+        selected,
+        cluster_files::ssh_config_path(&files.out_dir, cluster),
+    ))
+}
+
+/// Try each AZ in turn, and each acceptable instance type in it, until an instance launches,
+/// then write out the cluster files for it.
+async fn launch(
+    client: &aws_sdk_ec2::Client,
+    user_data_paths: &[PathBuf],
+    ttl_minutes: Option<u64>,
+    instance_types: &[String],
+    files: &ClusterFileArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let project_tag = "test";
+    let expires_at = match ttl_minutes {
+        Some(minutes) => Some(sdk_wrapper::expires_at(tokio::time::Duration::from_secs(
+            minutes * 60,
+        ))?),
+        None => None,
     };
 
+    // Render the user data for the (single) node being launched
+    let user_data_templates = user_data::load_templates(user_data_paths)?;
+    let user_data = user_data::render_for_node(
+        &user_data_templates,
+        &user_data::NodeVars {
+            node_index: 0,
+            batch: false,
+            cluster_size: 1,
+            cluster_name: project_tag,
+            head_node_ip: None,
+            shared_volume_device: None,
+        },
+    )?;
+
+    // Hunting for capacity is pointless if the instance doesn't fit in the vCPU quota
+    let mut instance_types = instance_types
+        .iter()
+        .map(|t| aws_sdk_ec2::types::InstanceType::from(t.as_str()));
+    let instance_type = instance_types
+        .next()
+        .unwrap_or(aws_sdk_ec2::types::InstanceType::G52xlarge);
+    let fallback_instance_types: Vec<_> = instance_types.collect();
+    preflight::check_quotas(
+        client,
+        &preflight::Requirements {
+            instances: vec![(
+                std::iter::once(&instance_type)
+                    .chain(&fallback_instance_types)
+                    .map(|t| t.as_str().to_string())
+                    .collect(),
+                1,
+            )],
+            ..Default::default()
+        },
+    )
+    .await?;
+
     // Attmempt to create instances by iterating through AZs
-    let azs = vec!["us-west-2a", "us-west-2b", "us-west-2c"];
+    let azs = ["us-west-2a", "us-west-2b", "us-west-2c"];
+    let mut launched = Vec::new();
     for i in 0..1024 {
+        shutdown::check()?;
+
         // Try an AZ
         let az = azs[i % 3];
-        println!("\n--- Attemtping to create instance in AZ: {} ---", az);
+        info!("Attempting to create instance in AZ: {}", az);
 
         // Set up template
         let template = sdk_wrapper::InstanceTemplate {
             availability_zone: az,
             ami_image_id: "ami-0c57248507328e2de", // AMI Name: sc24-nccl-experiments-v2
-            instance_type: aws_sdk_ec2::types::InstanceType::G52xlarge,
+            instance_type: instance_type.clone(),
+            fallback_instance_types: fallback_instance_types.clone(),
             subnet_id: "subnet-005f41c66eb78bc89",
             security_group_id: "sg-0fa33c632d08f14ea",
             user_data: Some(&user_data),
             num_ifaces: 4,
             use_efa: false,
-            project_tag: "test",
+            project_tag,
+            expires_at: expires_at.as_deref(),
+            shutdown_on_expiry: false,
+            ip_mode: sdk_wrapper::IpMode::Ipv4,
+            public_ip: sdk_wrapper::PublicIp::All,
+            role: sdk_wrapper::NodeRole::Head,
+            node_group: None,
+            placement_group: None,
         };
 
         // Try to create the instance(s)
-        match sdk_wrapper::create_instance_sdk(&client, &template).await {
-            Ok(_) => {
-                println!(
-                    "🎉🎉🎉🎉 Successfully created instance(s) in AZ: {} 🎉🎉🎉🎉",
+        match sdk_wrapper::create_instance_sdk(client, &template).await {
+            Ok(instances) => {
+                info!(
+                    "Successfully created {} instance(s) in AZ: {}",
+                    instances
+                        .first()
+                        .and_then(|i| i.instance_type())
+                        .map_or("", |t| t.as_str()),
                     az
                 );
+                launched = instances;
                 break;
             }
             Err(e) => {
-                println!("Failed to create instance(s) in AZ {}: {:#?}", az, e);
+                warn!("Failed to create instance(s) in AZ {}: {}", az, e);
             }
         }
 
-        // Sleep so as not to activate a rate limit
-        tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+        // Back off a little more after each round of AZs; throttled calls are already retried
+        // by the client
+        tokio::time::sleep(retry::policy().backoff((i / azs.len()) as u32 + 1)).await;
+    }
+
+    if launched.is_empty() {
+        return Err("Could not launch any instances in any AZ!".into());
+    }
+    if dry_run::enabled() {
+        return Ok(());
+    }
+
+    // The instance is meant to outlive this process, so an interrupt from here on must not tear it down
+    for instance_id in launched.iter().filter_map(|i| i.instance_id()) {
+        shutdown::untrack(instance_id);
+    }
+
+    // Wait for the nodes to come up so they have their IPs, then write the hostfiles and SSH config
+    cluster_files::wait_for_cluster_nodes(
+        client,
+        project_tag,
+        launched.len(),
+        tokio::time::Duration::from_secs(300),
+    )
+    .await?;
+    let (_, written) = cluster_files::generate_cluster_files(
+        client,
+        project_tag,
+        &files.out_dir,
+        &files.ssh_settings(),
+    )
+    .await?;
+    for path in written {
+        info!("Wrote {}", path.display());
     }
 
     Ok(())
@@ -72,7 +588,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 async fn setup_aws() -> Result<(), Box<dyn std::error::Error>> {
     // Set up AWS environment and client
     let shared_config = aws_config::load_from_env().await;
-    let client: aws_sdk_ec2::Client = aws_sdk_ec2::Client::new(&shared_config);
+    let client: aws_sdk_ec2::Client = retry::client(&shared_config);
 
     // Print info about the client
     print!("{:#?}", client.describe_instances().send().await?);
@@ -87,7 +603,7 @@ async fn setup_aws() -> Result<(), Box<dyn std::error::Error>> {
 async fn try_create_instance() -> Result<(), Box<dyn std::error::Error>> {
     // Set up AWS environment and client
     let shared_config = aws_config::load_from_env().await;
-    let client: aws_sdk_ec2::Client = aws_sdk_ec2::Client::new(&shared_config);
+    let client: aws_sdk_ec2::Client = retry::client(&shared_config);
 
     // Set up variables
     let availability_zone = "us-west-2a";
@@ -104,12 +620,20 @@ async fn try_create_instance() -> Result<(), Box<dyn std::error::Error>> {
         availability_zone: "us-west-2a",
         ami_image_id: "ami-07bff6261f14c3a45", // AMI Name: sc24-nccl-experiments-v2
         instance_type: aws_sdk_ec2::types::InstanceType::T2Micro,
+        fallback_instance_types: vec![],
         subnet_id: chosen_subnet,
-        security_group_id: security_group_id,
+        security_group_id,
         user_data: None,
         num_ifaces: 1,
         use_efa: false,
         project_tag: "testing_sdk",
+        expires_at: None,
+        shutdown_on_expiry: false,
+        ip_mode: sdk_wrapper::IpMode::Ipv4,
+        public_ip: sdk_wrapper::PublicIp::All,
+        role: sdk_wrapper::NodeRole::Head,
+        node_group: None,
+        placement_group: None,
     };
 
     // Try to create the instance(s)
@@ -137,7 +661,8 @@ async fn try_create_instance() -> Result<(), Box<dyn std::error::Error>> {
 async fn try_create_cluster() -> Result<(), Box<dyn std::error::Error>> {
     // Set up AWS environment and client
     let shared_config = aws_config::load_from_env().await;
-    let client: aws_sdk_ec2::Client = aws_sdk_ec2::Client::new(&shared_config);
+    let client: aws_sdk_ec2::Client = retry::client(&shared_config);
+    shutdown::install_handler();
 
     // Set up the cluster template
     let cluster_template = sdk_wrapper::ClusterTemplate {
@@ -147,16 +672,30 @@ async fn try_create_cluster() -> Result<(), Box<dyn std::error::Error>> {
             availability_zone: "us-west-2a",
             ami_image_id: "ami-07bff6261f14c3a45", // AMI Name: sc24-nccl-experiments-v2
             instance_type: aws_sdk_ec2::types::InstanceType::T2Micro,
+            fallback_instance_types: vec![],
             subnet_id: "subnet-005f41c66eb78bc89",
             security_group_id: "sg-0fa33c632d08f14ea",
             user_data: None,
             num_ifaces: 1,
             use_efa: false,
             project_tag: "testing_sdk",
+            expires_at: None,
+            shutdown_on_expiry: false,
+            ip_mode: sdk_wrapper::IpMode::Ipv4,
+            public_ip: sdk_wrapper::PublicIp::All,
+            role: sdk_wrapper::NodeRole::Head,
+            node_group: None,
+            placement_group: None,
         },
         attach_shared_ebs: false,
         shared_ebs_volume_size: None,
         project_tag: "testing_sdk",
+        ttl: None,
+        shutdown_on_expiry: false,
+        elastic_ip: false,
+        node_groups: Vec::new(),
+        launch_policy: sdk_wrapper::LaunchPolicy::All,
+        placement_group: false,
     };
     println!(
         "Will attempt to create a cluster with the following template: {:#?}",
@@ -168,6 +707,7 @@ async fn try_create_cluster() -> Result<(), Box<dyn std::error::Error>> {
         &client,
         cluster_template.cluster_name,
         cluster_template.project_tag,
+        None,
     )
     .await?;
     println!(
@@ -176,8 +716,24 @@ async fn try_create_cluster() -> Result<(), Box<dyn std::error::Error>> {
     );
 
     // Create the Cluster
-    // TODO: Implement
-    println!("[WARN] Cluster creation not yet implemented!");
+    let cluster_template = sdk_wrapper::ClusterTemplate {
+        instance_template: sdk_wrapper::InstanceTemplate {
+            subnet_id: vpc_cleanup
+                .subnet_id_for_az(cluster_template.instance_template.availability_zone)
+                .unwrap(),
+            security_group_id: vpc_cleanup.security_group_id().unwrap(),
+            ..cluster_template.instance_template.clone()
+        },
+        ..cluster_template
+    };
+    // Note: `create_cluster` rolls itself back on failure (or Ctrl-C), but the VPC is still ours to clean up
+    let cluster_cleanup = match sdk_wrapper::create_cluster(&client, &cluster_template).await {
+        Ok(val) => val,
+        Err(e) => {
+            sdk_wrapper::cleanup_vpc(&client, vpc_cleanup).await?;
+            return Err(e);
+        }
+    };
 
     // Wait a few seconds
     let wait_seconds = 15;
@@ -189,8 +745,7 @@ async fn try_create_cluster() -> Result<(), Box<dyn std::error::Error>> {
     println!("Done waiting. Will now tear down the cluster and associated entities.");
 
     // Tear down the cluster
-    // TODO: Implement
-    println!("[WARN] Cluster teardown not yet implemented!");
+    sdk_wrapper::cleanup_cluster(&client, cluster_cleanup).await?;
 
     // Tear down the VPC
     sdk_wrapper::cleanup_vpc(&client, vpc_cleanup).await?;
@@ -0,0 +1,93 @@
+use serde::Serialize;
+
+/// Version of the JSON documents printed by the read commands, bumped whenever a field is renamed
+/// or removed (adding fields doesn't change it).
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// How read commands (`status`, `describe`, `cost`, ...) print their results.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Tables for reading
+    #[default]
+    Text,
+    /// A JSON document with a stable schema
+    Json,
+}
+
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct OutputArgs {
+    /// Format of the results of read commands
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+
+    /// jq expression to run on the JSON results (implies `--output json`), e.g.
+    /// `.nodes[].private_ip`; string results are printed without quotes, like `jq -r`
+    #[arg(long, global = true)]
+    pub query: Option<String>,
+}
+
+impl OutputArgs {
+    /// Whether results are printed as JSON, in which case logs go to stderr to keep stdout clean.
+    pub fn is_json(&self) -> bool {
+        self.output == OutputFormat::Json || self.query.is_some()
+    }
+
+    /// Print a result: as JSON (filtered through `--query`, if given) or as the lines `text` makes.
+    pub fn print<T: Serialize>(
+        &self,
+        value: &T,
+        text: impl FnOnce(&T) -> Vec<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for line in self.render(value, text)? {
+            println!("{}", line);
+        }
+        Ok(())
+    }
+
+    fn render<T: Serialize>(
+        &self,
+        value: &T,
+        text: impl FnOnce(&T) -> Vec<String>,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        if let Some(query) = &self.query {
+            return run_query(query, &serde_json::to_string(value)?);
+        }
+        match self.output {
+            OutputFormat::Text => Ok(text(value)),
+            OutputFormat::Json => Ok(vec![serde_json::to_string_pretty(value)?]),
+        }
+    }
+}
+
+/// Run a jq expression on a JSON document, one line per result.
+pub fn run_query(query: &str, json: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let out = match jq_rs::run(query, json) {
+        Ok(out) => out,
+        Err(e) => return Err(format!("jq query {:?} failed: {}", query, e).into()),
+    };
+
+    // jq prints each result as compact JSON on its own line
+    Ok(out
+        .lines()
+        .map(|line| match serde_json::from_str(line) {
+            Ok(serde_json::Value::String(s)) => s,
+            _ => line.to_string(),
+        })
+        .collect())
+}
+
+#[test]
+fn test_query_output() {
+    let json = r#"{"nodes": [{"node": "node0", "private_ip": "10.0.0.4"}, {"node": "node1", "private_ip": null}]}"#;
+
+    assert_eq!(
+        run_query(".nodes[].private_ip", json).unwrap(),
+        vec!["10.0.0.4", "null"]
+    );
+    assert_eq!(run_query(".nodes | length", json).unwrap(), vec!["2"]);
+    assert_eq!(
+        run_query(".nodes[0]", json).unwrap(),
+        vec![r#"{"node":"node0","private_ip":"10.0.0.4"}"#]
+    );
+    assert!(run_query(".nodes[", json).is_err());
+}
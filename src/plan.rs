@@ -0,0 +1,1336 @@
+use aws_sdk_ec2::types;
+use aws_sdk_ec2::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+use crate::cluster_files;
+use crate::dry_run;
+use crate::preflight;
+use crate::pricing;
+use crate::sdk_wrapper::{self, ClusterCleanup, LaunchPolicy, VpcCleanup, NODE_INDEX_TAG};
+use crate::shutdown;
+use crate::spec::{self, ClusterSpec};
+use crate::user_data;
+
+/// What a cluster consisted of after the last `apply`, kept in `<out_dir>/<cluster>/state.json`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClusterState {
+    pub vpc: Option<VpcCleanup>,
+    pub cluster: ClusterCleanup,
+}
+
+/// A single ingress rule of a security group.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IngressRule {
+    pub protocol: String,
+    /// Port range, or `None` for all-traffic rules, which have no ports.
+    pub ports: Option<(i32, i32)>,
+    /// CIDR block (IPv4 or IPv6) or security group ID the traffic may come from.
+    pub source: String,
+}
+
+impl IngressRule {
+    /// Split an `IpPermission` into one rule per source.
+    fn from_permission(perm: &types::IpPermission) -> Vec<IngressRule> {
+        let protocol = perm.ip_protocol().unwrap_or("-1").to_string();
+        let ports = if protocol == "-1" {
+            None
+        } else {
+            Some((perm.from_port().unwrap_or(0), perm.to_port().unwrap_or(0)))
+        };
+
+        let sources = perm
+            .ip_ranges()
+            .iter()
+            .filter_map(|r| r.cidr_ip())
+            .chain(perm.ipv6_ranges().iter().filter_map(|r| r.cidr_ipv6()))
+            .chain(
+                perm.user_id_group_pairs()
+                    .iter()
+                    .filter_map(|p| p.group_id()),
+            );
+        sources
+            .map(|source| IngressRule {
+                protocol: protocol.clone(),
+                ports,
+                source: source.to_string(),
+            })
+            .collect()
+    }
+
+    fn to_permission(&self) -> types::IpPermission {
+        let mut perm = types::IpPermission::builder().ip_protocol(&self.protocol);
+        if let Some((from, to)) = self.ports {
+            perm = perm.from_port(from).to_port(to);
+        }
+        perm = if self.source.starts_with("sg-") {
+            perm.user_id_group_pairs(
+                types::UserIdGroupPair::builder()
+                    .group_id(&self.source)
+                    .build(),
+            )
+        } else if self.source.contains(':') {
+            perm.ipv6_ranges(types::Ipv6Range::builder().cidr_ipv6(&self.source).build())
+        } else {
+            perm.ip_ranges(types::IpRange::builder().cidr_ip(&self.source).build())
+        };
+        perm.build()
+    }
+
+    pub fn describe(&self) -> String {
+        let what = match (self.protocol.as_str(), self.ports) {
+            ("-1", _) | (_, None) => "all traffic".to_string(),
+            (protocol, Some((from, to))) if from == to => format!("{} {}", protocol, from),
+            (protocol, Some((from, to))) => format!("{} {}-{}", protocol, from, to),
+        };
+        format!("{} from {}", what, self.source)
+    }
+}
+
+/// The ingress rules of a cluster's security group, as set up by `create_vpc`: SSH from anywhere
+/// and all traffic between the nodes.
+pub fn desired_rules(group_id: &str) -> Vec<IngressRule> {
+    let ssh_from = |source: &str| IngressRule {
+        protocol: "tcp".to_string(),
+        ports: Some((22, 22)),
+        source: source.to_string(),
+    };
+    vec![
+        ssh_from("0.0.0.0/0"),
+        ssh_from("::/0"),
+        IngressRule {
+            protocol: "-1".to_string(),
+            ports: None,
+            source: group_id.to_string(),
+        },
+    ]
+}
+
+/// A VPC carrying the cluster's `project` tag.
+#[derive(Debug, Clone, Default)]
+pub struct LiveVpc {
+    pub vpc_id: String,
+    /// Subnet IDs and their availability zones.
+    pub subnets: Vec<(String, String)>,
+    /// The (non-default) security group and its ingress rules.
+    pub security_group: Option<(String, Vec<IngressRule>)>,
+}
+
+/// An instance carrying the cluster's `project` tag.
+#[derive(Debug, Clone, Default)]
+pub struct LiveInstance {
+    pub instance_id: String,
+    pub instance_type: String,
+    pub image_id: String,
+    pub availability_zone: String,
+    pub vpc_id: Option<String>,
+    pub node_index: Option<u64>,
+    pub private_ip: Option<String>,
+}
+
+/// A volume carrying the cluster's `project` tag.
+#[derive(Debug, Clone, Default)]
+pub struct LiveVolume {
+    pub volume_id: String,
+    pub size_gib: u64,
+    pub availability_zone: String,
+}
+
+/// Everything that currently exists in EC2 for a cluster.
+#[derive(Debug, Clone, Default)]
+pub struct LiveState {
+    pub vpcs: Vec<LiveVpc>,
+    pub instances: Vec<LiveInstance>,
+    pub volumes: Vec<LiveVolume>,
+}
+
+/// One step of a plan.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Change {
+    DestroyInstance {
+        instance_id: String,
+        reason: String,
+    },
+    DestroyVolume {
+        volume_id: String,
+        reason: String,
+    },
+    DestroyVpc {
+        vpc_id: String,
+        reason: String,
+    },
+    /// A new VPC with its subnets, internet gateway, route table and security group.
+    AddNetwork,
+    AuthorizeIngress {
+        group_id: String,
+        rule: IngressRule,
+    },
+    RevokeIngress {
+        group_id: String,
+        rule: IngressRule,
+    },
+    ResizeVolume {
+        volume_id: String,
+        from_gib: u64,
+        to_gib: u64,
+    },
+    AddVolume {
+        size_gib: u64,
+    },
+    AddInstances {
+        instance_type: String,
+        node_indices: Vec<u64>,
+    },
+}
+
+/// Whether a change adds, changes or destroys something.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Add,
+    Change,
+    Destroy,
+}
+
+impl Change {
+    pub fn action(&self) -> Action {
+        match self {
+            Change::AddNetwork | Change::AddVolume { .. } | Change::AddInstances { .. } => {
+                Action::Add
+            }
+            Change::AuthorizeIngress { .. }
+            | Change::RevokeIngress { .. }
+            | Change::ResizeVolume { .. } => Action::Change,
+            Change::DestroyInstance { .. }
+            | Change::DestroyVolume { .. }
+            | Change::DestroyVpc { .. } => Action::Destroy,
+        }
+    }
+
+    /// How many resources the change touches.
+    fn count(&self) -> usize {
+        match self {
+            Change::AddInstances { node_indices, .. } => node_indices.len(),
+            _ => 1,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Change::DestroyInstance {
+                instance_id,
+                reason,
+            } => format!("instance {} ({})", instance_id, reason),
+            Change::DestroyVolume { volume_id, reason } => {
+                format!("volume {} ({})", volume_id, reason)
+            }
+            Change::DestroyVpc { vpc_id, reason } => format!("VPC {} ({})", vpc_id, reason),
+            Change::AddNetwork => {
+                "VPC with subnets, internet gateway, route table and security group".to_string()
+            }
+            Change::AuthorizeIngress { group_id, rule } => {
+                format!("security group {}: allow {}", group_id, rule.describe())
+            }
+            Change::RevokeIngress { group_id, rule } => {
+                format!(
+                    "security group {}: stop allowing {}",
+                    group_id,
+                    rule.describe()
+                )
+            }
+            Change::ResizeVolume {
+                volume_id,
+                from_gib,
+                to_gib,
+            } => format!("volume {}: {} GiB -> {} GiB", volume_id, from_gib, to_gib),
+            Change::AddVolume { size_gib } => format!("shared io2 volume of {} GiB", size_gib),
+            Change::AddInstances {
+                instance_type,
+                node_indices,
+            } => format!(
+                "{} {} instance(s) (node(s) {})",
+                node_indices.len(),
+                instance_type,
+                node_indices
+                    .iter()
+                    .map(|i| i.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+/// The changes that bring a cluster in line with its spec, and what is kept as it is.
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    pub changes: Vec<Change>,
+    /// Differences between the recorded state and what is live, shown along with the plan.
+    pub notes: Vec<String>,
+    vpc_id: Option<String>,
+    volume_id: Option<String>,
+    /// The recorded Elastic IP of the head node, which a new head node takes over.
+    elastic_ip: Option<String>,
+    nodes: Vec<LiveInstance>,
+}
+
+/// What gets written to `<out_dir>/<cluster>/plan.json` for `apply` to pick up.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedPlan {
+    changes: Vec<Change>,
+}
+
+/// `"2 to add, 1 to change, 0 to destroy"`
+pub fn summary(changes: &[Change]) -> String {
+    let count = |action: Action| -> usize {
+        changes
+            .iter()
+            .filter(|c| c.action() == action)
+            .map(Change::count)
+            .sum()
+    };
+    format!(
+        "{} to add, {} to change, {} to destroy",
+        count(Action::Add),
+        count(Action::Change),
+        count(Action::Destroy)
+    )
+}
+
+/// Work out the changes that turn `live` into what `spec` describes.
+///
+/// The recorded state only breaks ties (which of several VPCs or volumes to keep) and produces
+/// notes about drift; what actually exists is taken from `live`. Changes come out in the order
+/// `apply` carries them out: instances, volumes and VPCs that go away first, then the network,
+/// then the volume, then new instances.
+pub fn diff(spec: &ClusterSpec, recorded: &ClusterState, live: &LiveState) -> Plan {
+    let mut plan = Plan::default();
+    let recorded_vpc = recorded.vpc.as_ref().and_then(|v| v.vpc_id());
+    let recorded_volume = recorded.cluster.volume_id.as_deref();
+    plan.elastic_ip = recorded.cluster.elastic_ip.clone();
+
+    // Drift since the last apply
+    if let Some(vpc_id) = recorded_vpc {
+        if !live.vpcs.iter().any(|v| v.vpc_id == vpc_id) {
+            plan.notes
+                .push(format!("Recorded VPC {} no longer exists", vpc_id));
+        }
+    }
+    for instance_id in &recorded.cluster.instance_ids {
+        if !live.instances.iter().any(|i| &i.instance_id == instance_id) {
+            plan.notes.push(format!(
+                "Recorded instance {} no longer exists",
+                instance_id
+            ));
+        }
+    }
+    if let Some(volume_id) = recorded_volume {
+        if !live.volumes.iter().any(|v| v.volume_id == volume_id) {
+            plan.notes
+                .push(format!("Recorded volume {} no longer exists", volume_id));
+        }
+    }
+
+    if spec.launch_policy != LaunchPolicy::All || spec.placement_group {
+        plan.notes.push(
+            "`launch_policy` and `placement_group` are only honoured by `run`; `apply` launches each \
+             missing node on its own and needs all of them to launch"
+                .to_string(),
+        );
+    }
+
+    // Keep one usable VPC, preferring the recorded one
+    let usable = |vpc: &LiveVpc| {
+        vpc.security_group.is_some()
+            && vpc
+                .subnets
+                .iter()
+                .any(|(_, az)| *az == spec.availability_zone)
+    };
+    let kept_vpc = live
+        .vpcs
+        .iter()
+        .filter(|v| usable(v))
+        .min_by_key(|v| Some(v.vpc_id.as_str()) != recorded_vpc);
+    plan.vpc_id = kept_vpc.map(|v| v.vpc_id.clone());
+
+    // Instances: keep those that match the spec, one per node index
+    let mut instances: Vec<&LiveInstance> = live.instances.iter().collect();
+    instances.sort_by_key(|i| (i.node_index.unwrap_or(u64::MAX), i.instance_id.clone()));
+    let mut taken = BTreeSet::new();
+    for instance in instances {
+        let reason = if instance.vpc_id != plan.vpc_id {
+            Some(format!(
+                "in {} rather than the cluster's VPC",
+                instance.vpc_id.as_deref().unwrap_or("no VPC")
+            ))
+        } else if let Some(wanted) = instance
+            .node_index
+            .map(|i| spec.acceptable_instance_types(i))
+            .filter(|wanted| !wanted.is_empty() && !wanted.contains(&&*instance.instance_type))
+        {
+            Some(format!(
+                "is {}, spec wants {}",
+                instance.instance_type,
+                wanted.join(" or ")
+            ))
+        } else if instance.image_id != spec.ami_image_id {
+            Some(format!(
+                "runs {}, spec wants {}",
+                instance.image_id, spec.ami_image_id
+            ))
+        } else if instance.availability_zone != spec.availability_zone {
+            Some(format!(
+                "is in {}, spec wants {}",
+                instance.availability_zone, spec.availability_zone
+            ))
+        } else {
+            match instance.node_index {
+                None => Some(format!("has no {} tag", NODE_INDEX_TAG)),
+                Some(i) if i >= spec.node_count() => Some(format!(
+                    "is node {} but the cluster has {} node(s)",
+                    i,
+                    spec.node_count()
+                )),
+                Some(i) if !taken.insert(i) => Some(format!("another instance is node {}", i)),
+                Some(_) => None,
+            }
+        };
+        match reason {
+            Some(reason) => plan.changes.push(Change::DestroyInstance {
+                instance_id: instance.instance_id.clone(),
+                reason,
+            }),
+            None => plan.nodes.push(instance.clone()),
+        }
+    }
+    if spec.elastic_ip
+        && plan.elastic_ip.is_none()
+        && plan.nodes.iter().any(|n| n.node_index == Some(0))
+    {
+        plan.notes.push(
+            "`elastic_ip` only takes effect when `apply` launches the head node; the current one \
+             keeps its address"
+                .to_string(),
+        );
+    }
+
+    // Volumes: keep one in the right AZ if the spec wants one, preferring the recorded one
+    let kept_volume = match spec.shared_ebs_volume_size {
+        Some(_) => live
+            .volumes
+            .iter()
+            .filter(|v| v.availability_zone == spec.availability_zone)
+            .min_by_key(|v| Some(v.volume_id.as_str()) != recorded_volume),
+        None => None,
+    };
+    for volume in &live.volumes {
+        if Some(&volume.volume_id) == kept_volume.map(|v| &v.volume_id) {
+            continue;
+        }
+        let reason = if spec.shared_ebs_volume_size.is_none() {
+            "spec has no shared volume".to_string()
+        } else if volume.availability_zone != spec.availability_zone {
+            format!("is in {}", volume.availability_zone)
+        } else {
+            "the cluster already has a shared volume".to_string()
+        };
+        plan.changes.push(Change::DestroyVolume {
+            volume_id: volume.volume_id.clone(),
+            reason,
+        });
+    }
+    let mut volume_changes = Vec::new();
+    match (spec.shared_ebs_volume_size, kept_volume) {
+        (Some(size_gib), None) => volume_changes.push(Change::AddVolume { size_gib }),
+        (Some(size_gib), Some(volume)) if volume.size_gib < size_gib => {
+            volume_changes.push(Change::ResizeVolume {
+                volume_id: volume.volume_id.clone(),
+                from_gib: volume.size_gib,
+                to_gib: size_gib,
+            });
+            plan.volume_id = Some(volume.volume_id.clone());
+        }
+        (Some(size_gib), Some(volume)) if volume.size_gib > size_gib => {
+            // Volumes can only grow, so a smaller one means a new volume
+            plan.changes.push(Change::DestroyVolume {
+                volume_id: volume.volume_id.clone(),
+                reason: format!(
+                    "is {} GiB, spec wants {} GiB and volumes can't shrink",
+                    volume.size_gib, size_gib
+                ),
+            });
+            volume_changes.push(Change::AddVolume { size_gib });
+        }
+        (_, volume) => plan.volume_id = volume.map(|v| v.volume_id.clone()),
+    }
+
+    // VPCs other than the one kept
+    for vpc in &live.vpcs {
+        if Some(&vpc.vpc_id) == plan.vpc_id.as_ref() {
+            continue;
+        }
+        let reason = if usable(vpc) {
+            "the cluster already has a VPC".to_string()
+        } else {
+            format!(
+                "has no subnet in {} or no security group",
+                spec.availability_zone
+            )
+        };
+        plan.changes.push(Change::DestroyVpc {
+            vpc_id: vpc.vpc_id.clone(),
+            reason,
+        });
+    }
+
+    // The network, or the rules of the security group of the one kept
+    match kept_vpc.and_then(|v| v.security_group.as_ref()) {
+        None => plan.changes.push(Change::AddNetwork),
+        Some((group_id, rules)) => {
+            let desired = desired_rules(group_id);
+            for rule in desired.iter().filter(|r| !rules.contains(r)) {
+                plan.changes.push(Change::AuthorizeIngress {
+                    group_id: group_id.clone(),
+                    rule: rule.clone(),
+                });
+            }
+            for rule in rules.iter().filter(|r| !desired.contains(r)) {
+                plan.changes.push(Change::RevokeIngress {
+                    group_id: group_id.clone(),
+                    rule: rule.clone(),
+                });
+            }
+        }
+    }
+
+    plan.changes.extend(volume_changes);
+
+    // One addition per instance type, in node order
+    let mut additions: Vec<(String, Vec<u64>)> = Vec::new();
+    for i in (0..spec.node_count()).filter(|i| !taken.contains(i)) {
+        let instance_type = spec.node_instance_type(i).unwrap_or_default();
+        match additions.iter_mut().find(|(t, _)| t == instance_type) {
+            Some((_, node_indices)) => node_indices.push(i),
+            None => additions.push((instance_type.to_string(), vec![i])),
+        }
+    }
+    for (instance_type, node_indices) in additions {
+        plan.changes.push(Change::AddInstances {
+            instance_type,
+            node_indices,
+        });
+    }
+
+    plan
+}
+
+fn project_filter(project_tag: &str) -> types::Filter {
+    types::Filter::builder()
+        .name("tag:project")
+        .values(project_tag)
+        .build()
+}
+
+/// Look up everything that exists in EC2 for a cluster, by its `project` tag.
+pub async fn live_state(
+    aws_client: &Client,
+    project_tag: &str,
+) -> Result<LiveState, Box<dyn std::error::Error>> {
+    let mut live = LiveState::default();
+
+    let vpcs = aws_client
+        .describe_vpcs()
+        .filters(project_filter(project_tag))
+        .send()
+        .await?;
+    for vpc_id in vpcs.vpcs().iter().filter_map(|v| v.vpc_id()) {
+        let vpc_filter = types::Filter::builder()
+            .name("vpc-id")
+            .values(vpc_id)
+            .build();
+        let subnets = aws_client
+            .describe_subnets()
+            .filters(vpc_filter.clone())
+            .send()
+            .await?;
+        let security_groups = aws_client
+            .describe_security_groups()
+            .filters(vpc_filter)
+            .send()
+            .await?;
+
+        live.vpcs.push(LiveVpc {
+            vpc_id: vpc_id.to_string(),
+            subnets: subnets
+                .subnets()
+                .iter()
+                .filter_map(|s| {
+                    Some((
+                        s.subnet_id()?.to_string(),
+                        s.availability_zone()?.to_string(),
+                    ))
+                })
+                .collect(),
+            security_group: security_groups
+                .security_groups()
+                .iter()
+                .filter(|sg| sg.group_name() != Some("default"))
+                .find_map(|sg| {
+                    Some((
+                        sg.group_id()?.to_string(),
+                        sg.ip_permissions()
+                            .iter()
+                            .flat_map(IngressRule::from_permission)
+                            .collect(),
+                    ))
+                }),
+        });
+    }
+
+    let mut next_token = None;
+    loop {
+        let out = aws_client
+            .describe_instances()
+            .filters(project_filter(project_tag))
+            .filters(
+                types::Filter::builder()
+                    .name("instance-state-name")
+                    .values("pending")
+                    .values("running")
+                    .values("stopping")
+                    .values("stopped")
+                    .build(),
+            )
+            .set_next_token(next_token)
+            .send()
+            .await?;
+
+        for instance in out.reservations().iter().flat_map(|r| r.instances()) {
+            live.instances.push(LiveInstance {
+                instance_id: instance.instance_id().unwrap_or_default().to_string(),
+                instance_type: instance
+                    .instance_type()
+                    .map(|t| t.as_str().to_string())
+                    .unwrap_or_default(),
+                image_id: instance.image_id().unwrap_or_default().to_string(),
+                availability_zone: instance
+                    .placement()
+                    .and_then(|p| p.availability_zone())
+                    .unwrap_or_default()
+                    .to_string(),
+                vpc_id: instance.vpc_id().map(|id| id.to_string()),
+                node_index: instance
+                    .tags()
+                    .iter()
+                    .find(|t| t.key() == Some(NODE_INDEX_TAG))
+                    .and_then(|t| t.value())
+                    .and_then(|v| v.parse().ok()),
+                private_ip: instance.private_ip_address().map(|ip| ip.to_string()),
+            });
+        }
+
+        next_token = out.next_token().map(|t| t.to_string());
+        if next_token.is_none() {
+            break;
+        }
+    }
+
+    let volumes = aws_client
+        .describe_volumes()
+        .filters(project_filter(project_tag))
+        .filters(
+            types::Filter::builder()
+                .name("status")
+                .values("creating")
+                .values("available")
+                .values("in-use")
+                .build(),
+        )
+        .send()
+        .await?;
+    for volume in volumes.volumes() {
+        live.volumes.push(LiveVolume {
+            volume_id: volume.volume_id().unwrap_or_default().to_string(),
+            size_gib: volume.size().unwrap_or(0) as u64,
+            availability_zone: volume.availability_zone().unwrap_or_default().to_string(),
+        });
+    }
+
+    Ok(live)
+}
+
+pub fn state_path(out_dir: &Path, cluster_name: &str) -> PathBuf {
+    cluster_files::cluster_dir(out_dir, cluster_name).join("state.json")
+}
+
+fn plan_path(out_dir: &Path, cluster_name: &str) -> PathBuf {
+    cluster_files::cluster_dir(out_dir, cluster_name).join("plan.json")
+}
+
+/// The recorded state of a cluster, or an empty one if it has never been applied.
+fn load_state(path: &Path) -> Result<ClusterState, Box<dyn std::error::Error>> {
+    if !path.exists() {
+        return Ok(ClusterState::default());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+fn save_json(path: &Path, value: &impl Serialize) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(value)?)?;
+    Ok(())
+}
+
+/// Compare a spec with the recorded state and what is live.
+async fn make_plan(
+    aws_client: &Client,
+    spec: &ClusterSpec,
+    out_dir: &Path,
+) -> Result<Plan, Box<dyn std::error::Error>> {
+    if spec.node_count() < 1 {
+        return Err("Number of instances must be at least 1!".into());
+    } else if spec.node_count() > 16 && spec.shared_ebs_volume_size.is_some() {
+        return Err("Cannot attach shared EBS volume to more than 16 instances!".into());
+    }
+
+    let recorded = load_state(&state_path(out_dir, &spec.name))?;
+    let live = live_state(aws_client, spec.project_tag()).await?;
+    Ok(diff(spec, &recorded, &live))
+}
+
+fn print_plan(plan: &Plan) {
+    for note in &plan.notes {
+        warn!("Note: {}", note);
+    }
+
+    if plan.changes.is_empty() {
+        info!("No changes, the cluster matches its spec");
+        return;
+    }
+
+    for change in &plan.changes {
+        let (action, symbol) = match change.action() {
+            Action::Add => ("add", "+ add    "),
+            Action::Change => ("change", "~ change "),
+            Action::Destroy => ("destroy", "- destroy"),
+        };
+        info!(action, "{} {}", symbol, change.describe());
+    }
+    info!("Plan: {}", summary(&plan.changes));
+}
+
+/// Show what `apply` would do to bring a cluster in line with its spec, and save the plan for it.
+pub async fn plan(
+    aws_client: &Client,
+    spec_path: &Path,
+    out_dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let spec = spec::load_cluster_spec(spec_path)?;
+    let plan = make_plan(aws_client, &spec, out_dir).await?;
+    print_plan(&plan);
+
+    let path = plan_path(out_dir, &spec.name);
+    save_json(
+        &path,
+        &SavedPlan {
+            changes: plan.changes,
+        },
+    )?;
+    info!(
+        "Saved plan to {}, run `apply {}` to carry it out",
+        path.display(),
+        spec_path.display()
+    );
+    Ok(())
+}
+
+/// Carry out the plan saved by `plan` for a spec.
+///
+/// The plan is made again first and has to come out exactly the same, so that `apply` never does
+/// anything that wasn't reviewed; if the cluster or the spec changed in between, it stops and asks
+/// for a new plan. On success the resulting state is recorded for the next `plan`.
+pub async fn apply(
+    aws_client: &Client,
+    spec_path: &Path,
+    out_dir: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let spec = spec::load_cluster_spec(spec_path)?;
+    let path = plan_path(out_dir, &spec.name);
+    let saved: SavedPlan = match fs::read_to_string(&path) {
+        Ok(text) => serde_json::from_str(&text)?,
+        Err(_) => {
+            return Err(format!(
+                "No saved plan at {}, run `plan {}` first",
+                path.display(),
+                spec_path.display()
+            )
+            .into())
+        }
+    };
+
+    let plan = make_plan(aws_client, &spec, out_dir).await?;
+    if plan.changes != saved.changes {
+        print_plan(&plan);
+        return Err(
+            "The cluster or its spec changed since the plan was made, run `plan` again".into(),
+        );
+    }
+    if plan.changes.is_empty() {
+        info!("Nothing to apply, the cluster matches its spec");
+        return Ok(());
+    }
+
+    info!("Applying plan: {}", summary(&plan.changes));
+    let adds_network = plan.changes.contains(&Change::AddNetwork) as u32;
+    let new_nodes: Vec<u64> = plan
+        .changes
+        .iter()
+        .flat_map(|c| match c {
+            Change::AddInstances { node_indices, .. } => node_indices.clone(),
+            _ => Vec::new(),
+        })
+        .collect();
+    let adds_nodes = !new_nodes.is_empty();
+    let adds_head = plan.changes.iter().any(
+        |c| matches!(c, Change::AddInstances { node_indices, .. } if node_indices.contains(&0)),
+    );
+    preflight::check_quotas(
+        aws_client,
+        &preflight::Requirements {
+            instances: spec.instance_counts_of(&new_nodes),
+            vpcs: adds_network,
+            internet_gateways: adds_network,
+            security_groups: 2 * adds_network,
+            elastic_ips: (spec.elastic_ip && adds_head && plan.elastic_ip.is_none()) as u32,
+        },
+    )
+    .await?;
+
+    // Show what the cluster will cost once nodes are added; an unknown price doesn't stop it
+    if adds_nodes {
+        if let Err(e) = pricing::print_estimate(aws_client, &spec).await {
+            warn!("Could not estimate the cost of the cluster: {}", e);
+        }
+    }
+
+    let state = execute(aws_client, &spec, &plan).await?;
+    if !dry_run::enabled() {
+        save_json(&state_path(out_dir, &spec.name), &state)?;
+        fs::remove_file(&path)?;
+    }
+
+    info!("Applied plan for {}: {}", spec.name, summary(&plan.changes));
+    Ok(())
+}
+
+/// Carry out the changes of a plan in order.
+///
+/// # Returns
+/// * The state of the cluster afterwards, or errors.
+async fn execute(
+    aws_client: &Client,
+    spec: &ClusterSpec,
+    plan: &Plan,
+) -> Result<ClusterState, Box<dyn std::error::Error>> {
+    let project_tag = spec.project_tag();
+    let expires_at = match spec.ttl() {
+        Some(ttl) => Some(sdk_wrapper::expires_at(ttl)?),
+        None => None,
+    };
+
+    // Take away what goes first, so nothing new ends up next to what it replaces
+    let doomed: Vec<String> = plan
+        .changes
+        .iter()
+        .filter_map(|c| match c {
+            Change::DestroyInstance { instance_id, .. } => Some(instance_id.clone()),
+            _ => None,
+        })
+        .collect();
+    if !doomed.is_empty() {
+        sdk_wrapper::cleanup_cluster(
+            aws_client,
+            ClusterCleanup {
+                instance_ids: doomed,
+                volume_id: None,
+                elastic_ip: None,
+                placement_group: None,
+                instance_types: BTreeMap::new(),
+            },
+        )
+        .await?;
+    }
+    for change in &plan.changes {
+        match change {
+            Change::DestroyVolume { volume_id, .. } => {
+                destroy_volume(aws_client, volume_id).await?
+            }
+            Change::DestroyVpc { vpc_id, .. } => {
+                let vpc = sdk_wrapper::discover_vpc(aws_client, vpc_id).await?;
+                sdk_wrapper::cleanup_vpc(aws_client, vpc).await?;
+            }
+            _ => {}
+        }
+    }
+
+    // Network
+    let vpc = if plan.changes.contains(&Change::AddNetwork) {
+        let (_, vpc) =
+            sdk_wrapper::create_vpc(aws_client, &spec.name, project_tag, expires_at.as_deref())
+                .await?;
+        vpc
+    } else {
+        match &plan.vpc_id {
+            Some(vpc_id) => sdk_wrapper::discover_vpc(aws_client, vpc_id).await?,
+            None => return Err("Plan has no VPC to put the cluster in".into()),
+        }
+    };
+    for change in &plan.changes {
+        match change {
+            Change::AuthorizeIngress { group_id, rule } => {
+                update_ingress(aws_client, group_id, rule, true).await?
+            }
+            Change::RevokeIngress { group_id, rule } => {
+                update_ingress(aws_client, group_id, rule, false).await?
+            }
+            _ => {}
+        }
+    }
+    let subnet_id = match vpc.subnet_id_for_az(&spec.availability_zone) {
+        Some(id) => id,
+        None => {
+            return Err(format!(
+                "VPC has no subnet in availability zone {}",
+                spec.availability_zone
+            )
+            .into())
+        }
+    };
+    let security_group_id = match vpc.security_group_id() {
+        Some(id) => id,
+        None => return Err("VPC has no security group".into()),
+    };
+
+    let user_data = user_data::combine_parts(&user_data::load_templates(&spec.user_data)?);
+    let group_user_data = spec.load_group_user_data()?;
+    let template = spec.cluster_template(
+        subnet_id,
+        security_group_id,
+        Some(&user_data),
+        &group_user_data,
+    );
+
+    // Shared volume
+    let mut volume_id = plan.volume_id.clone();
+    let mut new_volume = false;
+    for change in &plan.changes {
+        match change {
+            Change::ResizeVolume {
+                volume_id, to_gib, ..
+            } => resize_volume(aws_client, volume_id, *to_gib).await?,
+            Change::AddVolume { .. } => {
+                volume_id = Some(
+                    sdk_wrapper::create_shared_volume(aws_client, &template, expires_at.as_deref())
+                        .await?,
+                );
+                new_volume = true;
+            }
+            _ => {}
+        }
+    }
+
+    // Nodes, head node first if it is one of them
+    let mut head_node_ip = plan
+        .nodes
+        .iter()
+        .find(|n| n.node_index == Some(0))
+        .and_then(|n| n.private_ip.clone());
+    let mut new_nodes = ClusterCleanup::default();
+    let mut elastic_ip = plan.elastic_ip.clone();
+    for change in &plan.changes {
+        if let Change::AddInstances { node_indices, .. } = change {
+            let (head, rest): (Vec<u64>, Vec<u64>) = node_indices.iter().partition(|i| **i == 0);
+            if !head.is_empty() {
+                let launched = sdk_wrapper::launch_cluster_nodes(
+                    aws_client,
+                    &template,
+                    &head,
+                    head.len(),
+                    None,
+                    expires_at.as_deref(),
+                )
+                .await?;
+                head_node_ip = launched
+                    .first()
+                    .and_then(|i| i.private_ip_address())
+                    .map(|ip| ip.to_string());
+                new_nodes.add_instances(&launched);
+
+                // Give the new head node the cluster's Elastic IP, allocating one the first time
+                if spec.elastic_ip {
+                    let head_id = new_nodes.instance_ids[0].clone();
+                    let associated = match &elastic_ip {
+                        Some(allocation_id) => {
+                            sdk_wrapper::associate_elastic_ip(aws_client, allocation_id, &head_id)
+                                .await
+                        }
+                        None => match sdk_wrapper::allocate_elastic_ip(
+                            aws_client,
+                            project_tag,
+                            expires_at.as_deref(),
+                        )
+                        .await
+                        {
+                            Ok(allocation_id) => {
+                                new_nodes.elastic_ip = Some(allocation_id.clone());
+                                elastic_ip = Some(allocation_id.clone());
+                                sdk_wrapper::associate_elastic_ip(
+                                    aws_client,
+                                    &allocation_id,
+                                    &head_id,
+                                )
+                                .await
+                            }
+                            Err(e) => Err(e),
+                        },
+                    };
+                    if let Err(e) = associated {
+                        return Err(sdk_wrapper::roll_back(aws_client, new_nodes, e).await);
+                    }
+                }
+            }
+            match sdk_wrapper::launch_cluster_nodes(
+                aws_client,
+                &template,
+                &rest,
+                rest.len(),
+                head_node_ip.as_deref(),
+                expires_at.as_deref(),
+            )
+            .await
+            {
+                Ok(launched) => new_nodes.add_instances(&launched),
+                Err(e) => {
+                    return Err(sdk_wrapper::roll_back(aws_client, new_nodes, e).await);
+                }
+            }
+        }
+    }
+
+    let mut instance_ids: Vec<String> = plan.nodes.iter().map(|n| n.instance_id.clone()).collect();
+    instance_ids.extend(new_nodes.instance_ids.iter().cloned());
+    let mut instance_types: BTreeMap<String, String> = plan
+        .nodes
+        .iter()
+        .map(|n| (n.instance_id.clone(), n.instance_type.clone()))
+        .collect();
+    instance_types.extend(new_nodes.instance_types);
+
+    // A new volume goes on every node, an existing one only on the new nodes
+    if let Some(volume_id) = &volume_id {
+        let targets = if new_volume {
+            &instance_ids
+        } else {
+            &new_nodes.instance_ids
+        };
+        if !targets.is_empty() {
+            sdk_wrapper::attach_shared_volume(aws_client, volume_id, targets).await?;
+        }
+    }
+
+    // Everything applied is meant to outlive this process
+    for id in new_nodes
+        .instance_ids
+        .iter()
+        .map(|id| id.as_str())
+        .chain(volume_id.as_deref())
+        .chain(new_nodes.elastic_ip.as_deref())
+        .chain(vpc.vpc_id())
+    {
+        shutdown::untrack(id);
+    }
+
+    Ok(ClusterState {
+        vpc: Some(vpc.clone()),
+        cluster: ClusterCleanup {
+            instance_ids,
+            volume_id,
+            elastic_ip,
+            placement_group: None,
+            instance_types,
+        },
+    })
+}
+
+/// Add or remove an ingress rule of a security group.
+async fn update_ingress(
+    aws_client: &Client,
+    group_id: &str,
+    rule: &IngressRule,
+    authorize: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if authorize {
+        let call = aws_client
+            .authorize_security_group_ingress()
+            .dry_run(dry_run::enabled())
+            .group_id(group_id)
+            .ip_permissions(rule.to_permission())
+            .send();
+        let step = format!(
+            "AuthorizeSecurityGroupIngress {} on {}",
+            rule.describe(),
+            group_id
+        );
+        dry_run::send(&step, &[group_id], call).await?;
+    } else {
+        let call = aws_client
+            .revoke_security_group_ingress()
+            .dry_run(dry_run::enabled())
+            .group_id(group_id)
+            .ip_permissions(rule.to_permission())
+            .send();
+        let step = format!(
+            "RevokeSecurityGroupIngress {} on {}",
+            rule.describe(),
+            group_id
+        );
+        dry_run::send(&step, &[group_id], call).await?;
+    }
+    Ok(())
+}
+
+/// Grow a volume to the given size.
+async fn resize_volume(
+    aws_client: &Client,
+    volume_id: &str,
+    size_gib: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let call = aws_client
+        .modify_volume()
+        .dry_run(dry_run::enabled())
+        .volume_id(volume_id)
+        .size(size_gib.try_into()?)
+        .send();
+    let step = format!("ModifyVolume {} to {} GiB", volume_id, size_gib);
+    dry_run::send(&step, &[volume_id], call).await?;
+    Ok(())
+}
+
+/// Detach a volume from every instance it is still attached to, then delete it.
+async fn destroy_volume(
+    aws_client: &Client,
+    volume_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let volumes = aws_client
+        .describe_volumes()
+        .volume_ids(volume_id)
+        .send()
+        .await?;
+    for attachment in volumes.volumes().iter().flat_map(|v| v.attachments()) {
+        if let Some(instance_id) = attachment.instance_id() {
+            let call = aws_client
+                .detach_volume()
+                .dry_run(dry_run::enabled())
+                .volume_id(volume_id)
+                .instance_id(instance_id)
+                .send();
+            let step = format!("DetachVolume {} from {}", volume_id, instance_id);
+            dry_run::send(&step, &[volume_id, instance_id], call).await?;
+        }
+    }
+
+    while !dry_run::enabled() {
+        let volumes = aws_client
+            .describe_volumes()
+            .volume_ids(volume_id)
+            .send()
+            .await?;
+        let state = volumes.volumes().first().and_then(|v| v.state().cloned());
+        if state == Some(types::VolumeState::Available) {
+            break;
+        }
+        debug!("Waiting for volume {} to be detached...", volume_id);
+        shutdown::sleep(tokio::time::Duration::from_secs(5)).await?;
+    }
+
+    sdk_wrapper::delete_volume(aws_client, volume_id).await
+}
+
+#[cfg(test)]
+fn test_spec() -> ClusterSpec {
+    let text = r#"
+        [cluster]
+        name = "nccl"
+        num_instances = 3
+        availability_zone = "us-west-2a"
+        ami_image_id = "ami-1"
+        instance_type = "g5.2xlarge"
+        shared_ebs_volume_size = 100
+    "#;
+    spec::parse_cluster_spec(text, Path::new(".")).unwrap()
+}
+
+#[test]
+fn test_diff_creates_missing_cluster() {
+    let plan = diff(
+        &test_spec(),
+        &ClusterState::default(),
+        &LiveState::default(),
+    );
+
+    assert_eq!(
+        plan.changes,
+        vec![
+            Change::AddNetwork,
+            Change::AddVolume { size_gib: 100 },
+            Change::AddInstances {
+                instance_type: "g5.2xlarge".to_string(),
+                node_indices: vec![0, 1, 2],
+            },
+        ]
+    );
+    assert_eq!(
+        summary(&plan.changes),
+        "5 to add, 0 to change, 0 to destroy"
+    );
+}
+
+#[test]
+fn test_diff_against_existing_cluster() {
+    let node = |id: &str, instance_type: &str, index: u64| LiveInstance {
+        instance_id: id.to_string(),
+        instance_type: instance_type.to_string(),
+        image_id: "ami-1".to_string(),
+        availability_zone: "us-west-2a".to_string(),
+        vpc_id: Some("vpc-1".to_string()),
+        node_index: Some(index),
+        private_ip: Some(format!("10.0.0.{}", index + 10)),
+    };
+    let mut rules = desired_rules("sg-1");
+    rules.remove(1);
+    rules.push(IngressRule {
+        protocol: "tcp".to_string(),
+        ports: Some((80, 80)),
+        source: "0.0.0.0/0".to_string(),
+    });
+    let live = LiveState {
+        vpcs: vec![LiveVpc {
+            vpc_id: "vpc-1".to_string(),
+            subnets: vec![("subnet-1".to_string(), "us-west-2a".to_string())],
+            security_group: Some(("sg-1".to_string(), rules)),
+        }],
+        instances: vec![
+            node("i-0", "g5.2xlarge", 0),
+            node("i-1", "t2.micro", 1),
+            node("i-4", "g5.2xlarge", 4),
+        ],
+        volumes: vec![LiveVolume {
+            volume_id: "vol-1".to_string(),
+            size_gib: 50,
+            availability_zone: "us-west-2a".to_string(),
+        }],
+    };
+    let recorded = ClusterState {
+        vpc: None,
+        cluster: ClusterCleanup {
+            instance_ids: vec!["i-0".to_string(), "i-gone".to_string()],
+            volume_id: Some("vol-1".to_string()),
+            elastic_ip: None,
+            placement_group: None,
+            instance_types: BTreeMap::new(),
+        },
+    };
+
+    let plan = diff(&test_spec(), &recorded, &live);
+    let kinds: Vec<String> = plan
+        .changes
+        .iter()
+        .map(|c| format!("{:?} {}", c.action(), c.describe()))
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            "Destroy instance i-1 (is t2.micro, spec wants g5.2xlarge)",
+            "Destroy instance i-4 (is node 4 but the cluster has 3 node(s))",
+            "Change security group sg-1: allow tcp 22 from ::/0",
+            "Change security group sg-1: stop allowing tcp 80 from 0.0.0.0/0",
+            "Change volume vol-1: 50 GiB -> 100 GiB",
+            "Add 2 g5.2xlarge instance(s) (node(s) 1, 2)",
+        ]
+    );
+    assert_eq!(
+        plan.notes,
+        vec!["Recorded instance i-gone no longer exists"]
+    );
+    assert_eq!(plan.vpc_id.as_deref(), Some("vpc-1"));
+    assert_eq!(plan.nodes.len(), 1);
+}
+
+#[test]
+fn test_diff_with_node_groups() {
+    let text = r#"
+        [cluster]
+        name = "mixed"
+        availability_zone = "us-west-2a"
+        ami_image_id = "ami-1"
+
+        [[cluster.node_groups]]
+        name = "head"
+        count = 1
+        instance_type = "c6i.xlarge"
+
+        [[cluster.node_groups]]
+        name = "gpu"
+        count = 3
+        instance_type = "p4d.24xlarge"
+    "#;
+    let spec = spec::parse_cluster_spec(text, Path::new(".")).unwrap();
+    let live = LiveState {
+        instances: vec![LiveInstance {
+            instance_id: "i-0".to_string(),
+            instance_type: "p4d.24xlarge".to_string(),
+            image_id: "ami-1".to_string(),
+            availability_zone: "us-west-2a".to_string(),
+            vpc_id: None,
+            node_index: Some(0),
+            private_ip: None,
+        }],
+        ..LiveState::default()
+    };
+
+    let plan = diff(&spec, &ClusterState::default(), &live);
+    assert_eq!(
+        plan.changes,
+        vec![
+            Change::DestroyInstance {
+                instance_id: "i-0".to_string(),
+                reason: "is p4d.24xlarge, spec wants c6i.xlarge".to_string(),
+            },
+            Change::AddNetwork,
+            Change::AddInstances {
+                instance_type: "c6i.xlarge".to_string(),
+                node_indices: vec![0],
+            },
+            Change::AddInstances {
+                instance_type: "p4d.24xlarge".to_string(),
+                node_indices: vec![1, 2, 3],
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_ingress_rules_round_trip() {
+    for rule in desired_rules("sg-1") {
+        assert_eq!(
+            IngressRule::from_permission(&rule.to_permission()),
+            vec![rule]
+        );
+    }
+}
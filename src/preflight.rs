@@ -0,0 +1,390 @@
+use aws_sdk_ec2::types;
+use aws_sdk_ec2::Client;
+use aws_smithy_types::error::display::DisplayErrorContext;
+use tracing::{info, warn};
+
+use crate::retry;
+
+/// What a launch is about to create, so quotas can be checked before anything is.
+#[derive(Debug, Clone, Default)]
+pub struct Requirements {
+    /// Nodes to launch: the instance types each may end up as (the preferred one, then the ones it
+    /// falls back on) and how many of them there are.
+    pub instances: Vec<(Vec<String>, u64)>,
+    pub vpcs: u32,
+    pub internet_gateways: u32,
+    /// Including the default group every new VPC comes with.
+    pub security_groups: u32,
+    pub elastic_ips: u32,
+}
+
+/// One quota compared against what is in use and what the launch needs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotaCheck {
+    pub name: String,
+    pub in_use: f64,
+    pub required: f64,
+    /// `None` if the quota could not be looked up, in which case the check is skipped.
+    pub limit: Option<f64>,
+}
+
+impl QuotaCheck {
+    /// Why the launch would exceed this quota, if it would.
+    pub fn problem(&self) -> Option<String> {
+        let limit = self.limit?;
+        if self.in_use + self.required <= limit {
+            return None;
+        }
+        Some(format!(
+            "{}: need {} more but {} of {} are in use",
+            self.name, self.required, self.in_use, limit
+        ))
+    }
+}
+
+/// The Service Quotas code and name of the on-demand vCPU quota an instance type counts against.
+pub fn vcpu_quota(instance_type: &str) -> Option<(&'static str, &'static str)> {
+    let family: String = instance_type
+        .split('.')
+        .next()?
+        .chars()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect();
+
+    Some(match family.as_str() {
+        "dl" => ("L-6E869C2A", "Running On-Demand DL instances"),
+        "f" => ("L-74FC7D96", "Running On-Demand F instances"),
+        "g" | "vt" => ("L-DB2E81BA", "Running On-Demand G and VT instances"),
+        "hpc" => ("L-F7808C92", "Running On-Demand HPC instances"),
+        "inf" => ("L-1945791B", "Running On-Demand Inf instances"),
+        "p" => ("L-417A185B", "Running On-Demand P instances"),
+        "trn" => ("L-2C3B7624", "Running On-Demand Trn instances"),
+        "u" => ("L-43DA4232", "Running On-Demand High Memory instances"),
+        "x" => ("L-7295265B", "Running On-Demand X instances"),
+        "a" | "c" | "d" | "h" | "i" | "m" | "r" | "t" | "z" | "im" | "is" => (
+            "L-1216C47A",
+            "Running On-Demand Standard (A, C, D, H, I, M, R, T, Z) instances",
+        ),
+        _ => return None,
+    })
+}
+
+/// Look up the applied value of a quota through the Service Quotas API.
+async fn service_quota(
+    quotas: &aws_sdk_servicequotas::Client,
+    service_code: &str,
+    quota_code: &str,
+) -> Result<f64, Box<dyn std::error::Error>> {
+    let out = quotas
+        .get_service_quota()
+        .service_code(service_code)
+        .quota_code(quota_code)
+        .send()
+        .await?;
+    match out.quota().and_then(|q| q.value()) {
+        Some(value) => Ok(value),
+        None => Err(format!("No value for quota {}", quota_code).into()),
+    }
+}
+
+/// A quota's value, or `None` (with a warning) if it can't be looked up, e.g. for lack of
+/// `servicequotas:GetServiceQuota` permission.
+async fn quota_or_warn(
+    quotas: &aws_sdk_servicequotas::Client,
+    service_code: &str,
+    quota_code: &str,
+    name: &str,
+) -> Option<f64> {
+    match service_quota(quotas, service_code, quota_code).await {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!(
+                "Could not look up quota {:?}, not checking it: {}",
+                name,
+                DisplayErrorContext(e.as_ref())
+            );
+            None
+        }
+    }
+}
+
+/// vCPUs of the on-demand instances currently running in the same quota group as `quota_code`.
+async fn vcpus_in_use(
+    aws_client: &Client,
+    quota_code: &str,
+) -> Result<f64, Box<dyn std::error::Error>> {
+    let mut total = 0.0;
+    let mut next_token: Option<String> = None;
+    loop {
+        let out = aws_client
+            .describe_instances()
+            .filters(
+                types::Filter::builder()
+                    .name("instance-state-name")
+                    .values("pending")
+                    .values("running")
+                    .build(),
+            )
+            .set_next_token(next_token)
+            .send()
+            .await?;
+
+        for instance in out.reservations().iter().flat_map(|r| r.instances()) {
+            // Spot instances have quotas of their own
+            if instance.instance_lifecycle().is_some() {
+                continue;
+            }
+            let same_group = instance
+                .instance_type()
+                .and_then(|t| vcpu_quota(t.as_str()))
+                .is_some_and(|(code, _)| code == quota_code);
+            if let (true, Some(cpu)) = (same_group, instance.cpu_options()) {
+                total +=
+                    (cpu.core_count().unwrap_or(0) * cpu.threads_per_core().unwrap_or(1)) as f64;
+            }
+        }
+
+        next_token = out.next_token().map(|t| t.to_string());
+        if next_token.is_none() {
+            break;
+        }
+    }
+    Ok(total)
+}
+
+/// The value of an EC2 account attribute such as `vpc-max-elastic-ips`.
+async fn account_attribute(
+    aws_client: &Client,
+    name: &str,
+) -> Result<Option<f64>, Box<dyn std::error::Error>> {
+    let out = aws_client
+        .describe_account_attributes()
+        .attribute_names(types::AccountAttributeName::from(name))
+        .send()
+        .await?;
+
+    Ok(out
+        .account_attributes()
+        .first()
+        .and_then(|a| a.attribute_values().first())
+        .and_then(|v| v.attribute_value())
+        .and_then(|v| v.parse().ok()))
+}
+
+/// The vCPUs of the largest of a node's acceptable instance types under each vCPU quota, since the
+/// node may fall back to any of them.
+fn largest_vcpus_by_quota(
+    vcpus_per_type: &[(&str, i32)],
+) -> Vec<((&'static str, &'static str), u64)> {
+    let mut largest: Vec<((&str, &str), u64)> = Vec::new();
+    for &(instance_type, vcpus) in vcpus_per_type {
+        let vcpus = vcpus.max(0) as u64;
+        match vcpu_quota(instance_type) {
+            Some(quota) => match largest.iter_mut().find(|(q, _)| *q == quota) {
+                Some((_, most)) => *most = (*most).max(vcpus),
+                None => largest.push((quota, vcpus)),
+            },
+            None => warn!("No known vCPU quota for {}, not checking it", instance_type),
+        }
+    }
+    largest
+}
+
+/// Check that a launch fits within the account's quotas before anything is created.
+///
+/// Compares the vCPUs the instances need (from `describe_instance_types`, counting each node as the
+/// largest of the types it may fall back to) plus those already in use against the on-demand vCPU
+/// quota of each instance family, and checks the VPC, internet gateway,
+/// security group and Elastic IP limits for whatever else the launch creates. Quotas that can't be
+/// looked up are skipped with a warning rather than blocking the launch.
+///
+/// # Returns
+/// * An error listing every quota the launch would exceed, or `Ok` if there are none.
+pub async fn check_quotas(
+    aws_client: &Client,
+    requirements: &Requirements,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let quotas = retry::service_quotas_client()?;
+    let mut checks = Vec::new();
+
+    // Instance types of different families can count against the same quota
+    let mut vcpus_by_quota: Vec<((&str, &str), u64)> = Vec::new();
+    for (instance_types, num_instances) in &requirements.instances {
+        if *num_instances == 0 {
+            continue;
+        }
+        let out = aws_client
+            .describe_instance_types()
+            .set_instance_types(Some(
+                instance_types
+                    .iter()
+                    .map(|t| types::InstanceType::from(t.as_str()))
+                    .collect(),
+            ))
+            .send()
+            .await?;
+        let mut vcpus_per_type = Vec::new();
+        for instance_type in instance_types {
+            let vcpus = out
+                .instance_types()
+                .iter()
+                .find(|t| t.instance_type().map(|t| t.as_str()) == Some(instance_type.as_str()))
+                .and_then(|t| t.v_cpu_info())
+                .and_then(|v| v.default_v_cpus());
+            match vcpus {
+                Some(vcpus) => vcpus_per_type.push((instance_type.as_str(), vcpus)),
+                None => return Err(format!("Unknown instance type {}", instance_type).into()),
+            }
+        }
+
+        for (quota, vcpus_per_instance) in largest_vcpus_by_quota(&vcpus_per_type) {
+            let required = vcpus_per_instance * num_instances;
+            match vcpus_by_quota.iter_mut().find(|(q, _)| *q == quota) {
+                Some((_, vcpus)) => *vcpus += required,
+                None => vcpus_by_quota.push((quota, required)),
+            }
+        }
+    }
+    for ((code, name), required) in vcpus_by_quota {
+        checks.push(QuotaCheck {
+            name: format!("{} (vCPUs)", name),
+            in_use: vcpus_in_use(aws_client, code).await?,
+            required: required as f64,
+            limit: quota_or_warn(&quotas, "ec2", code, name).await,
+        });
+    }
+
+    if requirements.vpcs > 0 {
+        let in_use = aws_client.describe_vpcs().send().await?.vpcs().len();
+        checks.push(QuotaCheck {
+            name: "VPCs per Region".to_string(),
+            in_use: in_use as f64,
+            required: requirements.vpcs as f64,
+            limit: quota_or_warn(&quotas, "vpc", "L-F678F1CE", "VPCs per Region").await,
+        });
+    }
+
+    if requirements.internet_gateways > 0 {
+        let in_use = aws_client
+            .describe_internet_gateways()
+            .send()
+            .await?
+            .internet_gateways()
+            .len();
+        checks.push(QuotaCheck {
+            name: "Internet gateways per Region".to_string(),
+            in_use: in_use as f64,
+            required: requirements.internet_gateways as f64,
+            limit: quota_or_warn(&quotas, "vpc", "L-A4707A72", "Internet gateways per Region")
+                .await,
+        });
+    }
+
+    if requirements.security_groups > 0 {
+        let in_use = aws_client
+            .describe_security_groups()
+            .send()
+            .await?
+            .security_groups()
+            .len();
+        checks.push(QuotaCheck {
+            name: "VPC security groups per Region".to_string(),
+            in_use: in_use as f64,
+            required: requirements.security_groups as f64,
+            limit: quota_or_warn(
+                &quotas,
+                "vpc",
+                "L-E79EC296",
+                "VPC security groups per Region",
+            )
+            .await,
+        });
+    }
+
+    if requirements.elastic_ips > 0 {
+        let in_use = aws_client
+            .describe_addresses()
+            .send()
+            .await?
+            .addresses()
+            .len();
+        checks.push(QuotaCheck {
+            name: "Elastic IP addresses".to_string(),
+            in_use: in_use as f64,
+            required: requirements.elastic_ips as f64,
+            limit: account_attribute(aws_client, "vpc-max-elastic-ips").await?,
+        });
+    }
+
+    let problems: Vec<String> = checks.iter().filter_map(QuotaCheck::problem).collect();
+    if !problems.is_empty() {
+        return Err(format!(
+            "Launch would exceed service quotas (request an increase in the Service Quotas console): {}",
+            problems.join("; ")
+        )
+        .into());
+    }
+
+    info!(
+        "{} quota check(s) passed",
+        checks.iter().filter(|c| c.limit.is_some()).count()
+    );
+    Ok(())
+}
+
+#[test]
+fn test_vcpu_quota_by_family() {
+    assert_eq!(vcpu_quota("g5.2xlarge").unwrap().0, "L-DB2E81BA");
+    assert_eq!(vcpu_quota("t2.micro").unwrap().0, "L-1216C47A");
+    assert_eq!(vcpu_quota("c7gn.16xlarge").unwrap().0, "L-1216C47A");
+    assert_eq!(vcpu_quota("p4d.24xlarge").unwrap().0, "L-417A185B");
+    assert_eq!(vcpu_quota("inf2.xlarge").unwrap().0, "L-1945791B");
+    assert_eq!(vcpu_quota("trn1.32xlarge").unwrap().0, "L-2C3B7624");
+    assert_eq!(vcpu_quota("mac1.metal"), None);
+}
+
+#[test]
+fn test_quota_check_problem() {
+    let check = QuotaCheck {
+        name: "Running On-Demand G and VT instances (vCPUs)".to_string(),
+        in_use: 32.0,
+        required: 64.0,
+        limit: Some(64.0),
+    };
+    assert_eq!(
+        check.problem().unwrap(),
+        "Running On-Demand G and VT instances (vCPUs): need 64 more but 32 of 64 are in use"
+    );
+
+    assert_eq!(
+        QuotaCheck {
+            in_use: 0.0,
+            ..check.clone()
+        }
+        .problem(),
+        None
+    );
+    assert_eq!(
+        QuotaCheck {
+            limit: None,
+            ..check
+        }
+        .problem(),
+        None
+    );
+}
+
+#[test]
+fn test_largest_vcpus_by_quota() {
+    assert_eq!(
+        largest_vcpus_by_quota(&[("g5.2xlarge", 8), ("g5.4xlarge", 16), ("p5.48xlarge", 192)]),
+        vec![
+            (("L-DB2E81BA", "Running On-Demand G and VT instances"), 16),
+            (("L-417A185B", "Running On-Demand P instances"), 192)
+        ]
+    );
+    assert_eq!(
+        largest_vcpus_by_quota(&[("g5.4xlarge", 16), ("g5.2xlarge", 8)]),
+        vec![(("L-DB2E81BA", "Running On-Demand G and VT instances"), 16)]
+    );
+}
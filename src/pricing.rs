@@ -0,0 +1,805 @@
+use aws_sdk_ec2::primitives::DateTime;
+use aws_sdk_ec2::types;
+use aws_sdk_ec2::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+use crate::sdk_wrapper::PublicIp;
+use crate::spec::ClusterSpec;
+
+/// Hours in a month, as AWS uses for turning monthly EBS prices into hourly ones.
+const HOURS_PER_MONTH: f64 = 730.0;
+
+/// How long a cached price table is used before it is fetched again.
+const MAX_CACHE_AGE: std::time::Duration = std::time::Duration::from_secs(7 * 24 * 3600);
+
+/// IOPS included in the price of a gp3 volume.
+const GP3_FREE_IOPS: i32 = 3000;
+
+/// Price of a public IPv4 address per hour (the same in every region, and not in the EC2 price list).
+const PUBLIC_IPV4_HOURLY: f64 = 0.005;
+
+/// On-demand prices (USD) for one region, slimmed down from the AWS price list.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PriceTable {
+    pub region: String,
+    /// Linux, shared tenancy, per hour, by instance type.
+    pub instance_hourly: BTreeMap<String, f64>,
+    /// Per GiB-month, by volume type (e.g. `gp3`).
+    pub ebs_gb_month: BTreeMap<String, f64>,
+    /// Per provisioned IOPS-month (first tier), by volume type.
+    pub ebs_iops_month: BTreeMap<String, f64>,
+}
+
+impl PriceTable {
+    /// Hourly cost of a volume, or `None` if the volume type isn't priced.
+    pub fn volume_hourly(&self, volume_type: &str, size_gb: i32, iops: Option<i32>) -> Option<f64> {
+        let storage = self.ebs_gb_month.get(volume_type)? * size_gb as f64;
+        let billed_iops = match (volume_type, iops) {
+            ("gp3", Some(iops)) => (iops - GP3_FREE_IOPS).max(0),
+            ("io1" | "io2", Some(iops)) => iops,
+            _ => 0,
+        };
+        let iops_cost = match self.ebs_iops_month.get(volume_type) {
+            Some(price) => price * billed_iops as f64,
+            None => 0.0,
+        };
+        Some((storage + iops_cost) / HOURS_PER_MONTH)
+    }
+}
+
+// The parts of a product in the Price List API (`GetProducts` returns each as a JSON string) that
+// we need. Everything else in it is ignored.
+#[derive(Deserialize)]
+struct PriceListItem {
+    product: Product,
+    terms: Terms,
+}
+
+#[derive(Deserialize)]
+struct Product {
+    #[serde(rename = "productFamily", default)]
+    product_family: String,
+    #[serde(default)]
+    attributes: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct Terms {
+    #[serde(rename = "OnDemand", default)]
+    on_demand: HashMap<String, Term>,
+}
+
+#[derive(Deserialize)]
+struct Term {
+    #[serde(rename = "priceDimensions")]
+    price_dimensions: HashMap<String, PriceDimension>,
+}
+
+#[derive(Deserialize)]
+struct PriceDimension {
+    unit: String,
+    #[serde(rename = "pricePerUnit")]
+    price_per_unit: HashMap<String, String>,
+    #[serde(rename = "beginRange", default)]
+    begin_range: String,
+}
+
+impl PriceTable {
+    /// Add the on-demand price of one Price List API product to the table, if it is one we use.
+    pub fn add_price_list_item(
+        &mut self,
+        item_json: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let item: PriceListItem = serde_json::from_str(item_json)?;
+        let attr = |name: &str| item.product.attributes.get(name).map(String::as_str);
+        // Local Zones etc. can show up next to the region itself
+        if attr("regionCode").is_some_and(|code| code != self.region) {
+            return Ok(());
+        }
+
+        let dimensions = item
+            .terms
+            .on_demand
+            .values()
+            .flat_map(|term| term.price_dimensions.values());
+        let first_tier = |unit: &str| {
+            dimensions
+                .clone()
+                .filter(|d| d.unit == unit && (d.begin_range.is_empty() || d.begin_range == "0"))
+                .filter_map(|d| d.price_per_unit.get("USD")?.parse::<f64>().ok())
+                .next()
+        };
+
+        match item.product.product_family.as_str() {
+            "Compute Instance"
+                if attr("operatingSystem") == Some("Linux")
+                    && attr("tenancy") == Some("Shared")
+                    && attr("preInstalledSw") == Some("NA")
+                    && attr("capacitystatus") == Some("Used")
+                    && attr("licenseModel") != Some("Bring your own license") =>
+            {
+                if let (Some(instance_type), Some(price)) =
+                    (attr("instanceType"), first_tier("Hrs"))
+                {
+                    self.instance_hourly
+                        .insert(instance_type.to_string(), price);
+                }
+            }
+            "Storage" => {
+                if let (Some(volume_type), Some(price)) =
+                    (attr("volumeApiName"), first_tier("GB-Mo"))
+                {
+                    self.ebs_gb_month.insert(volume_type.to_string(), price);
+                }
+            }
+            "System Operation" if attr("group") == Some("EBS IOPS") => {
+                if let (Some(volume_type), Some(price)) =
+                    (attr("volumeApiName"), first_tier("IOPS-Mo"))
+                {
+                    self.ebs_iops_month.insert(volume_type.to_string(), price);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, other: PriceTable) {
+        self.instance_hourly.extend(other.instance_hourly);
+        self.ebs_gb_month.extend(other.ebs_gb_month);
+        self.ebs_iops_month.extend(other.ebs_iops_month);
+    }
+}
+
+/// Add every EC2 product of the table's region matching `filters` (exact matches on product
+/// attributes) to the table.
+async fn fetch_products(
+    client: &aws_sdk_pricing::Client,
+    table: &mut PriceTable,
+    filters: &[(&str, &str)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let term_match = |field: &str, value: &str| {
+        aws_sdk_pricing::types::Filter::builder()
+            .r#type(aws_sdk_pricing::types::FilterType::TermMatch)
+            .field(field)
+            .value(value)
+            .build()
+    };
+    let region_filter = term_match("regionCode", &table.region)?;
+
+    let mut next_token: Option<String> = None;
+    loop {
+        let mut request = client
+            .get_products()
+            .service_code("AmazonEC2")
+            .format_version("aws_v1")
+            .filters(region_filter.clone());
+        for (field, value) in filters {
+            request = request.filters(term_match(field, value)?);
+        }
+        let out = request.set_next_token(next_token).send().await?;
+        for item in out.price_list() {
+            table.add_price_list_item(item)?;
+        }
+
+        next_token = out.next_token().map(|t| t.to_string());
+        if next_token.is_none() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Fetch the on-demand prices of some instance types, and optionally of EBS, in a region.
+async fn fetch_prices(
+    region: &str,
+    instance_types: &[&str],
+    ebs: bool,
+) -> Result<PriceTable, Box<dyn std::error::Error>> {
+    let client = crate::retry::pricing_client()?;
+    let mut table = PriceTable {
+        region: region.to_string(),
+        ..Default::default()
+    };
+    for instance_type in instance_types {
+        let filters = [
+            ("instanceType", *instance_type),
+            ("operatingSystem", "Linux"),
+            ("tenancy", "Shared"),
+            ("preInstalledSw", "NA"),
+            ("capacitystatus", "Used"),
+        ];
+        fetch_products(&client, &mut table, &filters).await?;
+    }
+    if ebs {
+        fetch_products(&client, &mut table, &[("productFamily", "Storage")]).await?;
+        let iops = [("productFamily", "System Operation"), ("group", "EBS IOPS")];
+        fetch_products(&client, &mut table, &iops).await?;
+    }
+    Ok(table)
+}
+
+/// Where the price table for a region is cached: `$AWS_MANAGER_PRICE_CACHE` if set, otherwise
+/// `~/.cache/aws_manager/`.
+fn cache_path(region: &str) -> PathBuf {
+    let dir = match std::env::var_os("AWS_MANAGER_PRICE_CACHE") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME").unwrap_or_default())
+            .join(".cache")
+            .join("aws_manager"),
+    };
+    dir.join(format!("ec2-prices-{}.json", region))
+}
+
+/// Load the prices of some instance types (and of EBS) in a region, from the local cache if it is
+/// fresh enough and otherwise from the Price List API, caching what was fetched.
+///
+/// Only the products asked for are fetched, so a fresh cache only has to be topped up with the
+/// instance types it hasn't seen yet. A stale cache is still used if fetching fails, e.g. when
+/// offline.
+pub async fn load_price_table(
+    region: &str,
+    instance_types: &[&str],
+) -> Result<PriceTable, Box<dyn std::error::Error>> {
+    let path = cache_path(region);
+    let cached: Option<(PriceTable, bool)> = fs::read(&path).ok().and_then(|bytes| {
+        let table = serde_json::from_slice(&bytes).ok()?;
+        let fresh = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age < MAX_CACHE_AGE);
+        Some((table, fresh))
+    });
+
+    let mut table = match &cached {
+        Some((table, true)) => table.clone(),
+        _ => PriceTable {
+            region: region.to_string(),
+            ..Default::default()
+        },
+    };
+    let mut missing: Vec<&str> = instance_types
+        .iter()
+        .copied()
+        .filter(|t| !table.instance_hourly.contains_key(*t))
+        .collect();
+    missing.sort();
+    missing.dedup();
+    let fetch_ebs = table.ebs_gb_month.is_empty();
+    if missing.is_empty() && !fetch_ebs {
+        return Ok(table);
+    }
+
+    info!(
+        "Fetching EC2 prices for {} in {}...",
+        missing.join(", "),
+        region
+    );
+    match (fetch_prices(region, &missing, fetch_ebs).await, cached) {
+        (Ok(fetched), _) => {
+            table.merge(fetched);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(&path, serde_json::to_vec(&table)?)?;
+            Ok(table)
+        }
+        (Err(e), Some((table, _))) => {
+            warn!(
+                "Could not fetch prices ({}), using the cached ones in {}",
+                e,
+                path.display()
+            );
+            Ok(table)
+        }
+        (Err(e), None) => Err(e),
+    }
+}
+
+/// The most recent Linux spot price of an instance type in an availability zone.
+pub async fn spot_price(
+    aws_client: &Client,
+    instance_type: &types::InstanceType,
+    availability_zone: &str,
+) -> Result<Option<f64>, Box<dyn std::error::Error>> {
+    let out = aws_client
+        .describe_spot_price_history()
+        .instance_types(instance_type.clone())
+        .product_descriptions("Linux/UNIX")
+        .availability_zone(availability_zone)
+        .start_time(DateTime::from(std::time::SystemTime::now()))
+        .send()
+        .await?;
+
+    Ok(out
+        .spot_price_history()
+        .first()
+        .and_then(|p| p.spot_price())
+        .and_then(|p| p.parse().ok()))
+}
+
+/// Size and type of the root volume an AMI launches with.
+async fn root_volume(
+    aws_client: &Client,
+    ami_image_id: &str,
+) -> Result<Option<(String, i32)>, Box<dyn std::error::Error>> {
+    let out = aws_client
+        .describe_images()
+        .image_ids(ami_image_id)
+        .send()
+        .await?;
+    let image = match out.images().first() {
+        Some(image) => image,
+        None => return Ok(None),
+    };
+
+    Ok(image
+        .block_device_mappings()
+        .iter()
+        .find(|m| m.device_name() == image.root_device_name())
+        .and_then(|m| m.ebs())
+        .and_then(|ebs| {
+            let volume_type = ebs.volume_type().map(|t| t.as_str()).unwrap_or("gp2");
+            Some((volume_type.to_string(), ebs.volume_size()?))
+        }))
+}
+
+/// One line of a cost estimate or report.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CostLine {
+    pub description: String,
+    pub hourly: f64,
+    /// How long the resource has been up, for a report; `None` in an hourly estimate.
+    pub hours: Option<f64>,
+}
+
+impl CostLine {
+    /// The cost of this line: hourly for an estimate, accumulated so far for a report.
+    pub fn cost(&self) -> f64 {
+        self.hourly * self.hours.unwrap_or(1.0)
+    }
+}
+
+impl std::fmt::Display for CostLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.hours {
+            Some(hours) => write!(
+                f,
+                "{}: ${:.4}/h for {:.2} h = ${:.2}",
+                self.description,
+                self.hourly,
+                hours,
+                self.cost()
+            ),
+            None => write!(f, "{}: ${:.4}/h", self.description, self.hourly),
+        }
+    }
+}
+
+fn print_cost_lines(title: &str, lines: &[CostLine]) {
+    info!("{}", title);
+    for line in lines {
+        info!("  {}", line);
+    }
+}
+
+/// What a running cluster has cost so far, the result of `cost`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CostReport {
+    pub schema_version: u32,
+    pub cluster: String,
+    pub region: String,
+    pub lines: Vec<CostLine>,
+    pub total: f64,
+}
+
+impl CostReport {
+    pub fn text(&self) -> Vec<String> {
+        let mut text = vec![format!(
+            "Cost so far of cluster {} ({}, on-demand prices):",
+            self.cluster, self.region
+        )];
+        text.extend(self.lines.iter().map(|line| format!("  {}", line)));
+        text.push(format!("Total so far: ${:.2}", self.total));
+        text
+    }
+}
+
+/// Hourly cost lines for a cluster that is about to be created.
+pub fn estimate_lines(
+    table: &PriceTable,
+    instances: &[(&str, u64)],
+    root_volume: Option<(&str, i32)>,
+    shared_volume: Option<(&str, i32, i32)>,
+    public_ipv4s: u64,
+) -> Result<Vec<CostLine>, Box<dyn std::error::Error>> {
+    let mut lines = Vec::new();
+    for &(instance_type, count) in instances {
+        let instance_hourly = match table.instance_hourly.get(instance_type) {
+            Some(price) => *price,
+            None => return Err(format!("No on-demand price known for {}", instance_type).into()),
+        };
+        lines.push(CostLine {
+            description: format!("{} x {} on-demand", count, instance_type),
+            hourly: instance_hourly * count as f64,
+            hours: None,
+        });
+    }
+
+    let num_instances: u64 = instances.iter().map(|(_, count)| count).sum();
+    let n = num_instances as f64;
+    if let Some((volume_type, size_gb)) = root_volume {
+        if let Some(hourly) = table.volume_hourly(volume_type, size_gb, None) {
+            lines.push(CostLine {
+                description: format!(
+                    "{} x {} GiB {} root volume",
+                    num_instances, size_gb, volume_type
+                ),
+                hourly: hourly * n,
+                hours: None,
+            });
+        }
+    }
+    if let Some((volume_type, size_gb, iops)) = shared_volume {
+        if let Some(hourly) = table.volume_hourly(volume_type, size_gb, Some(iops)) {
+            lines.push(CostLine {
+                description: format!(
+                    "{} GiB {} shared volume ({} IOPS)",
+                    size_gb, volume_type, iops
+                ),
+                hourly,
+                hours: None,
+            });
+        }
+    }
+    if public_ipv4s > 0 {
+        lines.push(CostLine {
+            description: format!("{} public IPv4 address(es)", public_ipv4s),
+            hourly: PUBLIC_IPV4_HOURLY * public_ipv4s as f64,
+            hours: None,
+        });
+    }
+
+    Ok(lines)
+}
+
+/// Region the client is configured for.
+pub fn client_region(aws_client: &Client) -> Result<String, Box<dyn std::error::Error>> {
+    match aws_client.config().region() {
+        Some(region) => Ok(region.to_string()),
+        None => Err("No AWS region configured".into()),
+    }
+}
+
+/// Print the estimated hourly cost of a cluster before it is created, on-demand and on spot, and
+/// what it comes to if nodes fall back to pricier instance types.
+pub async fn print_estimate(
+    aws_client: &Client,
+    spec: &ClusterSpec,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let chains = spec.instance_counts();
+    let types: Vec<&str> = chains
+        .iter()
+        .flat_map(|(types, _)| types.iter().map(String::as_str))
+        .collect();
+    let table = load_price_table(&client_region(aws_client)?, &types).await?;
+    let root = root_volume(aws_client, &spec.ami_image_id).await?;
+    let shared = match spec.shared_ebs_volume_size {
+        Some(size) => Some(("io2", size.try_into()?, 3000)),
+        None => None,
+    };
+
+    // The estimate is for the preferred instance types
+    let instances: Vec<(&str, u64)> = chains
+        .iter()
+        .filter_map(|(types, n)| Some((types.first()?.as_str(), *n)))
+        .collect();
+    let lines = estimate_lines(
+        &table,
+        &instances,
+        root.as_ref().map(|(t, size)| (t.as_str(), *size)),
+        shared,
+        match (spec.ip_mode.public_ipv4(), spec.public_ip) {
+            (false, _) => 0,
+            (true, PublicIp::Head) => 1,
+            (true, PublicIp::All) => spec.node_count(),
+        },
+    )?;
+    let total: f64 = lines.iter().map(CostLine::cost).sum();
+    print_cost_lines(
+        &format!(
+            "Estimated cost of cluster {} ({}):",
+            spec.name, table.region
+        ),
+        &lines,
+    );
+
+    // Spot only helps if every instance type has a spot price
+    let mut spot_savings = Some(0.0);
+    for &(instance_type, count) in &instances {
+        let instance_type = types::InstanceType::from(instance_type);
+        let spot = spot_price(aws_client, &instance_type, &spec.availability_zone).await?;
+        spot_savings = match (spot_savings, spot) {
+            (Some(savings), Some(spot)) => {
+                let on_demand = table.instance_hourly[instance_type.as_str()];
+                Some(savings + (on_demand - spot) * count as f64)
+            }
+            _ => None,
+        };
+    }
+    let spot_note = match spot_savings {
+        Some(savings) => format!(
+            " (${:.4}/h with spot instances in {})",
+            total - savings,
+            spec.availability_zone
+        ),
+        None => String::new(),
+    };
+    info!(
+        hourly = total,
+        "Total: ${:.4}/h on-demand{}", total, spot_note
+    );
+
+    let extra = fallback_extra_hourly(&table, &chains);
+    if extra > 0.0 {
+        info!(
+            hourly = total + extra,
+            "Up to ${:.4}/h on-demand if nodes fall back to pricier instance types",
+            total + extra
+        );
+    }
+
+    Ok(())
+}
+
+/// How much more per hour the nodes cost at most if they launch as the priciest of their
+/// acceptable instance types rather than the preferred one. Types without a known price are left
+/// out.
+fn fallback_extra_hourly(table: &PriceTable, chains: &[(Vec<String>, u64)]) -> f64 {
+    let mut extra = 0.0;
+    for (instance_types, count) in chains {
+        let mut prices = instance_types
+            .iter()
+            .filter_map(|t| table.instance_hourly.get(t.as_str()).copied());
+        if let Some(preferred) = prices.next() {
+            let priciest = prices.fold(preferred, f64::max);
+            extra += (priciest - preferred) * *count as f64;
+        }
+    }
+    extra
+}
+
+/// Hours between `since` and now.
+pub fn hours_since(since: &DateTime) -> f64 {
+    let now = DateTime::from(std::time::SystemTime::now());
+    ((now.as_secs_f64() - since.as_secs_f64()) / 3600.0).max(0.0)
+}
+
+/// Work out what a running cluster has cost so far at on-demand prices: each instance's price
+/// times its uptime since `launch_time`, plus its volumes since they were created.
+pub async fn cost_report(
+    aws_client: &Client,
+    project_tag: &str,
+) -> Result<CostReport, Box<dyn std::error::Error>> {
+    let region = client_region(aws_client)?;
+    let project_filter = types::Filter::builder()
+        .name("tag:project")
+        .values(project_tag)
+        .build();
+
+    let mut instances = Vec::new();
+    let mut next_token: Option<String> = None;
+    loop {
+        let out = aws_client
+            .describe_instances()
+            .filters(project_filter.clone())
+            .filters(
+                types::Filter::builder()
+                    .name("instance-state-name")
+                    .values("pending")
+                    .values("running")
+                    .build(),
+            )
+            .set_next_token(next_token)
+            .send()
+            .await?;
+
+        for instance in out.reservations().iter().flat_map(|r| r.instances()) {
+            if let (Some(id), Some(t), Some(launched)) = (
+                instance.instance_id(),
+                instance.instance_type(),
+                instance.launch_time(),
+            ) {
+                instances.push((id.to_string(), t.as_str().to_string(), *launched));
+            }
+        }
+
+        next_token = out.next_token().map(|t| t.to_string());
+        if next_token.is_none() {
+            break;
+        }
+    }
+
+    // Only the prices of the instance types in use are needed
+    let types: Vec<&str> = instances.iter().map(|(_, t, _)| t.as_str()).collect();
+    let table = load_price_table(&region, &types).await?;
+    let mut lines = Vec::new();
+    for (id, instance_type, launched) in &instances {
+        match table.instance_hourly.get(instance_type) {
+            Some(hourly) => lines.push(CostLine {
+                description: format!("{} ({})", id, instance_type),
+                hourly: *hourly,
+                hours: Some(hours_since(launched)),
+            }),
+            None => warn!("No on-demand price known for {} ({})", id, instance_type),
+        }
+    }
+
+    let volumes = aws_client
+        .describe_volumes()
+        .filters(project_filter)
+        .send()
+        .await?;
+    for volume in volumes.volumes() {
+        let (id, volume_type, size, created) = match (
+            volume.volume_id(),
+            volume.volume_type(),
+            volume.size(),
+            volume.create_time(),
+        ) {
+            (Some(id), Some(t), Some(size), Some(created)) => (id, t.as_str(), size, created),
+            _ => continue,
+        };
+        if let Some(hourly) = table.volume_hourly(volume_type, size, volume.iops()) {
+            lines.push(CostLine {
+                description: format!("{} ({} GiB {})", id, size, volume_type),
+                hourly,
+                hours: Some(hours_since(created)),
+            });
+        }
+    }
+
+    if lines.is_empty() {
+        return Err(format!("No running resources found for cluster {:?}", project_tag).into());
+    }
+    Ok(CostReport {
+        schema_version: crate::output::SCHEMA_VERSION,
+        cluster: project_tag.to_string(),
+        region: table.region,
+        total: lines.iter().map(CostLine::cost).sum(),
+        lines,
+    })
+}
+
+#[test]
+fn test_add_price_list_item() {
+    let item = |family: &str, attributes: &str, unit: &str, dimensions: &str| {
+        format!(
+            r#"{{"product": {{"productFamily": "{}", "attributes": {{{}}}, "sku": "A"}},
+                "serviceCode": "AmazonEC2",
+                "terms": {{"OnDemand": {{"A.1": {{"priceDimensions": {{{}}}, "sku": "A"}}}}, "Reserved": {{}}}}}}"#,
+            family,
+            attributes,
+            dimensions.replace("UNIT", unit)
+        )
+    };
+    let linux = r#""instanceType": "g5.2xlarge", "operatingSystem": "Linux", "tenancy": "Shared", "preInstalledSw": "NA", "capacitystatus": "Used", "licenseModel": "No License required""#;
+    let mut table = PriceTable {
+        region: "us-west-2".to_string(),
+        ..Default::default()
+    };
+
+    let items = [
+        item(
+            "Compute Instance",
+            &format!(r#"{}, "regionCode": "us-west-2""#, linux),
+            "Hrs",
+            r#""A.1.1": {"unit": "UNIT", "beginRange": "0", "pricePerUnit": {"USD": "1.2120000000"}}"#,
+        ),
+        // A Local Zone of the region
+        item(
+            "Compute Instance",
+            &format!(r#"{}, "regionCode": "us-west-2-lax-1""#, linux),
+            "Hrs",
+            r#""A.1.1": {"unit": "UNIT", "pricePerUnit": {"USD": "9.99"}}"#,
+        ),
+        item(
+            "Storage",
+            r#""volumeApiName": "gp3", "regionCode": "us-west-2""#,
+            "GB-Mo",
+            r#""A.1.1": {"unit": "UNIT", "pricePerUnit": {"USD": "0.08"}}"#,
+        ),
+        item(
+            "System Operation",
+            r#""group": "EBS IOPS", "volumeApiName": "io2", "regionCode": "us-west-2""#,
+            "IOPS-Mo",
+            r#""A.1.1": {"unit": "UNIT", "beginRange": "32000", "pricePerUnit": {"USD": "0.0455"}},
+               "A.1.2": {"unit": "UNIT", "beginRange": "0", "pricePerUnit": {"USD": "0.065"}}"#,
+        ),
+    ];
+    for item in &items {
+        table.add_price_list_item(item).unwrap();
+    }
+
+    assert_eq!(table.instance_hourly.get("g5.2xlarge"), Some(&1.212));
+    assert_eq!(table.ebs_gb_month.get("gp3"), Some(&0.08));
+    assert_eq!(table.ebs_iops_month.get("io2"), Some(&0.065));
+    assert!(table.add_price_list_item("not json").is_err());
+}
+
+#[test]
+fn test_estimate_lines() {
+    let mut table = PriceTable::default();
+    table.instance_hourly.insert("g5.2xlarge".to_string(), 1.0);
+    table.ebs_gb_month.insert("gp3".to_string(), 0.073);
+    table.ebs_gb_month.insert("io2".to_string(), 0.0);
+    table.ebs_iops_month.insert("io2".to_string(), 0.073);
+
+    let lines = estimate_lines(
+        &table,
+        &[("g5.2xlarge", 4)],
+        Some(("gp3", 100)),
+        Some(("io2", 500, 3000)),
+        4,
+    )
+    .unwrap();
+
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0].hourly, 4.0);
+    assert!((lines[1].hourly - 0.04).abs() < 1e-9);
+    assert!((lines[2].hourly - 0.3).abs() < 1e-9);
+    assert!((lines[3].hourly - 0.02).abs() < 1e-9);
+    assert!(estimate_lines(&table, &[("p5.48xlarge", 1)], None, None, 0).is_err());
+
+    table.instance_hourly.insert("c6i.xlarge".to_string(), 0.17);
+    let mixed = estimate_lines(
+        &table,
+        &[("c6i.xlarge", 1), ("g5.2xlarge", 8)],
+        Some(("gp3", 100)),
+        None,
+        1,
+    )
+    .unwrap();
+    assert_eq!(mixed.len(), 4);
+    assert_eq!(mixed[0].description, "1 x c6i.xlarge on-demand");
+    assert_eq!(mixed[1].hourly, 8.0);
+    assert_eq!(mixed[2].description, "9 x 100 GiB gp3 root volume");
+    assert!(estimate_lines(
+        &table,
+        &[("c6i.xlarge", 1), ("p5.48xlarge", 1)],
+        None,
+        None,
+        0
+    )
+    .is_err());
+}
+
+#[test]
+fn test_fallback_extra_hourly() {
+    let mut table = PriceTable::default();
+    table.instance_hourly.insert("g5.2xlarge".to_string(), 1.0);
+    table.instance_hourly.insert("g5.4xlarge".to_string(), 1.5);
+    table.instance_hourly.insert("c6i.xlarge".to_string(), 0.17);
+
+    let chains = vec![
+        (vec!["c6i.xlarge".to_string()], 1),
+        (vec!["g5.2xlarge".to_string(), "g5.4xlarge".to_string()], 4),
+    ];
+    assert!((fallback_extra_hourly(&table, &chains) - 2.0).abs() < 1e-9);
+
+    // Cheaper fallbacks and ones without a price don't add anything
+    let chains = vec![(
+        vec![
+            "g5.4xlarge".to_string(),
+            "g5.2xlarge".to_string(),
+            "p5.48xlarge".to_string(),
+        ],
+        2,
+    )];
+    assert_eq!(fallback_extra_hourly(&table, &chains), 0.0);
+}
@@ -0,0 +1,212 @@
+use aws_sdk_ec2::primitives::{DateTime, DateTimeFormat};
+use aws_sdk_ec2::types;
+use std::collections::BTreeMap;
+use tracing::{error, info};
+
+use crate::sdk_wrapper::{self, ClusterCleanup, EXPIRES_AT_TAG};
+
+/// The value of a tag, if the resource has it.
+fn tag_value<'a>(tags: &'a [types::Tag], key: &str) -> Option<&'a str> {
+    tags.iter()
+        .find(|t| t.key() == Some(key))
+        .and_then(|t| t.value())
+}
+
+/// Whether a resource with the given tags has expired at `now`.
+///
+/// Resources without an `expires-at` tag (or with one that doesn't parse) never expire.
+pub fn is_expired(tags: &[types::Tag], now: &DateTime) -> bool {
+    tag_value(tags, EXPIRES_AT_TAG)
+        .and_then(|v| DateTime::from_str(v, DateTimeFormat::DateTime).ok())
+        .is_some_and(|at| at.secs() <= now.secs())
+}
+
+/// Filter matching resources that carry an `expires-at` tag at all.
+fn has_expiry() -> types::Filter {
+    types::Filter::builder()
+        .name("tag-key")
+        .values(EXPIRES_AT_TAG)
+        .build()
+}
+
+/// Describe an expired resource for the log.
+fn describe(id: &str, tags: &[types::Tag]) -> String {
+    format!(
+        "{} (project {}, expired at {})",
+        id,
+        tag_value(tags, "project").unwrap_or("?"),
+        tag_value(tags, EXPIRES_AT_TAG).unwrap_or("?")
+    )
+}
+
+/// Find every resource whose `expires-at` tag is in the past and tear it down.
+///
+/// Instances go first (and are waited on), then Elastic IPs, volumes that are no longer attached
+/// to anything, placement groups and VPCs. A resource that fails to go away is reported and skipped, so one stuck VPC doesn't
+/// keep everything else alive.
+pub async fn reap(aws_client: &aws_sdk_ec2::Client) -> Result<(), Box<dyn std::error::Error>> {
+    let now = DateTime::from(std::time::SystemTime::now());
+    let mut failures = Vec::new();
+
+    // Instances
+    let mut instance_ids = Vec::new();
+    let mut next_token = None;
+    loop {
+        let out = aws_client
+            .describe_instances()
+            .filters(has_expiry())
+            .filters(
+                types::Filter::builder()
+                    .name("instance-state-name")
+                    .values("pending")
+                    .values("running")
+                    .values("stopping")
+                    .values("stopped")
+                    .build(),
+            )
+            .set_next_token(next_token)
+            .send()
+            .await?;
+
+        for instance in out.reservations().iter().flat_map(|r| r.instances()) {
+            if let Some(id) = instance.instance_id() {
+                if is_expired(instance.tags(), &now) {
+                    info!("Reaping instance {}", describe(id, instance.tags()));
+                    instance_ids.push(id.to_string());
+                }
+            }
+        }
+
+        next_token = out.next_token().map(|t| t.to_string());
+        if next_token.is_none() {
+            break;
+        }
+    }
+    if !instance_ids.is_empty() {
+        if let Err(e) = sdk_wrapper::cleanup_cluster(
+            aws_client,
+            ClusterCleanup {
+                instance_ids: instance_ids.clone(),
+                volume_id: None,
+                elastic_ip: None,
+                placement_group: None,
+                instance_types: BTreeMap::new(),
+            },
+        )
+        .await
+        {
+            failures.push(format!("instances {:?}: {}", instance_ids, e));
+        }
+    }
+
+    // Volumes
+    let volumes = aws_client
+        .describe_volumes()
+        .filters(has_expiry())
+        .filters(
+            types::Filter::builder()
+                .name("status")
+                .values("available")
+                .build(),
+        )
+        .send()
+        .await?;
+    for volume in volumes.volumes() {
+        if let Some(id) = volume.volume_id() {
+            if is_expired(volume.tags(), &now) {
+                info!("Reaping volume {}", describe(id, volume.tags()));
+                if let Err(e) = sdk_wrapper::delete_volume(aws_client, id).await {
+                    failures.push(format!("volume {}: {}", id, e));
+                }
+            }
+        }
+    }
+
+    // Elastic IPs (those of reaped instances were disassociated when they terminated)
+    let addresses = aws_client
+        .describe_addresses()
+        .filters(has_expiry())
+        .send()
+        .await?;
+    for address in addresses.addresses() {
+        if let Some(id) = address.allocation_id() {
+            if is_expired(address.tags(), &now) {
+                info!("Reaping Elastic IP {}", describe(id, address.tags()));
+                if let Err(e) = sdk_wrapper::release_elastic_ip(aws_client, id).await {
+                    failures.push(format!("Elastic IP {}: {}", id, e));
+                }
+            }
+        }
+    }
+
+    // Placement groups (empty once their instances are gone)
+    let placement_groups = aws_client
+        .describe_placement_groups()
+        .filters(has_expiry())
+        .send()
+        .await?;
+    for group in placement_groups.placement_groups() {
+        if let Some(name) = group.group_name() {
+            if is_expired(group.tags(), &now) {
+                info!("Reaping placement group {}", describe(name, group.tags()));
+                if let Err(e) = sdk_wrapper::cleanup_cluster(
+                    aws_client,
+                    ClusterCleanup {
+                        placement_group: Some(name.to_string()),
+                        ..Default::default()
+                    },
+                )
+                .await
+                {
+                    failures.push(format!("placement group {}: {}", name, e));
+                }
+            }
+        }
+    }
+
+    // VPCs
+    let vpcs = aws_client
+        .describe_vpcs()
+        .filters(has_expiry())
+        .send()
+        .await?;
+    for vpc in vpcs.vpcs() {
+        if let Some(id) = vpc.vpc_id() {
+            if is_expired(vpc.tags(), &now) {
+                info!("Reaping VPC {}", describe(id, vpc.tags()));
+                let result = match sdk_wrapper::discover_vpc(aws_client, id).await {
+                    Ok(cleanup_items) => sdk_wrapper::cleanup_vpc(aws_client, cleanup_items).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    failures.push(format!("VPC {}: {}", id, e));
+                }
+            }
+        }
+    }
+
+    if !failures.is_empty() {
+        error!("Could not reap: {}", failures.join("; "));
+        return Err(format!("Failed to reap {} resource(s)", failures.len()).into());
+    }
+
+    info!("Nothing expired is left");
+    Ok(())
+}
+
+#[test]
+fn test_is_expired() {
+    let now = DateTime::from_str("2024-03-14T12:00:00Z", DateTimeFormat::DateTime).unwrap();
+    let tag = |key: &str, value: &str| types::Tag::builder().key(key).value(value).build();
+
+    assert!(is_expired(
+        &[tag(EXPIRES_AT_TAG, "2024-03-14T11:59:59Z")],
+        &now
+    ));
+    assert!(!is_expired(
+        &[tag(EXPIRES_AT_TAG, "2024-03-14T12:00:01Z")],
+        &now
+    ));
+    assert!(!is_expired(&[tag("project", "nccl")], &now));
+    assert!(!is_expired(&[tag(EXPIRES_AT_TAG, "tomorrow")], &now));
+}
@@ -0,0 +1,270 @@
+use futures::stream::{self, StreamExt};
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tracing::{error, info};
+
+use crate::cluster_files::ClusterNode;
+
+/// Outcome of running a command on a single node.
+#[derive(Debug, Clone)]
+pub struct NodeResult {
+    pub index: usize,
+    pub node: String,
+    /// Exit code of the remote command; `None` if it was killed by a signal or never started.
+    pub exit_code: Option<i32>,
+    /// Why the node failed even though the command itself may have exited cleanly.
+    pub error: Option<String>,
+}
+
+impl NodeResult {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0) && self.error.is_none()
+    }
+}
+
+/// Quote a string for use as a single word in a remote POSIX shell command.
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Quote a remote path like `shell_quote`, but keep a leading `~/` working by writing it as
+/// `"$HOME"/` (a quoted `~` is not expanded).
+pub fn shell_quote_path(path: &str) -> String {
+    match path.strip_prefix("~/") {
+        Some(rest) => format!("\"$HOME\"/{}", shell_quote(rest)),
+        None if path == "~" => "\"$HOME\"".to_string(),
+        None => shell_quote(path),
+    }
+}
+
+/// Parse a node selection such as `0,2,4-7` into sorted, de-duplicated node indices.
+pub fn parse_node_selection(selection: &str) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let mut indices = Vec::new();
+
+    for part in selection
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+    {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start.trim().parse()?;
+                let end: usize = end.trim().parse()?;
+                if start > end {
+                    return Err(format!("Invalid node range: {}", part).into());
+                }
+                indices.extend(start..=end);
+            }
+            None => indices.push(part.parse()?),
+        }
+    }
+
+    indices.sort_unstable();
+    indices.dedup();
+    Ok(indices)
+}
+
+/// Narrow the nodes of a cluster down to a selection (all nodes if no selection is given).
+pub fn select_nodes(
+    nodes: &[ClusterNode],
+    selection: Option<&str>,
+) -> Result<Vec<ClusterNode>, Box<dyn std::error::Error>> {
+    let selection = match selection {
+        Some(selection) => parse_node_selection(selection)?,
+        None => return Ok(nodes.to_vec()),
+    };
+
+    let mut selected = Vec::new();
+    for index in selection {
+        match nodes.iter().find(|n| n.index == index) {
+            Some(node) => selected.push(node.clone()),
+            None => {
+                return Err(format!(
+                    "Node {} does not exist (cluster has {} node(s))",
+                    index,
+                    nodes.len()
+                )
+                .into())
+            }
+        }
+    }
+
+    Ok(selected)
+}
+
+/// Run `program args...` locally and stream its output, prefixing every line with `[prefix]`.
+///
+/// Stdout lines go to stdout and stderr lines to stderr, so the prefixed output can still be
+/// piped and filtered like the output of a single command.
+pub async fn run_prefixed(
+    prefix: &str,
+    program: &str,
+    args: &[String],
+) -> Result<Option<i32>, Box<dyn std::error::Error>> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+
+    let out_task = async {
+        while let Ok(Some(line)) = stdout.next_line().await {
+            println!("[{}] {}", prefix, line);
+        }
+    };
+    let err_task = async {
+        while let Ok(Some(line)) = stderr.next_line().await {
+            eprintln!("[{}] {}", prefix, line);
+        }
+    };
+    futures::join!(out_task, err_task);
+
+    let status = child.wait().await?;
+    Ok(status.code())
+}
+
+/// Run `program args...` locally and capture its stdout instead of streaming it.
+///
+/// Stderr is still streamed with the prefix so that connection problems are visible.
+pub async fn run_captured(
+    prefix: &str,
+    program: &str,
+    args: &[String],
+) -> Result<(Option<i32>, String), Box<dyn std::error::Error>> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        eprintln!("[{}] {}", prefix, line);
+    }
+
+    Ok((
+        output.status.code(),
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+    ))
+}
+
+/// Build the arguments for `ssh` to run a command on one node using the generated SSH config.
+pub fn ssh_args(ssh_config: &Path, alias: &str, command: &[String]) -> Vec<String> {
+    let mut args = vec![
+        "-F".to_string(),
+        ssh_config.display().to_string(),
+        "-o".to_string(),
+        "BatchMode=yes".to_string(),
+        alias.to_string(),
+        "--".to_string(),
+    ];
+    args.extend(command.iter().cloned());
+    args
+}
+
+/// Run a command over SSH on every given node concurrently.
+///
+/// # Arguments
+/// * `ssh_config` - The SSH config generated for the cluster (see `cluster_files`).
+/// * `nodes` - The nodes to run the command on.
+/// * `command` - The command and its arguments, joined by `ssh` on the remote side.
+/// * `parallel` - How many nodes to run on at once.
+///
+/// # Returns
+/// * One result per node, in node order.
+pub async fn exec_on_nodes(
+    ssh_config: &Path,
+    nodes: &[ClusterNode],
+    command: &[String],
+    parallel: usize,
+) -> Vec<NodeResult> {
+    let mut results: Vec<NodeResult> = stream::iter(nodes.iter().map(|node| async move {
+        let alias = node.alias();
+        let exit_code =
+            match run_prefixed(&alias, "ssh", &ssh_args(ssh_config, &alias, command)).await {
+                Ok(code) => code,
+                Err(e) => {
+                    error!(node = %alias, "Failed to run ssh: {}", e);
+                    None
+                }
+            };
+
+        NodeResult {
+            index: node.index,
+            node: alias,
+            exit_code,
+            error: None,
+        }
+    }))
+    .buffer_unordered(parallel.max(1))
+    .collect()
+    .await;
+
+    results.sort_by_key(|r| r.index);
+    results
+}
+
+/// Print a summary of per-node results.
+///
+/// # Returns
+/// * `true` if the command succeeded on every node.
+pub fn print_summary(results: &[NodeResult]) -> bool {
+    let failed: Vec<&NodeResult> = results.iter().filter(|r| !r.success()).collect();
+
+    if failed.is_empty() {
+        info!("Succeeded on all {} node(s)", results.len());
+        return true;
+    }
+
+    let failures: Vec<String> = failed
+        .iter()
+        .map(|r| match (&r.error, r.exit_code) {
+            (Some(error), _) => format!("{} ({})", r.node, error),
+            (None, Some(code)) => format!("{} (exit {})", r.node, code),
+            (None, None) => format!("{} (no exit code)", r.node),
+        })
+        .collect();
+    error!(
+        "Failed on {}/{} node(s): {}",
+        failed.len(),
+        results.len(),
+        failures.join(", ")
+    );
+
+    false
+}
+
+#[test]
+fn test_parse_node_selection() {
+    assert_eq!(parse_node_selection("0").unwrap(), vec![0]);
+    assert_eq!(parse_node_selection("3, 0-2,2").unwrap(), vec![0, 1, 2, 3]);
+    assert!(parse_node_selection("4-2").is_err());
+    assert!(parse_node_selection("a").is_err());
+}
+
+#[test]
+fn test_shell_quote() {
+    assert_eq!(shell_quote("/tmp/a b"), "'/tmp/a b'");
+    assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    assert_eq!(shell_quote_path("~/results"), "\"$HOME\"/'results'");
+    assert_eq!(shell_quote_path("~"), "\"$HOME\"");
+    assert_eq!(shell_quote_path("/opt/~x"), "'/opt/~x'");
+}
+
+#[tokio::test]
+async fn test_run_prefixed_returns_exit_code() -> Result<(), Box<dyn std::error::Error>> {
+    let ok = run_prefixed("local", "sh", &["-c".to_string(), "echo hi".to_string()]).await?;
+    let fail = run_prefixed("local", "sh", &["-c".to_string(), "exit 3".to_string()]).await?;
+
+    assert_eq!(ok, Some(0));
+    assert_eq!(fail, Some(3));
+
+    Ok(())
+}
@@ -0,0 +1,281 @@
+use aws_sdk_ec2::config::interceptors::{
+    BeforeTransmitInterceptorContextRef, FinalizerInterceptorContextRef,
+};
+use aws_sdk_ec2::config::retry::{RetryConfig, RetryMode};
+use aws_sdk_ec2::config::timeout::TimeoutConfig;
+use aws_sdk_ec2::config::{ConfigBag, Intercept, RuntimeComponents};
+use aws_smithy_runtime_api::box_error::BoxError;
+use aws_smithy_runtime_api::client::orchestrator::Metadata;
+use aws_smithy_runtime_api::client::retries::RequestAttempts;
+use aws_smithy_types::config_bag::{Storable, StoreReplace};
+use aws_smithy_types::error::display::DisplayErrorContext;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::{error, warn};
+
+use crate::logging::RETRY_TARGET;
+
+static POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+static SHARED_CONFIG: OnceLock<aws_config::SdkConfig> = OnceLock::new();
+
+/// How every EC2 call is retried when it is throttled (`RequestLimitExceeded`) or fails
+/// transiently (5xx, timeouts, dropped connections). Retries wait a random part of the backoff
+/// ("full jitter"), so parallel callers don't retry in lockstep.
+#[derive(clap::Args, Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts per EC2 call, including the first (1 turns retries off)
+    #[arg(long, global = true, default_value_t = 8)]
+    pub max_attempts: u32,
+
+    /// Delay before the first retry of an EC2 call, in milliseconds; it doubles with each retry
+    #[arg(long, global = true, default_value_t = 500)]
+    pub retry_backoff_ms: u64,
+
+    /// Longest delay between two retries, in seconds
+    #[arg(long, global = true, default_value_t = 20)]
+    pub max_retry_backoff: u64,
+
+    /// Give up on an EC2 call, retries included, after this many seconds
+    #[arg(long, global = true, default_value_t = 120)]
+    pub operation_timeout: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 8,
+            retry_backoff_ms: 500,
+            max_retry_backoff: 20,
+            operation_timeout: 120,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The longest the policy waits before retry number `retry` (counting from 1).
+    pub fn max_backoff(&self, retry: u32) -> Duration {
+        let initial = Duration::from_millis(self.retry_backoff_ms);
+        let doubled = initial.saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)));
+        doubled.min(Duration::from_secs(self.max_retry_backoff))
+    }
+
+    /// How long to wait before retry number `retry`: a random part of `max_backoff`, like the SDK
+    /// does for the calls themselves.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.max_backoff(retry).mul_f64(fastrand::f64())
+    }
+
+    fn retry_config(&self) -> RetryConfig {
+        // Adaptive mode also slows the whole client down once EC2 starts throttling, which is what
+        // launching many nodes at once needs
+        RetryConfig::standard()
+            .with_retry_mode(RetryMode::Adaptive)
+            .with_max_attempts(self.max_attempts.max(1))
+            .with_initial_backoff(Duration::from_millis(self.retry_backoff_ms))
+            .with_max_backoff(Duration::from_secs(self.max_retry_backoff))
+    }
+
+    fn timeout_config(&self) -> TimeoutConfig {
+        TimeoutConfig::builder()
+            .operation_timeout(Duration::from_secs(self.operation_timeout))
+            .build()
+    }
+}
+
+/// Set the policy for the rest of the process. Only the first call has an effect.
+pub fn configure(policy: RetryPolicy) {
+    let _ = POLICY.set(policy);
+}
+
+/// The policy set with `configure`, or the default one.
+pub fn policy() -> RetryPolicy {
+    POLICY.get().copied().unwrap_or_default()
+}
+
+/// An EC2 client whose calls all follow the retry policy, and log their retries.
+///
+/// The first config a client is made from is kept for the clients of the other AWS services used
+/// alongside EC2 (see `pricing_client` and `service_quotas_client`).
+pub fn client(shared_config: &aws_config::SdkConfig) -> aws_sdk_ec2::Client {
+    let _ = SHARED_CONFIG.set(shared_config.clone());
+    let policy = policy();
+    let config = aws_sdk_ec2::config::Builder::from(shared_config)
+        .retry_config(policy.retry_config())
+        .timeout_config(policy.timeout_config())
+        .interceptor(RetryLog {
+            max_attempts: policy.max_attempts.max(1),
+        })
+        .build();
+    aws_sdk_ec2::Client::from_conf(config)
+}
+
+/// The config the EC2 client was made from.
+fn shared_config() -> Result<&'static aws_config::SdkConfig, Box<dyn std::error::Error>> {
+    SHARED_CONFIG
+        .get()
+        .ok_or_else(|| "No AWS client has been set up".into())
+}
+
+/// A Price List API client with the same credentials and retry policy as the EC2 client. The API
+/// is only served from a few regions, so it always goes to us-east-1 (prices of every region are
+/// there).
+pub fn pricing_client() -> Result<aws_sdk_pricing::Client, Box<dyn std::error::Error>> {
+    let policy = policy();
+    let config = aws_sdk_pricing::config::Builder::from(shared_config()?)
+        .region(aws_sdk_pricing::config::Region::new("us-east-1"))
+        .retry_config(policy.retry_config())
+        .timeout_config(policy.timeout_config())
+        .interceptor(RetryLog {
+            max_attempts: policy.max_attempts.max(1),
+        })
+        .build();
+    Ok(aws_sdk_pricing::Client::from_conf(config))
+}
+
+/// A Service Quotas client with the same credentials, region and retry policy as the EC2 client.
+pub fn service_quotas_client() -> Result<aws_sdk_servicequotas::Client, Box<dyn std::error::Error>>
+{
+    let policy = policy();
+    let config = aws_sdk_servicequotas::config::Builder::from(shared_config()?)
+        .retry_config(policy.retry_config())
+        .timeout_config(policy.timeout_config())
+        .interceptor(RetryLog {
+            max_attempts: policy.max_attempts.max(1),
+        })
+        .build();
+    Ok(aws_sdk_servicequotas::Client::from_conf(config))
+}
+
+/// Why the previous attempt of a call failed, kept for the message about retrying it.
+#[derive(Debug, Clone)]
+struct LastFailure(String);
+
+impl Storable for LastFailure {
+    type Storer = StoreReplace<Self>;
+}
+
+/// Logs each retry of a call as a warning, and a call that fails after retries as an error. A call
+/// that fails on its first attempt isn't logged here; the caller reports it.
+#[derive(Debug)]
+struct RetryLog {
+    max_attempts: u32,
+}
+
+fn operation(cfg: &ConfigBag) -> String {
+    cfg.load::<Metadata>()
+        .map_or_else(|| "EC2 call".to_string(), |m| m.name().to_string())
+}
+
+fn attempts(cfg: &ConfigBag) -> u32 {
+    cfg.load::<RequestAttempts>().map_or(1, |a| a.attempts())
+}
+
+/// The `<Code>` of an EC2 error response, e.g. `RequestLimitExceeded`.
+fn error_code(body: &str) -> Option<&str> {
+    let start = body.find("<Code>")? + "<Code>".len();
+    let len = body[start..].find("</Code>")?;
+    Some(&body[start..start + len])
+}
+
+fn describe_failure(context: &FinalizerInterceptorContextRef<'_>) -> Option<String> {
+    let error = match context.output_or_error() {
+        Some(Err(error)) => error,
+        _ => return None,
+    };
+    let response = match context.response() {
+        Some(response) => response,
+        None => return Some(DisplayErrorContext(error).to_string()),
+    };
+    let code = response
+        .body()
+        .bytes()
+        .and_then(|body| std::str::from_utf8(body).ok())
+        .and_then(error_code)
+        .unwrap_or("no error code");
+    Some(format!("{} (HTTP {})", code, response.status()))
+}
+
+impl Intercept for RetryLog {
+    fn name(&self) -> &'static str {
+        "RetryLog"
+    }
+
+    fn read_before_attempt(
+        &self,
+        _context: &BeforeTransmitInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let attempt = attempts(cfg);
+        if attempt > 1 {
+            let reason = cfg
+                .load::<LastFailure>()
+                .map_or("the previous attempt failed", |f| f.0.as_str());
+            warn!(
+                target: RETRY_TARGET,
+                attempt,
+                max_attempts = self.max_attempts,
+                "Retrying {} after {}",
+                operation(cfg),
+                reason
+            );
+        }
+        Ok(())
+    }
+
+    fn read_after_attempt(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        if let Some(failure) = describe_failure(context) {
+            cfg.interceptor_state().store_put(LastFailure(failure));
+        }
+        Ok(())
+    }
+
+    fn read_after_execution(
+        &self,
+        context: &FinalizerInterceptorContextRef<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let attempt = attempts(cfg);
+        if attempt > 1 {
+            if let Some(failure) = describe_failure(context) {
+                error!(
+                    target: RETRY_TARGET,
+                    attempts = attempt,
+                    "Giving up on {} after {} attempts: {}",
+                    operation(cfg),
+                    attempt,
+                    failure
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_backoff() {
+    let policy = RetryPolicy::default();
+    assert_eq!(policy.max_backoff(1), Duration::from_millis(500));
+    assert_eq!(policy.max_backoff(2), Duration::from_secs(1));
+    assert_eq!(policy.max_backoff(4), Duration::from_secs(4));
+    assert_eq!(policy.max_backoff(7), Duration::from_secs(20));
+    assert_eq!(policy.max_backoff(100), Duration::from_secs(20));
+    for retry in 1..10 {
+        assert!(policy.backoff(retry) <= policy.max_backoff(retry));
+    }
+
+    assert_eq!(
+        error_code(
+            "<Response><Errors><Error><Code>RequestLimitExceeded</Code>\
+             <Message>Request limit exceeded.</Message></Error></Errors></Response>"
+        ),
+        Some("RequestLimitExceeded")
+    );
+    assert_eq!(error_code("<html>Bad Gateway</html>"), None);
+}
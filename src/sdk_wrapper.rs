@@ -1,26 +1,271 @@
 use aws_sdk_ec2::error::ProvideErrorMetadata;
+use aws_sdk_ec2::operation::run_instances::RunInstancesError;
 use aws_sdk_ec2::types::Instance;
 use aws_sdk_ec2::Client;
 use aws_sdk_ec2::{error::SdkError, types};
-use base64::prelude::*;
+use futures::FutureExt;
+use std::collections::BTreeMap;
+use tracing::{debug, error, info, warn};
+
+use crate::dry_run;
+use crate::logging::CLEANUP_TARGET;
+use crate::shutdown::{self, Tracked};
+use crate::teardown;
+#[cfg(test)]
 use termion::{color, style};
 
+/// Name of the EC2 key pair that instances are launched with.
+pub const KEY_NAME: &str = "adam-hwkey";
+
+/// Tag holding the time (RFC 3339, UTC) after which a resource may be reaped.
+pub const EXPIRES_AT_TAG: &str = "expires-at";
+
+/// The `expires-at` tag value for something created now that should live for `ttl`.
+pub fn expires_at(ttl: tokio::time::Duration) -> Result<String, Box<dyn std::error::Error>> {
+    let at = aws_sdk_ec2::primitives::DateTime::from(std::time::SystemTime::now() + ttl);
+    Ok(at.fmt(aws_sdk_ec2::primitives::DateTimeFormat::DateTime)?)
+}
+
+/// Adds the `expires-at` tag to a tag specification when the resource has a TTL.
+trait ExpiryTag {
+    fn expiry_tag(self, expires_at: Option<&str>) -> Self;
+}
+
+impl ExpiryTag for types::builders::TagSpecificationBuilder {
+    fn expiry_tag(self, expires_at: Option<&str>) -> Self {
+        match expires_at {
+            Some(expires_at) => self.tags(
+                types::Tag::builder()
+                    .key(EXPIRES_AT_TAG)
+                    .value(expires_at)
+                    .build(),
+            ),
+            None => self,
+        }
+    }
+}
+
+/// How the nodes of a cluster are addressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpMode {
+    /// A private and a public IPv4 address
+    Ipv4,
+    /// A private and a public IPv4 address, plus an IPv6 address
+    #[default]
+    DualStack,
+    /// A private IPv4 address (for traffic within the VPC) and an IPv6 address, but no public
+    /// IPv4 address, so nodes are only reachable (and can only reach out) over IPv6 and no public
+    /// IPv4 charges are incurred
+    Ipv6Only,
+}
+
+impl IpMode {
+    pub fn public_ipv4(self) -> bool {
+        self != IpMode::Ipv6Only
+    }
+
+    pub fn ipv6(self) -> bool {
+        self != IpMode::Ipv4
+    }
+}
+
+/// Which nodes of a cluster get a public IPv4 address (if the `IpMode` has them at all).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PublicIp {
+    /// Every node
+    All,
+    /// Only the head node; the workers are reached through it. There is no NAT gateway, so the
+    /// workers can only reach out to the internet over IPv6, which takes an `IpMode` with IPv6.
+    #[default]
+    Head,
+}
+
+/// How many of a cluster's nodes have to launch for `create_cluster` to keep the cluster. If fewer
+/// do, everything it created is torn down again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LaunchPolicy {
+    /// Every node, each launched by its own request
+    #[default]
+    All,
+    /// Every node, launched by a single request (`min_count` = `max_count`) so that EC2 starts all
+    /// of them or none. The nodes look the head node's IP up when they boot, and if only the head
+    /// node should have a public IPv4 address it gets an Elastic IP instead, since one request
+    /// gives every node the same interfaces. That takes every node coming from one template; with
+    /// several node groups, the head node is launched on its own first and then each group's
+    /// workers by one request, and if a group fails the nodes already launched are terminated again
+    Atomic,
+    /// At least this many nodes, head node included; the others are given up on
+    AtLeast(u64),
+    /// Whatever launches, as long as the head node does
+    BestEffort,
+}
+
+impl LaunchPolicy {
+    /// How many of a cluster's `num_instances` nodes have to launch.
+    pub fn required(self, num_instances: u64) -> u64 {
+        match self {
+            LaunchPolicy::All | LaunchPolicy::Atomic => num_instances,
+            LaunchPolicy::AtLeast(min_nodes) => min_nodes,
+            LaunchPolicy::BestEffort => 1,
+        }
+    }
+}
+
+/// The part a node plays in its cluster, recorded in the `role` tag.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NodeRole {
+    /// Node 0, which is also what a lone instance counts as
+    #[default]
+    Head,
+    Worker,
+}
+
+impl NodeRole {
+    pub fn for_index(node_index: u64) -> Self {
+        if node_index == 0 {
+            NodeRole::Head
+        } else {
+            NodeRole::Worker
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NodeRole::Head => "head",
+            NodeRole::Worker => "worker",
+        }
+    }
+}
+
+/// Tag recording whether an instance is the head node or a worker, or which node group it is in.
+pub const ROLE_TAG: &str = "role";
+
+#[allow(unused)]
 #[derive(Debug, Clone)]
 pub struct InstanceTemplate<'a> {
     pub availability_zone: &'a str,
     pub ami_image_id: &'a str,
     pub instance_type: types::InstanceType,
+    /// Instance types to fall back on, in order, when EC2 has no capacity for `instance_type` in
+    /// the AZ (or doesn't offer it there).
+    pub fallback_instance_types: Vec<types::InstanceType>,
     pub subnet_id: &'a str,
     pub security_group_id: &'a str,
     pub num_ifaces: u64,
     pub use_efa: bool,
     pub user_data: Option<&'a str>,
     pub project_tag: &'a str,
+    /// Value of the `expires-at` tag.
+    pub expires_at: Option<&'a str>,
+    /// The instance shuts itself down once it expires, so it terminates (rather than stops) when
+    /// shut down from the inside and the shutdown timer gets rid of it for good.
+    pub shutdown_on_expiry: bool,
+    /// Which addresses the first interface gets (the subnet needs an IPv6 block for IPv6).
+    pub ip_mode: IpMode,
+    /// Which nodes of the cluster get a public IPv4 address.
+    pub public_ip: PublicIp,
+    pub role: NodeRole,
+    /// Name of the node group the instance belongs to, which goes in the `role` tag instead of
+    /// `head` or `worker`.
+    pub node_group: Option<&'a str>,
+    /// Name of the placement group to launch the instance into.
+    pub placement_group: Option<&'a str>,
+}
+
+impl InstanceTemplate<'_> {
+    /// Whether the instance gets a public IPv4 address on its first interface.
+    pub fn has_public_ipv4(&self) -> bool {
+        self.ip_mode.public_ipv4()
+            && (self.public_ip == PublicIp::All || self.role == NodeRole::Head)
+    }
+
+    /// Value of the instance's `role` tag.
+    pub fn role_tag(&self) -> &str {
+        self.node_group.unwrap_or(self.role.as_str())
+    }
+
+    /// Every instance type the instance may be launched as, in order of preference.
+    pub fn instance_types(&self) -> Vec<&types::InstanceType> {
+        std::iter::once(&self.instance_type)
+            .chain(&self.fallback_instance_types)
+            .collect()
+    }
+}
+
+/// Whether a failed `RunInstances` could go through with another instance type: EC2 has no
+/// capacity for the type in the AZ right now, doesn't offer it there at all, or the type's family
+/// has used up its vCPU quota (another family, or a smaller type, may still fit).
+pub fn is_capacity_error(code: Option<&str>) -> bool {
+    matches!(
+        code,
+        Some("InsufficientInstanceCapacity") | Some("Unsupported") | Some("VcpuLimitExceeded")
+    )
 }
 
 pub async fn create_instance_sdk<'a>(
     aws_client: &aws_sdk_ec2::Client,
     template: &InstanceTemplate<'a>,
+) -> Result<Vec<Instance>, Box<dyn std::error::Error>> {
+    create_instances_sdk(aws_client, template, 1).await
+}
+
+/// Launch `count` instances from one template with a single request, which EC2 carries out for
+/// all of them or none (`min_count` = `max_count`). Each instance's `ami_launch_index` says which
+/// of them it is.
+///
+/// If EC2 has no capacity for the template's instance type, its fallback types are tried in order;
+/// the type the instances were launched as is in their `instance_type`.
+pub async fn create_instances_sdk<'a>(
+    aws_client: &aws_sdk_ec2::Client,
+    template: &InstanceTemplate<'a>,
+    count: i32,
+) -> Result<Vec<Instance>, Box<dyn std::error::Error>> {
+    let instance_types = template.instance_types();
+    let mut last_error = None;
+    for (i, instance_type) in instance_types.iter().enumerate() {
+        let attempt = InstanceTemplate {
+            instance_type: (*instance_type).clone(),
+            ..template.clone()
+        };
+        match run_instances(aws_client, &attempt, count).await {
+            Ok(instances) => return Ok(instances),
+            Err(e) if is_capacity_error(run_instances_code(e.as_ref())) => {
+                if let Some(next) = instance_types.get(i + 1) {
+                    warn!(
+                        "Can't launch {} in {}, trying {}: {}",
+                        instance_type.as_str(),
+                        template.availability_zone,
+                        next.as_str(),
+                        e
+                    );
+                }
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    match last_error {
+        Some(e) => Err(e),
+        None => Err("No instance type to launch!".into()),
+    }
+}
+
+/// The EC2 error code of a failed `run_instances`, if the request got as far as EC2.
+fn run_instances_code<'e>(e: &'e (dyn std::error::Error + 'static)) -> Option<&'e str> {
+    e.downcast_ref::<SdkError<RunInstancesError>>()
+        .and_then(|e| e.code())
+}
+
+/// Launch `count` instances of the template's instance type (and only that one) with a single
+/// request.
+async fn run_instances<'a>(
+    aws_client: &aws_sdk_ec2::Client,
+    template: &InstanceTemplate<'a>,
+    count: i32,
 ) -> Result<Vec<Instance>, Box<dyn std::error::Error>> {
     // let placement_group_id = "pg-026f038784dd1240b";
 
@@ -36,6 +281,9 @@ pub async fn create_instance_sdk<'a>(
     //         .build())
     //     .build();
 
+    // Don't start anything new once an interrupt has come in
+    shutdown::check()?;
+
     // Create network interfaces
     let mut network_interfaces = Vec::new();
     for i in 0..template.num_ifaces {
@@ -43,7 +291,8 @@ pub async fn create_instance_sdk<'a>(
             let net_iface = types::InstanceNetworkInterfaceSpecification::builder()
                 .subnet_id(template.subnet_id)
                 .delete_on_termination(true)
-                .associate_public_ip_address(if i == 0 { true } else { false })
+                .associate_public_ip_address(i == 0 && template.has_public_ipv4())
+                .set_ipv6_address_count((i == 0 && template.ip_mode.ipv6()).then_some(1))
                 .device_index(i.try_into()?)
                 .network_card_index(i.try_into()?)
                 .groups(template.security_group_id)
@@ -54,14 +303,15 @@ pub async fn create_instance_sdk<'a>(
             let net_iface = types::InstanceNetworkInterfaceSpecification::builder()
                 .subnet_id(template.subnet_id)
                 .delete_on_termination(true)
-                .associate_public_ip_address(if i == 0 { true } else { false })
+                .associate_public_ip_address(i == 0 && template.has_public_ipv4())
+                .set_ipv6_address_count((i == 0 && template.ip_mode.ipv6()).then_some(1))
                 .device_index(i.try_into()?)
                 .network_card_index(i.try_into()?)
                 .groups(template.security_group_id)
                 .build();
             network_interfaces.push(net_iface);
 
-            panic!("Only EFAs are supported as network interfaces right now!");
+            return Err("Only EFAs are supported as network interfaces right now!".into());
         }
     }
 
@@ -78,14 +328,19 @@ pub async fn create_instance_sdk<'a>(
     // Create the instance with specific options
     let mut run_instance_builder = aws_client
         .run_instances()
-        .key_name("adam-hwkey")
+        .key_name(KEY_NAME)
         .image_id(template.ami_image_id)
         .instance_type(template.instance_type.clone())
         .disable_api_termination(false)
         .set_network_interfaces(Some(network_interfaces)) // Using `set` here overrides any other usages of `network_interfaces` in the builder
         // .block_device_mappings(block_dev)
-        .min_count(1)
-        .max_count(1)
+        .min_count(count)
+        .max_count(count)
+        .set_placement(
+            template
+                .placement_group
+                .map(|name| types::Placement::builder().group_name(name).build()),
+        )
         .tag_specifications(
             types::TagSpecification::builder()
                 .resource_type(types::ResourceType::Instance)
@@ -95,6 +350,13 @@ pub async fn create_instance_sdk<'a>(
                         .value(template.project_tag)
                         .build(),
                 )
+                .tags(
+                    types::Tag::builder()
+                        .key(ROLE_TAG)
+                        .value(template.role_tag())
+                        .build(),
+                )
+                .expiry_tag(template.expires_at)
                 .build(),
         )
         .iam_instance_profile(
@@ -112,33 +374,70 @@ pub async fn create_instance_sdk<'a>(
                 .build(),
         );
 
-    // Add user data only if it is given
+    if template.shutdown_on_expiry {
+        run_instance_builder = run_instance_builder
+            .instance_initiated_shutdown_behavior(types::ShutdownBehavior::Terminate);
+    }
+
+    // Add user data only if it is given (the template holds plain text; it is encoded exactly once here)
     run_instance_builder = match template.user_data {
-        Some(user_data) => {
-            let user_data = user_data.as_bytes();
-            let user_data_b64 = BASE64_STANDARD.encode(user_data);
-            run_instance_builder.user_data(user_data_b64)
-        }
+        Some(user_data) => run_instance_builder.user_data(crate::user_data::encode(user_data)?),
         None => run_instance_builder,
     };
 
-    let run_instances_req = run_instance_builder.send().await;
+    let mut ids = vec![template.subnet_id, template.security_group_id];
+    ids.extend(template.placement_group);
+    let run_instances_req = dry_run::send(
+        &format!(
+            "RunInstances {} x {} in {} (subnet {})",
+            count,
+            template.instance_type.as_str(),
+            template.availability_zone,
+            template.subnet_id
+        ),
+        &ids,
+        run_instance_builder.dry_run(dry_run::enabled()).send(),
+    )
+    .await;
 
     // Handle the result of the attempted instance creation
     let instances = match run_instances_req {
-        Ok(val) => {
-            println!("Creation did not error. Checking for instance...");
+        // Dry run: stand in for the instances that would have been launched
+        Ok(None) => (0..count)
+            .map(|i| {
+                Instance::builder()
+                    .instance_id(dry_run::placeholder("i"))
+                    .instance_type(template.instance_type.clone())
+                    .private_ip_address(format!("10.0.0.{}", 10 + i))
+                    .ami_launch_index(i)
+                    .build()
+            })
+            .collect(),
+        Ok(Some(val)) => {
             let instances = match val.instances {
                 Some(instances) => instances,
                 None => {
-                    println!("No instances were returned in the response!");
+                    error!("No instances were returned in the response!");
                     return Err("No instances were returned in the response!".into());
                 }
             };
-            println!("Successfully created instance: {:#?}", instances);
+            for instance in &instances {
+                info!(
+                    instance_id = instance.instance_id(),
+                    private_ip = instance.private_ip_address(),
+                    availability_zone = template.availability_zone,
+                    "Launched {} instance",
+                    template.instance_type.as_str()
+                );
+            }
+
+            // Record the instances so they are torn down if we get interrupted
+            for instance_id in instances.iter().filter_map(|i| i.instance_id()) {
+                shutdown::track(instance_id, Tracked::Instance(instance_id.to_string()));
+            }
 
-            if instances.len() != 1 {
-                println!("[WARNING] Expected to create a single instance, but created {} instead! This is probably a serious problem with the tool; report immediately!", instances.len());
+            if instances.len() != count as usize {
+                warn!("Expected to create {} instance(s), but created {} instead! This is probably a serious problem with the tool; report immediately!", count, instances.len());
             }
 
             instances
@@ -147,26 +446,27 @@ pub async fn create_instance_sdk<'a>(
             SdkError::ServiceError(_) => {
                 match err.meta().code() {
                     Some(code) => {
-                        println!("Got ServiceError with code: {}", code);
+                        debug!(code, "RunInstances failed with a service error");
                         return Err(Box::new(err));
                     }
                     None => {
-                        println!("Got ServiceError without code: {:#?}", err);
+                        debug!(error = ?err, "RunInstances failed with a service error without code");
                         return Err(Box::new(err));
                     }
                 };
             }
             _ => {
-                println!("Got some other error");
+                debug!(error = %err, "RunInstances failed");
                 return Err(Box::new(err));
             }
         },
     };
 
-    // Return a vector of instances. Should only contain a single value!
+    // Return a vector of instances. Should contain exactly `count` values!
     Ok(instances)
 }
 
+#[allow(unused)]
 #[derive(Debug, Clone)]
 pub struct ClusterTemplate<'a> {
     pub cluster_name: &'a str,
@@ -175,13 +475,172 @@ pub struct ClusterTemplate<'a> {
     pub attach_shared_ebs: bool,
     pub shared_ebs_volume_size: Option<u64>,
     pub project_tag: &'a str,
+    /// How long the cluster may live; every resource is tagged with the resulting `expires-at` time
+    /// so that `reap` can tear it down once it is up.
+    pub ttl: Option<tokio::time::Duration>,
+    /// Also shut each node down from the inside once the TTL is up, in case nothing reaps it.
+    pub shutdown_on_expiry: bool,
+    /// Give the head node an Elastic IP, which (unlike an auto-assigned public IP) stays the same
+    /// across stop/start.
+    pub elastic_ip: bool,
+    /// Groups of nodes with their own instance type, interfaces and user data, e.g. a small head
+    /// node plus GPU workers. Nodes are numbered through the groups in order, so the head node is
+    /// the first node of the first group. Without any, the cluster is `num_instances` nodes launched
+    /// from `instance_template`; with some, `num_instances` must be their total count.
+    pub node_groups: Vec<NodeGroup<'a>>,
+    /// How many nodes have to launch for the cluster to be kept.
+    pub launch_policy: LaunchPolicy,
+    /// Launch the nodes into a cluster placement group made for them, which packs them close
+    /// together for low-latency networking.
+    pub placement_group: bool,
+}
+
+/// A group of identical nodes within a cluster. Everything not set here (AMI, networking, tags)
+/// comes from the cluster's instance template.
+#[derive(Debug, Clone)]
+pub struct NodeGroup<'a> {
+    /// Put in the `role` tag of the group's nodes.
+    pub name: &'a str,
+    pub count: u64,
+    pub instance_type: types::InstanceType,
+    /// Instance types to fall back on, in order, when there is no capacity for `instance_type`.
+    pub fallback_instance_types: Vec<types::InstanceType>,
+    pub num_ifaces: u64,
+    pub use_efa: bool,
+    /// The group's own user-data template, used instead of the instance template's.
+    pub user_data: Option<&'a str>,
+}
+
+impl<'a> ClusterTemplate<'a> {
+    /// The position of the node group node `node_index` belongs to, if the cluster has node groups.
+    fn group_index(&self, node_index: u64) -> Option<usize> {
+        let mut first = 0;
+        for (i, group) in self.node_groups.iter().enumerate() {
+            if node_index < first + group.count {
+                return Some(i);
+            }
+            first += group.count;
+        }
+        None
+    }
+
+    /// The node group node `node_index` belongs to, if the cluster has node groups.
+    pub fn node_group(&self, node_index: u64) -> Option<&NodeGroup<'a>> {
+        self.group_index(node_index).map(|i| &self.node_groups[i])
+    }
+
+    /// Split nodes into runs of consecutive nodes of the same node group, which can be launched
+    /// from one template.
+    pub fn batches(&self, node_indices: &[u64]) -> Vec<Vec<u64>> {
+        let mut batches: Vec<Vec<u64>> = Vec::new();
+        for &i in node_indices {
+            match batches.last_mut() {
+                Some(batch)
+                    if batch.last().is_some_and(|&last| last + 1 == i)
+                        && self.group_index(i) == self.group_index(i - 1) =>
+                {
+                    batch.push(i)
+                }
+                _ => batches.push(vec![i]),
+            }
+        }
+        batches
+    }
+
+    /// Whether the atomic policy launches the whole cluster with a single request, which it does
+    /// when every node comes from the same template (no node groups, or just one).
+    pub fn launches_in_one_request(&self) -> bool {
+        self.launch_policy == LaunchPolicy::Atomic && self.node_groups.len() <= 1
+    }
+
+    /// Whether the head node gets an Elastic IP: when asked for, or when it should be the only node
+    /// with a public IPv4 address but is launched by the same request as the workers.
+    pub fn head_elastic_ip(&self) -> bool {
+        self.elastic_ip
+            || (self.launches_in_one_request()
+                && self.num_instances > 1
+                && self.node_template(0).has_public_ipv4()
+                && !self.node_template(1).has_public_ipv4())
+    }
+
+    /// The template node `node_index` is launched from (before its user data is rendered).
+    pub fn node_template(&self, node_index: u64) -> InstanceTemplate<'a> {
+        let role = NodeRole::for_index(node_index);
+        match self.node_group(node_index) {
+            Some(group) => InstanceTemplate {
+                instance_type: group.instance_type.clone(),
+                fallback_instance_types: group.fallback_instance_types.clone(),
+                num_ifaces: group.num_ifaces,
+                use_efa: group.use_efa,
+                user_data: group.user_data.or(self.instance_template.user_data),
+                role,
+                node_group: Some(group.name),
+                ..self.instance_template.clone()
+            },
+            None => InstanceTemplate {
+                role,
+                ..self.instance_template.clone()
+            },
+        }
+    }
+}
+
+/// Tag recording each instance's position in its cluster (`0` is the head node).
+pub const NODE_INDEX_TAG: &str = "node-index";
+
+/// Device name the shared EBS volume is attached under on every node.
+pub const SHARED_VOLUME_DEVICE: &str = "/dev/sdf";
+
+/// Everything `create_cluster` created, so that it can be torn down again with `cleanup_cluster`.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct ClusterCleanup {
+    pub instance_ids: Vec<String>,
+    pub volume_id: Option<String>,
+    /// Allocation ID of the head node's Elastic IP.
+    #[serde(default)]
+    pub elastic_ip: Option<String>,
+    /// Name of the placement group the nodes were launched into.
+    #[serde(default)]
+    pub placement_group: Option<String>,
+    /// The instance type each instance was launched as (which can be a fallback type), by ID.
+    #[serde(default)]
+    pub instance_types: BTreeMap<String, String>,
+}
+
+impl ClusterCleanup {
+    /// Record the instances of launched nodes, along with the type each was launched as.
+    pub fn add_instances(&mut self, instances: &[Instance]) {
+        for instance in instances {
+            if let Some(instance_id) = instance.instance_id() {
+                self.instance_ids.push(instance_id.to_string());
+                if let Some(instance_type) = instance.instance_type() {
+                    self.instance_types
+                        .insert(instance_id.to_string(), instance_type.as_str().to_string());
+                }
+            }
+        }
+    }
 }
 
 /// Create a cluster of instances based on a template.
+///
+/// The head node (node 0) is launched first so that its private IP can be rendered into the user
+/// data of the remaining nodes, which are then launched concurrently, unless the atomic policy
+/// launches the whole cluster with one request (see `ClusterTemplate::launches_in_one_request`). If
+/// the instance template has user data, it is treated as a template and rendered per node (see
+/// `user_data::render`). Each node is launched from its node group's settings, if the cluster has
+/// node groups.
+///
+/// How the workers are launched, and how many of them have to come up, depends on the template's
+/// `launch_policy`. If fewer nodes launch than it needs, or anything else fails part-way, whatever
+/// was already created is torn down again before returning.
+///
+/// # Returns
+/// * A `ClusterCleanup` that can be used to tear the cluster down, or errors.
 pub async fn create_cluster<'a>(
     aws_client: &aws_sdk_ec2::Client,
     template: &ClusterTemplate<'a>,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<ClusterCleanup, Box<dyn std::error::Error>> {
     // Verify settings
     if template.num_instances < 1 {
         return Err("Number of instances must be at least 1!".into());
@@ -199,291 +658,1919 @@ pub async fn create_cluster<'a>(
         return Err("If attaching shared EBS volume, must specify its size!".into());
     }
 
-    // Create the shared block storage
-    let ebs_vol_id = if template.attach_shared_ebs {
-        let ebs_vol = aws_client
-            .create_volume()
-            .availability_zone(template.instance_template.availability_zone)
-            .size(template.shared_ebs_volume_size.unwrap().try_into()?)
-            .volume_type(types::VolumeType::Gp3)
-            .tag_specifications(
-                types::TagSpecification::builder()
-                    .resource_type(types::ResourceType::Volume)
-                    .tags(
-                        types::Tag::builder()
-                            .key("project")
-                            .value(template.instance_template.project_tag)
-                            .build(),
-                    )
-                    .build(),
+    if template.shutdown_on_expiry && template.ttl.is_none() {
+        return Err("Shutting down on expiry needs a TTL!".into());
+    }
+
+    if let LaunchPolicy::AtLeast(min_nodes) = template.launch_policy {
+        if min_nodes < 1 || min_nodes > template.num_instances {
+            return Err(format!(
+                "A cluster of {} nodes can't need at least {} of them to launch!",
+                template.num_instances, min_nodes
             )
-            .send()
-            .await?;
+            .into());
+        }
+    }
+
+    if template.elastic_ip && !template.instance_template.ip_mode.public_ipv4() {
+        return Err(
+            "An Elastic IP needs public IPv4, which the IPv6-only mode doesn't have!".into(),
+        );
+    }
+
+    if !template.node_groups.is_empty() {
+        if template.node_groups.iter().any(|g| g.count < 1) {
+            return Err("Every node group needs at least 1 node!".into());
+        }
+        let total: u64 = template.node_groups.iter().map(|g| g.count).sum();
+        if total != template.num_instances {
+            return Err(format!(
+                "Node groups add up to {} nodes, but the cluster has {}!",
+                total, template.num_instances
+            )
+            .into());
+        }
+    }
+
+    let expires_at = match template.ttl {
+        Some(ttl) => Some(expires_at(ttl)?),
+        None => None,
+    };
+    let mut cluster_cleanup = ClusterCleanup::default();
 
-        Some(ebs_vol.volume_id.unwrap())
+    // Create the shared block storage
+    if template.attach_shared_ebs {
+        cluster_cleanup.volume_id =
+            Some(create_shared_volume(aws_client, template, expires_at.as_deref()).await?);
+    }
+
+    // Create the placement group, and launch every node into it
+    let placement_group = if template.placement_group {
+        match create_placement_group(aws_client, template.project_tag, expires_at.as_deref()).await
+        {
+            Ok(name) => Some(name),
+            Err(e) => {
+                error!("Failed to create placement group: {}", e);
+                return Err(roll_back(aws_client, cluster_cleanup, e).await);
+            }
+        }
     } else {
         None
     };
+    cluster_cleanup.placement_group = placement_group.clone();
+    let template = &ClusterTemplate {
+        instance_template: InstanceTemplate {
+            placement_group: placement_group.as_deref(),
+            ..template.instance_template.clone()
+        },
+        ..template.clone()
+    };
 
-    // Create VPC
-    // Create a VPC
-    let vpc = aws_client
-        .create_vpc()
-        .cidr_block("10.0.0.0/24")
+    // Launch the nodes
+    let launched = if template.launches_in_one_request() {
+        let node_indices: Vec<u64> = (0..template.num_instances).collect();
+        launch_node_batches(
+            aws_client,
+            template,
+            &node_indices,
+            None,
+            expires_at.as_deref(),
+        )
+        .await
+        .map(|instances| cluster_cleanup.add_instances(&instances))
+    } else {
+        launch_head_then_workers(
+            aws_client,
+            template,
+            &mut cluster_cleanup,
+            expires_at.as_deref(),
+        )
+        .await
+    };
+    if let Err(e) = launched {
+        return Err(roll_back(aws_client, cluster_cleanup, e).await);
+    }
+    if (cluster_cleanup.instance_ids.len() as u64) < template.num_instances {
+        warn!(
+            launch_policy = ?template.launch_policy,
+            "Only {} of the {} nodes of cluster {} launched, going ahead without the others (the \
+             nodes' user data still has a cluster_size of {})",
+            cluster_cleanup.instance_ids.len(),
+            template.num_instances,
+            template.cluster_name,
+            template.num_instances
+        );
+    }
+
+    // Give the head node its stable address (or, if it was launched along with workers without a
+    // public IPv4 address, its only public one)
+    if template.head_elastic_ip() {
+        let head_id = cluster_cleanup.instance_ids[0].clone();
+        let associated = match allocate_elastic_ip(
+            aws_client,
+            template.project_tag,
+            expires_at.as_deref(),
+        )
+        .await
+        {
+            Ok(allocation_id) => {
+                cluster_cleanup.elastic_ip = Some(allocation_id.clone());
+                associate_elastic_ip(aws_client, &allocation_id, &head_id).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = associated {
+            error!("Failed to give the head node an Elastic IP: {}", e);
+            return Err(roll_back(aws_client, cluster_cleanup, e).await);
+        }
+    }
+
+    // Attach the shared volume once every node is running
+    if let Some(volume_id) = cluster_cleanup.volume_id.clone() {
+        if let Err(e) =
+            attach_shared_volume(aws_client, &volume_id, &cluster_cleanup.instance_ids).await
+        {
+            error!("Failed to attach shared volume: {}", e);
+            return Err(roll_back(aws_client, cluster_cleanup, e).await);
+        }
+    }
+
+    info!(
+        instance_ids = ?cluster_cleanup.instance_ids,
+        volume_id = cluster_cleanup.volume_id.as_deref(),
+        elastic_ip = cluster_cleanup.elastic_ip.as_deref(),
+        placement_group = cluster_cleanup.placement_group.as_deref(),
+        instance_types = ?cluster_cleanup.instance_types,
+        "Created cluster {} with {} instance(s)",
+        template.cluster_name,
+        cluster_cleanup.instance_ids.len()
+    );
+
+    Ok(cluster_cleanup)
+}
+
+/// Launch the head node of a cluster on its own, then the remaining nodes concurrently with its
+/// private IP rendered into their user data, as the launch policy says. Whatever launches is added
+/// to `cluster_cleanup`, so that it can be torn down again if this fails.
+async fn launch_head_then_workers<'a>(
+    aws_client: &aws_sdk_ec2::Client,
+    template: &ClusterTemplate<'a>,
+    cluster_cleanup: &mut ClusterCleanup,
+    expires_at: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let head = match launch_cluster_nodes(aws_client, template, &[0], 1, None, expires_at).await {
+        Ok(instances) => instances,
+        Err(e) => {
+            error!("Failed to launch head node: {}", e);
+            return Err(e);
+        }
+    };
+    cluster_cleanup.add_instances(&head);
+    let head_node_ip = head
+        .first()
+        .and_then(|i| i.private_ip_address())
+        .map(|ip| ip.to_string());
+    debug!(head_node_ip = head_node_ip.as_deref(), "Launched head node");
+
+    let worker_indices: Vec<u64> = (1..template.num_instances).collect();
+    let workers = match template.launch_policy {
+        LaunchPolicy::Atomic => {
+            launch_node_batches(
+                aws_client,
+                template,
+                &worker_indices,
+                head_node_ip.as_deref(),
+                expires_at,
+            )
+            .await
+        }
+        policy => {
+            let required = policy.required(template.num_instances).saturating_sub(1);
+            launch_cluster_nodes(
+                aws_client,
+                template,
+                &worker_indices,
+                required.try_into()?,
+                head_node_ip.as_deref(),
+                expires_at,
+            )
+            .await
+        }
+    };
+    match workers {
+        Ok(instances) => cluster_cleanup.add_instances(&instances),
+        Err(e) => {
+            error!("Failed to launch worker nodes: {}", e);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Create a cluster placement group for the nodes of a cluster, tagged like the rest of it.
+///
+/// # Returns
+/// * The name of the group (a placeholder in a dry run), or errors.
+pub async fn create_placement_group(
+    aws_client: &aws_sdk_ec2::Client,
+    project_tag: &str,
+    expires_at: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    shutdown::check()?;
+    // Names are unique per region, so a cluster that is created again doesn't clash with the last one
+    let name = format!("{}-{:08x}", project_tag, fastrand::u32(..));
+    let create = aws_client
+        .create_placement_group()
+        .dry_run(dry_run::enabled())
+        .group_name(&name)
+        .strategy(types::PlacementStrategy::Cluster)
         .tag_specifications(
             types::TagSpecification::builder()
-                .resource_type(types::ResourceType::Vpc)
+                .resource_type(types::ResourceType::PlacementGroup)
+                .tags(
+                    types::Tag::builder()
+                        .key("project")
+                        .value(project_tag)
+                        .build(),
+                )
+                .expiry_tag(expires_at)
+                .build(),
+        )
+        .send();
+
+    let name = match dry_run::send(
+        &format!("CreatePlacementGroup {} (cluster)", name),
+        &[],
+        create,
+    )
+    .await?
+    {
+        Some(_) => name,
+        None => dry_run::placeholder("pg"),
+    };
+    shutdown::track(&name, Tracked::PlacementGroup(name.clone()));
+    info!(placement_group = name, "Created placement group");
+
+    Ok(name)
+}
+
+/// Allocate an Elastic IP for the head node of a cluster, tagged like the rest of it.
+///
+/// # Returns
+/// * The allocation ID of the address (a placeholder in a dry run), or errors.
+pub async fn allocate_elastic_ip(
+    aws_client: &aws_sdk_ec2::Client,
+    project_tag: &str,
+    expires_at: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    shutdown::check()?;
+    let allocate = aws_client
+        .allocate_address()
+        .dry_run(dry_run::enabled())
+        .domain(types::DomainType::Vpc)
+        .tag_specifications(
+            types::TagSpecification::builder()
+                .resource_type(types::ResourceType::ElasticIp)
                 .tags(
                     types::Tag::builder()
                         .key("Name")
-                        .value(template.cluster_name)
+                        .value(format!("Head node of {}", project_tag))
                         .build(),
                 )
                 .tags(
                     types::Tag::builder()
                         .key("project")
-                        .value(template.project_tag)
+                        .value(project_tag)
                         .build(),
                 )
+                .expiry_tag(expires_at)
                 .build(),
         )
-        .send()
-        .await?
-        .vpc
-        .unwrap();
-
-    // Create Subnet
-    // TODO
-
-    // Create Security Group
-    // TODO
-
-    // Create placement group
-    // TODO
+        .send();
 
-    // Loop to create instances
-    // let mut futs = Vec::new();
-    for i in 0..template.num_instances {
-        // futs.push(create_instance_sdk(aws_client, &template.instance_template));
-
-        // Create network interfaces
-        // TODO
+    let allocation_id = match dry_run::send("AllocateAddress", &[], allocate).await? {
+        Some(out) => match out.allocation_id {
+            Some(allocation_id) => allocation_id,
+            None => return Err("No allocation ID was returned in the response!".into()),
+        },
+        None => dry_run::placeholder("eipalloc"),
+    };
+    shutdown::track(&allocation_id, Tracked::ElasticIp(allocation_id.clone()));
+    info!(allocation_id, "Allocated Elastic IP");
 
-        // Create the instance with specific options based on the instance template
-        // TODO
-    }
+    Ok(allocation_id)
+}
 
-    // Await futures
-    // join_all(futs).await;
+/// Associate an Elastic IP with an instance, once the instance is running.
+pub async fn associate_elastic_ip(
+    aws_client: &aws_sdk_ec2::Client,
+    allocation_id: &str,
+    instance_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    wait_for_instance_state(
+        aws_client,
+        &[instance_id.to_string()],
+        types::InstanceStateName::Running,
+        tokio::time::Duration::from_secs(600),
+    )
+    .await?;
+
+    let associate = aws_client
+        .associate_address()
+        .dry_run(dry_run::enabled())
+        .allocation_id(allocation_id)
+        .instance_id(instance_id)
+        .send();
+    dry_run::send(
+        &format!("AssociateAddress {} with {}", allocation_id, instance_id),
+        &[allocation_id, instance_id],
+        associate,
+    )
+    .await?;
+    debug!(
+        "Associated Elastic IP {} with {}",
+        allocation_id, instance_id
+    );
 
-    // TODO
+    Ok(())
+}
 
+/// Release an Elastic IP that is no longer associated with anything.
+pub async fn release_elastic_ip(
+    aws_client: &aws_sdk_ec2::Client,
+    allocation_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!(target: CLEANUP_TARGET, "Releasing Elastic IP: {}", allocation_id);
+    let release = aws_client
+        .release_address()
+        .dry_run(dry_run::enabled())
+        .allocation_id(allocation_id)
+        .send();
+    dry_run::send(
+        &format!("ReleaseAddress {}", allocation_id),
+        &[allocation_id],
+        release,
+    )
+    .await?;
+    shutdown::untrack(allocation_id);
     Ok(())
 }
 
-/// Terminate a list of instances by their instance IDs.
+/// Create the multi-attach volume shared between the nodes of a cluster.
 ///
-/// # Arguments
-/// * `aws_client` - The AWS client to use for the operation.
-/// * `instance_ids` - A list of instance IDs to terminate.
+/// Only io1/io2 volumes support multi-attach, which is what sharing across nodes needs.
 ///
 /// # Returns
-/// * A result indicating success or failure.
-#[allow(unused)]
-pub async fn terminate_instances(
+/// * The ID of the volume (a placeholder in a dry run), or errors.
+pub async fn create_shared_volume<'a>(
     aws_client: &aws_sdk_ec2::Client,
-    instance_ids: Vec<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut terms = aws_client.terminate_instances();
-    for id in instance_ids {
-        terms = terms.instance_ids(id);
-    }
-
+    template: &ClusterTemplate<'a>,
+    expires_at: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let size = match template.shared_ebs_volume_size {
+        Some(size) => size,
+        None => return Err("If attaching shared EBS volume, must specify its size!".into()),
+    };
+
+    shutdown::check()?;
+    let create_volume = aws_client
+        .create_volume()
+        .dry_run(dry_run::enabled())
+        .availability_zone(template.instance_template.availability_zone)
+        .size(size.try_into()?)
+        .volume_type(types::VolumeType::Io2)
+        .iops(3000)
+        .multi_attach_enabled(true)
+        .tag_specifications(
+            types::TagSpecification::builder()
+                .resource_type(types::ResourceType::Volume)
+                .tags(
+                    types::Tag::builder()
+                        .key("project")
+                        .value(template.instance_template.project_tag)
+                        .build(),
+                )
+                .expiry_tag(expires_at)
+                .build(),
+        )
+        .send();
+    let ebs_vol = dry_run::send(
+        &format!(
+            "CreateVolume io2 {} GiB (multi-attach) in {}",
+            size, template.instance_template.availability_zone
+        ),
+        &[],
+        create_volume,
+    )
+    .await?;
+
+    let volume_id = match ebs_vol.and_then(|v| v.volume_id) {
+        Some(volume_id) => volume_id,
+        None if dry_run::enabled() => dry_run::placeholder("vol"),
+        None => return Err("No volume ID was returned in the response!".into()),
+    };
+    shutdown::track(&volume_id, Tracked::Volume(volume_id.clone()));
+
+    Ok(volume_id)
+}
+
+/// Render the user data for one node of a cluster, adding the shutdown timer if the cluster should
+/// shut itself down on expiry. With `batch`, the user data is for all the nodes from `node_index`
+/// on that one request launches (see `user_data::NodeVars`).
+fn render_node_user_data(
+    template: &ClusterTemplate,
+    node_index: u64,
+    batch: bool,
+    head_node_ip: Option<&str>,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let shared_volume_device = if template.attach_shared_ebs {
+        Some(SHARED_VOLUME_DEVICE)
+    } else {
+        None
+    };
+    let rendered = match template.node_template(node_index).user_data {
+        Some(user_data) => Some(crate::user_data::render(
+            user_data,
+            &crate::user_data::NodeVars {
+                node_index,
+                batch,
+                cluster_size: template.num_instances,
+                cluster_name: template.cluster_name,
+                head_node_ip,
+                shared_volume_device,
+            },
+        )?),
+        None => None,
+    };
+    let shutdown_timer = match template.ttl {
+        Some(ttl) if template.shutdown_on_expiry => {
+            Some(crate::user_data::shutdown_timer(ttl.as_secs().div_ceil(60)))
+        }
+        _ => None,
+    };
+
+    Ok(match (rendered, shutdown_timer) {
+        (Some(rendered), Some(timer)) => Some(crate::user_data::add_part(&rendered, &timer)),
+        (None, Some(timer)) => Some(timer),
+        (rendered, None) => rendered,
+    })
+}
+
+/// Tag each launched node with its index, so node numbering matches launch order (head node
+/// first), and with its role, which a request launching the head node along with workers gets
+/// wrong for the head node.
+async fn tag_nodes(
+    aws_client: &aws_sdk_ec2::Client,
+    template: &ClusterTemplate<'_>,
+    launched: &[(u64, Instance)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (i, instance) in launched {
+        let instance_id = instance.instance_id().unwrap_or_default();
+        let node_template = template.node_template(*i);
+        let role = node_template.role_tag();
+        let create_tags = aws_client
+            .create_tags()
+            .dry_run(dry_run::enabled())
+            .resources(instance_id)
+            .tags(
+                types::Tag::builder()
+                    .key(NODE_INDEX_TAG)
+                    .value(i.to_string())
+                    .build(),
+            )
+            .tags(types::Tag::builder().key(ROLE_TAG).value(role).build())
+            .send();
+        if let Err(e) = dry_run::send(
+            &format!(
+                "CreateTags {}={} {}={} on {}",
+                NODE_INDEX_TAG, i, ROLE_TAG, role, instance_id
+            ),
+            &[instance_id],
+            create_tags,
+        )
+        .await
+        {
+            error!("Failed to tag node {}: {}", i, e);
+            return Err(Box::new(e));
+        }
+    }
+    Ok(())
+}
+
+/// The instances of launched nodes, as something `cleanup_cluster` can terminate.
+fn launched_cleanup(launched: &[(u64, Instance)]) -> ClusterCleanup {
+    ClusterCleanup {
+        instance_ids: launched
+            .iter()
+            .filter_map(|(_, instance)| instance.instance_id.clone())
+            .collect(),
+        volume_id: None,
+        elastic_ip: None,
+        placement_group: None,
+        instance_types: BTreeMap::new(),
+    }
+}
+
+/// Launch the given nodes of a cluster concurrently, each with its own request, and tag each with
+/// its index and role.
+///
+/// Each node's user data is rendered from the cluster template; `head_node_ip` is the private IP of
+/// node 0, if it is already running. Nodes that fail to launch are given up on as long as at least
+/// `required` of them launch; if fewer do, or tagging fails, the ones that did launch are
+/// terminated again before returning.
+///
+/// # Returns
+/// * The launched instances, in the order of `node_indices`, or errors.
+pub async fn launch_cluster_nodes<'a>(
+    aws_client: &aws_sdk_ec2::Client,
+    template: &ClusterTemplate<'a>,
+    node_indices: &[u64],
+    required: usize,
+    head_node_ip: Option<&str>,
+    expires_at: Option<&str>,
+) -> Result<Vec<Instance>, Box<dyn std::error::Error>> {
+    // Render everything before launching anything, so a bad template doesn't leave nodes behind
+    let mut user_data = Vec::new();
+    for &i in node_indices {
+        user_data.push(render_node_user_data(template, i, false, head_node_ip)?);
+    }
+    let templates: Vec<InstanceTemplate> = user_data
+        .iter()
+        .zip(node_indices)
+        .map(|(user_data, &i)| InstanceTemplate {
+            user_data: user_data.as_deref(),
+            expires_at,
+            shutdown_on_expiry: template.shutdown_on_expiry,
+            ..template.node_template(i)
+        })
+        .collect();
+    let results =
+        futures::future::join_all(templates.iter().map(|t| create_instance_sdk(aws_client, t)))
+            .await;
+
+    let mut launched: Vec<(u64, Instance)> = Vec::new();
+    let mut first_error = None;
+    for (&i, result) in node_indices.iter().zip(results) {
+        match result {
+            Ok(instances) => launched.extend(instances.into_iter().map(|instance| (i, instance))),
+            Err(e) => {
+                error!("Failed to launch node {}: {}", i, e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(e) = first_error {
+        if launched.len() < required {
+            error!(
+                "Only {} of {} node(s) launched, but {} have to",
+                launched.len(),
+                node_indices.len(),
+                required
+            );
+            return Err(roll_back(aws_client, launched_cleanup(&launched), e).await);
+        }
+        warn!(
+            "Giving up on {} node(s) that failed to launch",
+            node_indices.len() - launched.len()
+        );
+    }
+
+    if let Err(e) = shutdown::check() {
+        return Err(roll_back(aws_client, launched_cleanup(&launched), e).await);
+    }
+
+    if let Err(e) = tag_nodes(aws_client, template, &launched).await {
+        return Err(roll_back(aws_client, launched_cleanup(&launched), e).await);
+    }
+
+    Ok(launched.into_iter().map(|(_, instance)| instance).collect())
+}
+
+/// Launch the given nodes of a cluster with one request per node group (see
+/// `ClusterTemplate::batches`), so that each group launches completely or not at all, and tag each
+/// node with its index and role.
+///
+/// The nodes of a request share their user data, which works out their index (and, if the request
+/// launches the head node too, the head node's IP) when it runs. A head node launched along with
+/// workers gets their interfaces, so no public IPv4 address if they don't have one. If any request
+/// fails, the nodes the others launched are terminated again before returning.
+///
+/// # Returns
+/// * The launched instances, in node order, or errors.
+pub async fn launch_node_batches<'a>(
+    aws_client: &aws_sdk_ec2::Client,
+    template: &ClusterTemplate<'a>,
+    node_indices: &[u64],
+    head_node_ip: Option<&str>,
+    expires_at: Option<&str>,
+) -> Result<Vec<Instance>, Box<dyn std::error::Error>> {
+    let batches = template.batches(node_indices);
+    let mut user_data = Vec::new();
+    for batch in &batches {
+        user_data.push(render_node_user_data(
+            template,
+            batch[0],
+            batch.len() > 1,
+            head_node_ip,
+        )?);
+    }
+    let templates: Vec<InstanceTemplate> = user_data
+        .iter()
+        .zip(&batches)
+        .map(|(user_data, batch)| InstanceTemplate {
+            user_data: user_data.as_deref(),
+            expires_at,
+            shutdown_on_expiry: template.shutdown_on_expiry,
+            // Launched as the batch's last node, which is a worker unless the batch is the head node
+            ..template.node_template(batch[batch.len() - 1])
+        })
+        .collect();
+    let mut requests = Vec::new();
+    for (t, batch) in templates.iter().zip(&batches) {
+        requests.push(create_instances_sdk(aws_client, t, batch.len().try_into()?));
+    }
+    let results = futures::future::join_all(requests).await;
+
+    let mut launched: Vec<(u64, Instance)> = Vec::new();
+    let mut first_error = None;
+    for (batch, result) in batches.iter().zip(results) {
+        match result {
+            Ok(instances) => launched.extend(instances.into_iter().map(|instance| {
+                let launch_index = instance.ami_launch_index().unwrap_or_default() as u64;
+                (batch[0] + launch_index, instance)
+            })),
+            Err(e) => {
+                error!(
+                    "Failed to launch nodes {}-{} together: {}",
+                    batch[0],
+                    batch[batch.len() - 1],
+                    e
+                );
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    launched.sort_by_key(|(i, _)| *i);
+    if let Some(e) = first_error {
+        return Err(roll_back(aws_client, launched_cleanup(&launched), e).await);
+    }
+
+    if let Err(e) = shutdown::check() {
+        return Err(roll_back(aws_client, launched_cleanup(&launched), e).await);
+    }
+
+    if let Err(e) = tag_nodes(aws_client, template, &launched).await {
+        return Err(roll_back(aws_client, launched_cleanup(&launched), e).await);
+    }
+
+    Ok(launched.into_iter().map(|(_, instance)| instance).collect())
+}
+
+/// Wait for a volume and the instances to be ready, then attach the volume to every instance.
+pub async fn attach_shared_volume(
+    aws_client: &aws_sdk_ec2::Client,
+    volume_id: &str,
+    instance_ids: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    wait_for_instance_state(
+        aws_client,
+        instance_ids,
+        types::InstanceStateName::Running,
+        tokio::time::Duration::from_secs(600),
+    )
+    .await?;
+
+    // Nothing was created in a dry run, so there is nothing to wait for
+    while !dry_run::enabled() {
+        let volumes = aws_client
+            .describe_volumes()
+            .volume_ids(volume_id)
+            .send()
+            .await?;
+        let state = volumes.volumes().first().and_then(|v| v.state().cloned());
+        if state == Some(types::VolumeState::Available) {
+            break;
+        }
+        debug!("Waiting for volume {} to become available...", volume_id);
+        shutdown::sleep(tokio::time::Duration::from_secs(5)).await?;
+    }
+
+    for instance_id in instance_ids {
+        shutdown::check()?;
+        let attach_volume = aws_client
+            .attach_volume()
+            .dry_run(dry_run::enabled())
+            .volume_id(volume_id)
+            .instance_id(instance_id)
+            .device(SHARED_VOLUME_DEVICE)
+            .send();
+        let step = format!(
+            "AttachVolume {} to {} as {}",
+            volume_id, instance_id, SHARED_VOLUME_DEVICE
+        );
+        if dry_run::send(&step, &[volume_id, instance_id], attach_volume)
+            .await?
+            .is_some()
+        {
+            debug!("Attached volume {} to {}", volume_id, instance_id);
+        }
+    }
+
+    Ok(())
+}
+
+/// Poll until every given instance is in the given state.
+pub async fn wait_for_instance_state(
+    aws_client: &aws_sdk_ec2::Client,
+    instance_ids: &[String],
+    state: types::InstanceStateName,
+    timeout: tokio::time::Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if instance_ids.is_empty() || dry_run::enabled() {
+        return Ok(());
+    }
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        let out = aws_client
+            .describe_instances()
+            .set_instance_ids(Some(instance_ids.to_vec()))
+            .send()
+            .await?;
+        let states: Vec<Option<&types::InstanceStateName>> = out
+            .reservations()
+            .iter()
+            .flat_map(|r| r.instances())
+            .map(|i| i.state().and_then(|s| s.name()))
+            .collect();
+
+        let done = states.iter().filter(|s| **s == Some(&state)).count();
+        if done == instance_ids.len() {
+            return Ok(());
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(format!(
+                "Timed out waiting for instances to be {} ({}/{} are)",
+                state.as_str(),
+                done,
+                instance_ids.len()
+            )
+            .into());
+        }
+
+        debug!(
+            "Waiting for instances to be {}: {}/{}",
+            state.as_str(),
+            done,
+            instance_ids.len()
+        );
+        shutdown::sleep(tokio::time::Duration::from_secs(5)).await?;
+    }
+}
+
+/// Terminate a list of instances by their instance IDs.
+///
+/// # Arguments
+/// * `aws_client` - The AWS client to use for the operation.
+/// * `instance_ids` - A list of instance IDs to terminate.
+///
+/// # Returns
+/// * A result indicating success or failure.
+#[allow(unused)]
+pub async fn terminate_instances(
+    aws_client: &aws_sdk_ec2::Client,
+    instance_ids: Vec<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let step = format!("TerminateInstances {:?}", instance_ids);
+    let ids: Vec<&str> = instance_ids.iter().map(|id| id.as_str()).collect();
+    let mut terms = aws_client.terminate_instances().dry_run(dry_run::enabled());
+    for id in &instance_ids {
+        terms = terms.instance_ids(id);
+    }
+
     // Attempt to terminate the instances
-    let _ = terms.send().await?;
+    if let Some(out) = dry_run::send(&step, &ids, terms.send()).await? {
+        for change in out.terminating_instances() {
+            if let Some(instance_id) = change.instance_id() {
+                shutdown::untrack(instance_id);
+            }
+        }
+    }
 
     Ok(())
 }
 
-#[derive(Debug, Clone)]
-pub struct VpcCleanup {
-    vpc_id: Option<String>,
-    igw_id: Option<String>,
-    subnet_ids: Option<Vec<String>>,
-    route_table_ids: Option<Vec<String>>,
-    security_group_ids: Option<Vec<String>>,
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct VpcCleanup {
+    vpc_id: Option<String>,
+    igw_id: Option<String>,
+    subnet_ids: Option<Vec<String>>,
+    subnet_azs: Option<Vec<String>>,
+    route_table_ids: Option<Vec<String>>,
+    security_group_ids: Option<Vec<String>>,
+}
+
+impl VpcCleanup {
+    pub fn vpc_id(&self) -> Option<&str> {
+        self.vpc_id.as_deref()
+    }
+
+    /// The ID of the subnet created in the given availability zone, if any.
+    pub fn subnet_id_for_az(&self, availability_zone: &str) -> Option<&str> {
+        let subnet_ids = self.subnet_ids.as_ref()?;
+        let subnet_azs = self.subnet_azs.as_ref()?;
+        subnet_azs
+            .iter()
+            .position(|az| az == availability_zone)
+            .and_then(|i| subnet_ids.get(i))
+            .map(|id| id.as_str())
+    }
+
+    /// The ID of the security group created for the VPC, if any.
+    pub fn security_group_id(&self) -> Option<&str> {
+        self.security_group_ids
+            .as_ref()
+            .and_then(|ids| ids.first())
+            .map(|id| id.as_str())
+    }
+}
+
+/// The `project` tag of an existing VPC, or `None` if it has none (i.e. this tool did not create
+/// it).
+pub async fn vpc_project(
+    aws_client: &aws_sdk_ec2::Client,
+    vpc_id: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let out = aws_client.describe_vpcs().vpc_ids(vpc_id).send().await?;
+    let vpc = out
+        .vpcs()
+        .first()
+        .ok_or_else(|| format!("VPC {} does not exist", vpc_id))?;
+    Ok(vpc
+        .tags()
+        .iter()
+        .find(|t| t.key() == Some("project"))
+        .and_then(|t| t.value())
+        .filter(|project| !project.is_empty())
+        .map(|project| project.to_string()))
+}
+
+/// Build a `VpcCleanup` for an existing VPC by looking up what is in it.
+///
+/// Used to tear down VPCs this process did not create (e.g. when reaping). The main route table and
+/// the default security group are left out, since they go away with the VPC itself.
+pub async fn discover_vpc(
+    aws_client: &aws_sdk_ec2::Client,
+    vpc_id: &str,
+) -> Result<VpcCleanup, Box<dyn std::error::Error>> {
+    let vpc_filter = |name: &str| types::Filter::builder().name(name).values(vpc_id).build();
+
+    let subnets = aws_client
+        .describe_subnets()
+        .filters(vpc_filter("vpc-id"))
+        .send()
+        .await?;
+    let igws = aws_client
+        .describe_internet_gateways()
+        .filters(vpc_filter("attachment.vpc-id"))
+        .send()
+        .await?;
+    let route_tables = aws_client
+        .describe_route_tables()
+        .filters(vpc_filter("vpc-id"))
+        .send()
+        .await?;
+    let security_groups = aws_client
+        .describe_security_groups()
+        .filters(vpc_filter("vpc-id"))
+        .send()
+        .await?;
+
+    let subnets = subnets.subnets();
+    Ok(VpcCleanup {
+        vpc_id: Some(vpc_id.to_string()),
+        igw_id: igws
+            .internet_gateways()
+            .first()
+            .and_then(|igw| igw.internet_gateway_id())
+            .map(|id| id.to_string()),
+        subnet_ids: Some(
+            subnets
+                .iter()
+                .filter_map(|s| s.subnet_id())
+                .map(|id| id.to_string())
+                .collect(),
+        ),
+        subnet_azs: Some(
+            subnets
+                .iter()
+                .filter(|s| s.subnet_id().is_some())
+                .map(|s| s.availability_zone().unwrap_or_default().to_string())
+                .collect(),
+        ),
+        route_table_ids: Some(
+            route_tables
+                .route_tables()
+                .iter()
+                .filter(|rt| !rt.associations().iter().any(|a| a.main() == Some(true)))
+                .filter_map(|rt| rt.route_table_id())
+                .map(|id| id.to_string())
+                .collect(),
+        ),
+        security_group_ids: Some(
+            security_groups
+                .security_groups()
+                .iter()
+                .filter(|sg| sg.group_name() != Some("default"))
+                .filter_map(|sg| sg.group_id())
+                .map(|id| id.to_string())
+                .collect(),
+        ),
+    })
+}
+
+/// What a teardown did, item by item. Items are named like `subnet subnet-0a1b2c3d`.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct CleanupReport {
+    pub deleted: Vec<String>,
+    /// Items that were gone already (`*.NotFound`), e.g. because an earlier cleanup got to them.
+    pub already_gone: Vec<String>,
+    /// Items that could not be deleted, with the error.
+    pub failed: Vec<(String, String)>,
+    /// Items still there when the teardown was checked afterwards (see `teardown::verify`).
+    pub survivors: Vec<String>,
+}
+
+impl CleanupReport {
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty() && self.survivors.is_empty()
+    }
+
+    pub fn extend(&mut self, other: CleanupReport) {
+        self.deleted.extend(other.deleted);
+        self.already_gone.extend(other.already_gone);
+        self.failed.extend(other.failed);
+        self.survivors.extend(other.survivors);
+    }
+
+    /// Fold a failed cleanup into the report: the cleanup's own report if the error is one, or
+    /// else the error as a failure to delete `item`.
+    pub fn add_error(&mut self, item: &str, e: &(dyn std::error::Error + 'static)) {
+        match e.downcast_ref::<CleanupReport>() {
+            Some(report) => self.extend(report.clone()),
+            None => self.failed.push((item.to_string(), e.to_string())),
+        }
+    }
+
+    pub fn text(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        lines.extend(self.deleted.iter().map(|item| format!("Deleted {}", item)));
+        lines.extend(
+            self.already_gone
+                .iter()
+                .map(|item| format!("Already gone: {}", item)),
+        );
+        lines.extend(
+            self.failed
+                .iter()
+                .map(|(item, e)| format!("FAILED to delete {}: {}", item, e)),
+        );
+        lines.extend(
+            self.survivors
+                .iter()
+                .map(|item| format!("STILL THERE: {}", item)),
+        );
+        lines
+    }
+
+    /// The report, or the report as the error if anything could not be deleted.
+    pub fn into_result(self) -> Result<CleanupReport, Box<dyn std::error::Error>> {
+        if self.is_complete() {
+            Ok(self)
+        } else {
+            Err(Box::new(self))
+        }
+    }
+}
+
+impl std::fmt::Display for CleanupReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} deleted, {} already gone, {} failed, {} still there",
+            self.deleted.len(),
+            self.already_gone.len(),
+            self.failed.len(),
+            self.survivors.len()
+        )?;
+        for (item, e) in &self.failed {
+            write!(f, "; {}: {}", item, e)?;
+        }
+        for item in &self.survivors {
+            write!(f, "; {} is still there", item)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CleanupReport {}
+
+/// A launch error whose rollback failed as well, so that neither gets lost.
+#[derive(Debug)]
+pub struct RollbackError {
+    pub error: Box<dyn std::error::Error>,
+    pub rollback: Box<dyn std::error::Error>,
+}
+
+impl std::fmt::Display for RollbackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (rolling back failed as well: {})",
+            self.error, self.rollback
+        )
+    }
+}
+
+impl std::error::Error for RollbackError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+/// Tear down what a failed launch created and hand back the launch error. If the teardown fails
+/// too, that is logged and attached to the error rather than taking its place.
+pub async fn roll_back(
+    aws_client: &aws_sdk_ec2::Client,
+    cleanup: ClusterCleanup,
+    error: Box<dyn std::error::Error>,
+) -> Box<dyn std::error::Error> {
+    match cleanup_cluster(aws_client, cleanup).await {
+        Ok(()) => error,
+        Err(rollback) => {
+            error!(
+                target: CLEANUP_TARGET,
+                "Rolling back after \"{}\" failed: {}", error, rollback
+            );
+            Box::new(RollbackError { error, rollback })
+        }
+    }
+}
+
+/// `roll_back` for a VPC that failed part way through being created.
+async fn roll_back_vpc(
+    aws_client: &aws_sdk_ec2::Client,
+    cleanup: &VpcCleanup,
+    error: Box<dyn std::error::Error>,
+) -> Box<dyn std::error::Error> {
+    match cleanup_vpc(aws_client, cleanup.clone()).await {
+        Ok(_) => error,
+        Err(rollback) => {
+            error!(
+                target: CLEANUP_TARGET,
+                "Rolling back after \"{}\" failed: {}", error, rollback
+            );
+            Box::new(RollbackError { error, rollback })
+        }
+    }
+}
+
+/// How long a deletion is retried while it fails with `DependencyViolation`, which it does until
+/// the network interfaces of terminated instances (and the like) have drained.
+const DEPENDENCY_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(300);
+
+/// The code and message of an EC2 error, for reports and logs.
+fn error_text<E: ProvideErrorMetadata + std::error::Error + 'static>(e: &SdkError<E>) -> String {
+    match (e.code(), e.message()) {
+        (Some(code), Some(message)) => format!("{}: {}", code, message),
+        (Some(code), None) => code.to_string(),
+        _ => aws_smithy_types::error::display::DisplayErrorContext(e).to_string(),
+    }
+}
+
+/// Whether an error means the thing being deleted (or detached) is gone already.
+fn already_gone(code: Option<&str>) -> bool {
+    code.is_some_and(|code| {
+        code.ends_with(".NotFound")
+            || code == "Gateway.NotAttached"
+            || code == "InvalidPlacementGroup.Unknown"
+    })
+}
+
+/// A report of a lookup that failed, which leaves whatever it would have found undeleted.
+fn lookup_failed(what: &str, e: String) -> CleanupReport {
+    warn!(target: CLEANUP_TARGET, "Failed to look up {}: {}", what, e);
+    CleanupReport {
+        failed: vec![(what.to_string(), e)],
+        ..Default::default()
+    }
+}
+
+/// Run one deletion of a teardown and report how it went, instead of failing the teardown.
+///
+/// `*.NotFound` counts as done, and `DependencyViolation` is retried for up to
+/// `DEPENDENCY_TIMEOUT`. `call` builds the request afresh for each attempt.
+async fn cleanup_step<T, E, F, Fut>(item: &str, step: &str, ids: &[&str], call: F) -> CleanupReport
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = Result<T, SdkError<E>>>,
+{
+    info!(target: CLEANUP_TARGET, "Deleting {}", item);
+    let mut report = CleanupReport::default();
+    let deadline = tokio::time::Instant::now() + DEPENDENCY_TIMEOUT;
+    loop {
+        let e = match dry_run::send(step, ids, call()).await {
+            Ok(_) => {
+                report.deleted.push(item.to_string());
+                return report;
+            }
+            Err(e) => e,
+        };
+        if already_gone(e.code()) {
+            debug!(target: CLEANUP_TARGET, "{} is already gone", item);
+            report.already_gone.push(item.to_string());
+            return report;
+        }
+        if e.code() == Some("DependencyViolation")
+            && !dry_run::enabled()
+            && tokio::time::Instant::now() < deadline
+        {
+            debug!(target: CLEANUP_TARGET, "{} still has dependencies, retrying: {}", item, error_text(&e));
+            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            continue;
+        }
+        warn!(target: CLEANUP_TARGET, "Failed to delete {}: {}", item, error_text(&e));
+        report.failed.push((item.to_string(), error_text(&e)));
+        return report;
+    }
+}
+
+/// Terminate instances and wait for them to be gone.
+async fn terminate_step(
+    aws_client: &aws_sdk_ec2::Client,
+    instance_ids: Vec<String>,
+) -> CleanupReport {
+    let item = format!("instances {}", instance_ids.join(", "));
+    let ids: Vec<&str> = instance_ids.iter().map(|id| id.as_str()).collect();
+    let mut report = cleanup_step(
+        &item,
+        &format!("TerminateInstances {:?}", instance_ids),
+        &ids,
+        || {
+            aws_client
+                .terminate_instances()
+                .dry_run(dry_run::enabled())
+                .set_instance_ids(Some(instance_ids.clone()))
+                .send()
+        },
+    )
+    .await;
+    if !report.deleted.is_empty() {
+        if let Err(e) = wait_for_instance_state(
+            aws_client,
+            &instance_ids,
+            types::InstanceStateName::Terminated,
+            tokio::time::Duration::from_secs(900),
+        )
+        .await
+        {
+            report.deleted.clear();
+            report.failed.push((item, e.to_string()));
+            return report;
+        }
+    }
+    for instance_id in &instance_ids {
+        shutdown::untrack(instance_id);
+    }
+    report
+}
+
+/// Release an Elastic IP, reporting rather than failing.
+async fn release_step(aws_client: &aws_sdk_ec2::Client, allocation_id: String) -> CleanupReport {
+    let report = cleanup_step(
+        &format!("Elastic IP {}", allocation_id),
+        &format!("ReleaseAddress {}", allocation_id),
+        &[&allocation_id],
+        || {
+            aws_client
+                .release_address()
+                .dry_run(dry_run::enabled())
+                .allocation_id(&allocation_id)
+                .send()
+        },
+    )
+    .await;
+    if report.is_complete() {
+        shutdown::untrack(&allocation_id);
+    }
+    report
+}
+
+/// Wait until the given NAT gateways are deleted, which also frees their addresses and interfaces.
+async fn wait_for_nat_gateways_deleted(
+    aws_client: &aws_sdk_ec2::Client,
+    nat_gateway_ids: &[String],
+    timeout: tokio::time::Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if nat_gateway_ids.is_empty() || dry_run::enabled() {
+        return Ok(());
+    }
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        let out = aws_client
+            .describe_nat_gateways()
+            .set_nat_gateway_ids(Some(nat_gateway_ids.to_vec()))
+            .send()
+            .await?;
+        let left = out
+            .nat_gateways()
+            .iter()
+            .filter(|nat| nat.state() != Some(&types::NatGatewayState::Deleted))
+            .count();
+        if left == 0 {
+            return Ok(());
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(format!(
+                "Timed out waiting for {} NAT gateway(s) to be deleted",
+                left
+            )
+            .into());
+        }
+
+        debug!(target: CLEANUP_TARGET, "Waiting for {} NAT gateway(s) to be deleted", left);
+        shutdown::sleep(tokio::time::Duration::from_secs(10)).await?;
+    }
+}
+
+/// Delete one VPC endpoint. The call succeeds even when the endpoint can't be deleted, and says so
+/// per endpoint.
+async fn delete_endpoint_step(
+    aws_client: &aws_sdk_ec2::Client,
+    vpc_id: &str,
+    endpoint_id: String,
+) -> CleanupReport {
+    let item = format!("VPC endpoint {}", endpoint_id);
+    info!(target: CLEANUP_TARGET, "Deleting {}", item);
+    let mut report = CleanupReport::default();
+    let delete = aws_client
+        .delete_vpc_endpoints()
+        .dry_run(dry_run::enabled())
+        .vpc_endpoint_ids(&endpoint_id)
+        .send();
+    match dry_run::send(
+        &format!("DeleteVpcEndpoints {}", endpoint_id),
+        &[vpc_id],
+        delete,
+    )
+    .await
+    {
+        Ok(Some(out)) => match out.unsuccessful().first().and_then(|u| u.error()) {
+            Some(e) if already_gone(e.code()) => report.already_gone.push(item),
+            Some(e) => {
+                let text = format!(
+                    "{}: {}",
+                    e.code().unwrap_or_default(),
+                    e.message().unwrap_or_default()
+                );
+                warn!(target: CLEANUP_TARGET, "Failed to delete {}: {}", item, text);
+                report.failed.push((item, text));
+            }
+            None => report.deleted.push(item),
+        },
+        Ok(None) => report.deleted.push(item),
+        Err(e) if already_gone(e.code()) => report.already_gone.push(item),
+        Err(e) => {
+            warn!(target: CLEANUP_TARGET, "Failed to delete {}: {}", item, error_text(&e));
+            report.failed.push((item, error_text(&e)));
+        }
+    }
+    report
+}
+
+/// The steps that empty a VPC of what `create_vpc` didn't put there but would keep it from being
+/// deleted: instances (terminated and waited on), NAT gateways, VPC endpoints, the Elastic IPs on
+/// any of their interfaces, and network interfaces left behind.
+///
+/// Everything is looked up in the VPC itself, so it works whoever created it. Lookups that fail are
+/// returned as a report, and the instances and Elastic IPs found as what to verify is gone.
+async fn vpc_content_steps<'a>(
+    aws_client: &'a aws_sdk_ec2::Client,
+    vpc_id: &'a str,
+) -> (Vec<teardown::Step<'a>>, CleanupReport, teardown::Inventory) {
+    let vpc_filter = move |name: &str| types::Filter::builder().name(name).values(vpc_id).build();
+    let mut report = CleanupReport::default();
+
+    // Look everything up first: the addresses have to be found while their interfaces still exist
+    let (instances, nat_gateways, endpoints, enis) = futures::join!(
+        aws_client
+            .describe_instances()
+            .filters(vpc_filter("vpc-id"))
+            .filters(
+                types::Filter::builder()
+                    .name("instance-state-name")
+                    .values("pending")
+                    .values("running")
+                    .values("stopping")
+                    .values("stopped")
+                    .values("shutting-down")
+                    .build(),
+            )
+            .send(),
+        aws_client
+            .describe_nat_gateways()
+            .filter(vpc_filter("vpc-id"))
+            .filter(
+                types::Filter::builder()
+                    .name("state")
+                    .values("pending")
+                    .values("available")
+                    .build()
+            )
+            .send(),
+        aws_client
+            .describe_vpc_endpoints()
+            .filters(vpc_filter("vpc-id"))
+            .send(),
+        aws_client
+            .describe_network_interfaces()
+            .filters(vpc_filter("vpc-id"))
+            .send(),
+    );
+    let instance_ids: Vec<String> = match instances {
+        Ok(out) => out
+            .reservations()
+            .iter()
+            .flat_map(|r| r.instances())
+            .filter_map(|i| i.instance_id())
+            .map(|id| id.to_string())
+            .collect(),
+        Err(e) => {
+            report.extend(lookup_failed(
+                &format!("instances in {}", vpc_id),
+                error_text(&e),
+            ));
+            Vec::new()
+        }
+    };
+    let nat_gateway_ids: Vec<String> = match nat_gateways {
+        Ok(out) => out
+            .nat_gateways()
+            .iter()
+            .filter_map(|nat| nat.nat_gateway_id())
+            .map(|id| id.to_string())
+            .collect(),
+        Err(e) => {
+            report.extend(lookup_failed(
+                &format!("NAT gateways in {}", vpc_id),
+                error_text(&e),
+            ));
+            Vec::new()
+        }
+    };
+    let endpoint_ids: Vec<String> = match endpoints {
+        Ok(out) => out
+            .vpc_endpoints()
+            .iter()
+            .filter(|endpoint| endpoint.state() != Some(&types::State::Deleted))
+            .filter_map(|endpoint| endpoint.vpc_endpoint_id())
+            .map(|id| id.to_string())
+            .collect(),
+        Err(e) => {
+            report.extend(lookup_failed(
+                &format!("VPC endpoints in {}", vpc_id),
+                error_text(&e),
+            ));
+            Vec::new()
+        }
+    };
+    let allocation_ids: Vec<String> = match enis {
+        Ok(out) => out
+            .network_interfaces()
+            .iter()
+            .filter_map(|eni| eni.association().and_then(|a| a.allocation_id()))
+            .map(|id| id.to_string())
+            .collect(),
+        Err(e) => {
+            report.extend(lookup_failed(
+                &format!("Elastic IPs in {}", vpc_id),
+                error_text(&e),
+            ));
+            Vec::new()
+        }
+    };
+
+    let inventory = teardown::Inventory {
+        instance_ids: instance_ids.clone(),
+        allocation_ids: allocation_ids.clone(),
+        ..Default::default()
+    };
+
+    let mut steps: Vec<teardown::Step> = Vec::new();
+    if !instance_ids.is_empty() {
+        steps.push((
+            teardown::Kind::Instances,
+            terminate_step(aws_client, instance_ids).boxed(),
+        ));
+    }
+    if !nat_gateway_ids.is_empty() {
+        steps.push((
+            teardown::Kind::NatGateways,
+            async move {
+                let mut report =
+                    teardown::each(nat_gateway_ids.clone(), |nat_gateway_id| async move {
+                        cleanup_step(
+                            &format!("NAT gateway {}", nat_gateway_id),
+                            &format!("DeleteNatGateway {}", nat_gateway_id),
+                            &[vpc_id],
+                            || {
+                                aws_client
+                                    .delete_nat_gateway()
+                                    .dry_run(dry_run::enabled())
+                                    .nat_gateway_id(&nat_gateway_id)
+                                    .send()
+                            },
+                        )
+                        .await
+                    })
+                    .await;
+                let waited = wait_for_nat_gateways_deleted(
+                    aws_client,
+                    &nat_gateway_ids,
+                    tokio::time::Duration::from_secs(600),
+                )
+                .await;
+                if let Err(e) = waited {
+                    report.failed.push((
+                        format!("NAT gateways {}", nat_gateway_ids.join(", ")),
+                        e.to_string(),
+                    ));
+                }
+                report
+            }
+            .boxed(),
+        ));
+    }
+    if !endpoint_ids.is_empty() {
+        steps.push((
+            teardown::Kind::VpcEndpoints,
+            teardown::each(endpoint_ids, move |endpoint_id| {
+                delete_endpoint_step(aws_client, vpc_id, endpoint_id)
+            })
+            .boxed(),
+        ));
+    }
+    if !allocation_ids.is_empty() {
+        // Terminating or deleting what they were on has disassociated them
+        steps.push((
+            teardown::Kind::ElasticIps,
+            teardown::each(allocation_ids, move |allocation_id| {
+                release_step(aws_client, allocation_id)
+            })
+            .boxed(),
+        ));
+    }
+
+    // Delete the network interfaces nothing is using any more (the rest went with their owners)
+    steps.push((
+        teardown::Kind::NetworkInterfaces,
+        async move {
+            let leftover = aws_client
+                .describe_network_interfaces()
+                .filters(vpc_filter("vpc-id"))
+                .filters(
+                    types::Filter::builder()
+                        .name("status")
+                        .values("available")
+                        .build(),
+                )
+                .send()
+                .await;
+            let eni_ids: Vec<String> = match leftover {
+                Ok(out) => out
+                    .network_interfaces()
+                    .iter()
+                    .filter(|eni| eni.requester_managed() != Some(true))
+                    .filter_map(|eni| eni.network_interface_id())
+                    .map(|id| id.to_string())
+                    .collect(),
+                Err(e) => {
+                    return lookup_failed(
+                        &format!("network interfaces in {}", vpc_id),
+                        error_text(&e),
+                    )
+                }
+            };
+            teardown::each(eni_ids, |eni_id| async move {
+                cleanup_step(
+                    &format!("network interface {}", eni_id),
+                    &format!("DeleteNetworkInterface {}", eni_id),
+                    &[&eni_id],
+                    || {
+                        aws_client
+                            .delete_network_interface()
+                            .dry_run(dry_run::enabled())
+                            .network_interface_id(&eni_id)
+                            .send()
+                    },
+                )
+                .await
+            })
+            .await
+        }
+        .boxed(),
+    ));
+
+    (steps, report, inventory)
 }
 
-/// Print a message indicating that a clean-up operation is being performed.
+/// Tear down a VPC, everything in it (see `vpc_content_steps`) and what `create_vpc` put in it.
 ///
-/// Used pretty much like the `println!` macro. The arguments to the macro are provided to a `format!`
-/// macro, and the resulting string is printed with a "CLEAN UP" prefix in yellow.
-#[macro_export]
-macro_rules! print_cln {
-    ( $( $x:expr ),* ) => {
-        println!("{}[CLEAN UP] {}{}", color::Fg(color::Yellow), format!($( $x, )*), style::Reset);
-    };
-}
-
-#[allow(unused)]
+/// The deletions run as a dependency graph (see `teardown::Kind::depends_on`), so e.g. all the
+/// subnets and all the security groups go at once. Every step is attempted even if unrelated ones
+/// fail, and steps can be repeated: what is gone already counts as done. The main route table and
+/// the default security group are left to go away with the VPC.
+///
+/// # Returns
+/// * The report of what was deleted, or the report as the error if anything is left over.
 pub async fn cleanup_vpc(
     aws_client: &aws_sdk_ec2::Client,
     cleanup_items: VpcCleanup,
-) -> Result<(), Box<dyn std::error::Error>> {
-    print_cln!("Received clean-up...");
+) -> Result<CleanupReport, Box<dyn std::error::Error>> {
+    info!(target: CLEANUP_TARGET, vpc_id = cleanup_items.vpc_id.as_deref(), "Cleaning up VPC");
+    let mut report = CleanupReport::default();
+    let mut steps: Vec<teardown::Step> = Vec::new();
+    let mut inventory = teardown::Inventory::default();
+
+    // Clear out whatever else lives in the VPC (a VPC that was never created has nothing in it)
+    if let Some(vpc_id) = cleanup_items
+        .vpc_id
+        .as_deref()
+        .filter(|id| !dry_run::is_placeholder(id))
+    {
+        let (content_steps, lookups, content) = vpc_content_steps(aws_client, vpc_id).await;
+        steps.extend(content_steps);
+        report.extend(lookups);
+        inventory = content;
+    }
 
-    // Delete the security groups
-    if let Some(security_group_ids) = cleanup_items.security_group_ids.clone() {
-        for sg_id in security_group_ids {
-            print_cln!("Deleting security group: {:#?}", sg_id);
+    // Detach and delete the IGW
+    if let Some(igw_id) = cleanup_items.igw_id.as_deref() {
+        let vpc_id = cleanup_items.vpc_id.as_deref();
+        steps.push((
+            teardown::Kind::InternetGateway,
+            async move {
+                let mut report = CleanupReport::default();
+                if let Some(vpc_id) = vpc_id {
+                    report.extend(
+                        cleanup_step(
+                            &format!("attachment of {} to {}", igw_id, vpc_id),
+                            &format!("DetachInternetGateway {} from {}", igw_id, vpc_id),
+                            &[igw_id, vpc_id],
+                            || {
+                                aws_client
+                                    .detach_internet_gateway()
+                                    .dry_run(dry_run::enabled())
+                                    .internet_gateway_id(igw_id)
+                                    .vpc_id(vpc_id)
+                                    .send()
+                            },
+                        )
+                        .await,
+                    );
+                }
+                report.extend(
+                    cleanup_step(
+                        &format!("internet gateway {}", igw_id),
+                        &format!("DeleteInternetGateway {}", igw_id),
+                        &[igw_id],
+                        || {
+                            aws_client
+                                .delete_internet_gateway()
+                                .dry_run(dry_run::enabled())
+                                .internet_gateway_id(igw_id)
+                                .send()
+                        },
+                    )
+                    .await,
+                );
+                report
+            }
+            .boxed(),
+        ));
+    } else {
+        debug!(target: CLEANUP_TARGET, "No IGW to delete.");
+    }
 
-            let del_sg_out = aws_client
-                .delete_security_group()
-                .group_id(sg_id)
-                .send()
-                .await?;
-            print_cln!("Sent delete security group, got: {:#?}", del_sg_out);
+    // Look up the route tables' associations with the subnets (a route table that was never
+    // created has none)
+    let route_table_ids: Vec<String> = cleanup_items.route_table_ids.clone().unwrap_or_default();
+    let mut main_route_tables = Vec::new();
+    let mut associations = Vec::new();
+    for rt_id in route_table_ids
+        .iter()
+        .filter(|id| !dry_run::is_placeholder(id))
+    {
+        match aws_client
+            .describe_route_tables()
+            .route_table_ids(rt_id)
+            .send()
+            .await
+        {
+            Ok(out) => {
+                for rt in out.route_tables() {
+                    for assoc in rt.associations() {
+                        if assoc.main() == Some(true) {
+                            main_route_tables.push(rt_id.clone());
+                        } else if let Some(assoc_id) = assoc.route_table_association_id() {
+                            associations.push((rt_id.clone(), assoc_id.to_string()));
+                        }
+                    }
+                }
+            }
+            Err(e) if already_gone(e.code()) => {}
+            Err(e) => report.extend(lookup_failed(
+                &format!("associations of route table {}", rt_id),
+                error_text(&e),
+            )),
         }
-    } else {
-        print_cln!("No security groups to delete.");
     }
+    steps.push((
+        teardown::Kind::RouteTableAssociations,
+        teardown::each(associations, move |(rt_id, assoc_id)| async move {
+            cleanup_step(
+                &format!("route table association {}", assoc_id),
+                &format!("DisassociateRouteTable {}", assoc_id),
+                &[&rt_id],
+                || {
+                    aws_client
+                        .disassociate_route_table()
+                        .dry_run(dry_run::enabled())
+                        .association_id(&assoc_id)
+                        .send()
+                },
+            )
+            .await
+        })
+        .boxed(),
+    ));
 
-    // Disassociate the IGW from the VPC
-    if let Some(igw_id) = cleanup_items.igw_id.clone() {
-        print_cln!("Disassociating IGW: {:#?}", igw_id);
+    // Delete the subnets
+    steps.push((
+        teardown::Kind::Subnets,
+        teardown::each(
+            cleanup_items.subnet_ids.clone().unwrap_or_default(),
+            move |subnet_id| async move {
+                cleanup_step(
+                    &format!("subnet {}", subnet_id),
+                    &format!("DeleteSubnet {}", subnet_id),
+                    &[&subnet_id],
+                    || {
+                        aws_client
+                            .delete_subnet()
+                            .dry_run(dry_run::enabled())
+                            .subnet_id(&subnet_id)
+                            .send()
+                    },
+                )
+                .await
+            },
+        )
+        .boxed(),
+    ));
+
+    // Delete the route tables, except a main one, which goes with the VPC
+    let deleted_route_tables: Vec<String> = route_table_ids
+        .into_iter()
+        .filter(|rt_id| {
+            let main = main_route_tables.contains(rt_id);
+            if main {
+                debug!(target: CLEANUP_TARGET, "Leaving main route table {} to go with the VPC", rt_id);
+            }
+            !main
+        })
+        .collect();
+    steps.push((
+        teardown::Kind::RouteTables,
+        teardown::each(deleted_route_tables, move |rt_id| async move {
+            cleanup_step(
+                &format!("route table {}", rt_id),
+                &format!("DeleteRouteTable {}", rt_id),
+                &[&rt_id],
+                || {
+                    aws_client
+                        .delete_route_table()
+                        .dry_run(dry_run::enabled())
+                        .route_table_id(&rt_id)
+                        .send()
+                },
+            )
+            .await
+        })
+        .boxed(),
+    ));
+
+    // Delete the security groups, once no network interface uses them any more
+    steps.push((
+        teardown::Kind::SecurityGroups,
+        teardown::each(
+            cleanup_items.security_group_ids.clone().unwrap_or_default(),
+            move |sg_id| async move {
+                cleanup_step(
+                    &format!("security group {}", sg_id),
+                    &format!("DeleteSecurityGroup {}", sg_id),
+                    &[&sg_id],
+                    || {
+                        aws_client
+                            .delete_security_group()
+                            .dry_run(dry_run::enabled())
+                            .group_id(&sg_id)
+                            .send()
+                    },
+                )
+                .await
+            },
+        )
+        .boxed(),
+    ));
 
-        let disassoc_igw_out = aws_client
-            .detach_internet_gateway()
-            .internet_gateway_id(igw_id.clone())
-            .vpc_id(cleanup_items.vpc_id.clone().unwrap())
-            .send()
-            .await?;
-        print_cln!(
-            "Sent disassociate internet gateway, got: {:#?}",
-            disassoc_igw_out
-        );
+    // Delete the VPC
+    if let Some(vpc_id) = cleanup_items.vpc_id.as_deref() {
+        steps.push((
+            teardown::Kind::Vpc,
+            async move {
+                cleanup_step(
+                    &format!("VPC {}", vpc_id),
+                    &format!("DeleteVpc {}", vpc_id),
+                    &[vpc_id],
+                    || {
+                        aws_client
+                            .delete_vpc()
+                            .dry_run(dry_run::enabled())
+                            .vpc_id(vpc_id)
+                            .send()
+                    },
+                )
+                .await
+            }
+            .boxed(),
+        ));
     } else {
-        print_cln!("No IGW to disassociate.");
+        debug!(target: CLEANUP_TARGET, "No VPC to delete.");
     }
 
-    // Delete the IGW
-    if let Some(igw_id) = cleanup_items.igw_id.clone() {
-        print_cln!("Deleting IGW: {:#?}", igw_id);
+    report.extend(teardown::run(steps).await);
 
-        let del_igw_out = aws_client
-            .delete_internet_gateway()
-            .internet_gateway_id(igw_id)
-            .send()
-            .await?;
-        print_cln!("Sent delete internet gateway, got: {:#?}", del_igw_out);
+    // Check that it all really went (main route tables included, as they go with the VPC)
+    inventory.vpc_ids = cleanup_items.vpc_id.iter().cloned().collect();
+    inventory.subnet_ids = cleanup_items.subnet_ids.clone().unwrap_or_default();
+    inventory.internet_gateway_ids = cleanup_items.igw_id.iter().cloned().collect();
+    inventory.route_table_ids = cleanup_items.route_table_ids.clone().unwrap_or_default();
+    inventory.security_group_ids = cleanup_items.security_group_ids.clone().unwrap_or_default();
+    teardown::verify(aws_client, &inventory, &mut report).await;
+
+    if report.is_complete() {
+        if let Some(vpc_id) = &cleanup_items.vpc_id {
+            shutdown::untrack(vpc_id);
+        }
+        info!(target: CLEANUP_TARGET, vpc_id = cleanup_items.vpc_id.as_deref(), "Clean-up complete for VPC: {}", report);
     } else {
-        print_cln!("No IGW to delete.");
+        error!(target: CLEANUP_TARGET, vpc_id = cleanup_items.vpc_id.as_deref(), "Clean-up incomplete for VPC: {}", report);
     }
+    report.into_result()
+}
 
-    // Delete the subnets
-    if let Some(subnet_ids) = cleanup_items.subnet_ids.clone() {
-        for subnet_id in subnet_ids {
-            print_cln!("Deleting subnet: {:#?}", subnet_id);
+/// Tear down everything created by `create_cluster`.
+///
+/// Instances are terminated and waited on, since neither the shared volume nor the VPC they live in
+/// can be deleted while they are still around; the Elastic IP and the volume then go at once.
+pub async fn cleanup_cluster(
+    aws_client: &aws_sdk_ec2::Client,
+    cleanup_items: ClusterCleanup,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let inventory = teardown::Inventory {
+        instance_ids: cleanup_items.instance_ids.clone(),
+        volume_ids: cleanup_items.volume_id.iter().cloned().collect(),
+        allocation_ids: cleanup_items.elastic_ip.iter().cloned().collect(),
+        placement_groups: cleanup_items.placement_group.iter().cloned().collect(),
+        ..Default::default()
+    };
 
-            let del_subnet_out = aws_client
-                .delete_subnet()
-                .subnet_id(subnet_id)
-                .send()
-                .await?;
-            print_cln!("Sent delete subnet, got: {:#?}", del_subnet_out);
-        }
+    let mut steps: Vec<teardown::Step> = Vec::new();
+    if !cleanup_items.instance_ids.is_empty() {
+        steps.push((
+            teardown::Kind::Instances,
+            terminate_step(aws_client, cleanup_items.instance_ids).boxed(),
+        ));
     } else {
-        print_cln!("No subnets to delete.");
+        debug!(target: CLEANUP_TARGET, "No instances to terminate.");
     }
 
-    // Disassociate all routes from route tables
-    if let Some(route_table_ids) = cleanup_items.route_table_ids.clone() {
-        for rt_id in route_table_ids {
-            let routes = aws_client
-                .describe_route_tables()
-                .route_table_ids(rt_id.clone())
-                .send()
-                .await?
-                .route_tables
-                .unwrap();
-
-            for rt in routes {
-                let associations = rt.associations.unwrap();
-                for assoc in associations {
-                    if let Some(assoc_id) = assoc.route_table_association_id {
-                        print_cln!("Disassociating route table: {:#?}", assoc_id);
-
-                        // Try to disassociate the route table
-                        let disassoc_out = match aws_client
-                            .disassociate_route_table()
-                            .association_id(assoc_id)
+    if let Some(allocation_id) = cleanup_items.elastic_ip {
+        steps.push((
+            teardown::Kind::ElasticIps,
+            release_step(aws_client, allocation_id).boxed(),
+        ));
+    }
+
+    if let Some(volume_id) = cleanup_items.volume_id {
+        steps.push((
+            teardown::Kind::Volumes,
+            async move {
+                let report = cleanup_step(
+                    &format!("volume {}", volume_id),
+                    &format!("DeleteVolume {}", volume_id),
+                    &[&volume_id],
+                    || {
+                        aws_client
+                            .delete_volume()
+                            .dry_run(dry_run::enabled())
+                            .volume_id(&volume_id)
                             .send()
-                            .await {
-                            Ok(val) => val,
-                            Err(e) => {
-                                print_cln!(
-                                    "[WARNING] Failed to disassociate route table: {:#?}",
-                                    e
-                                );
-
-                                // Handle skipping if the association was not found
-                                // Note: Not found means we can't disassociate it, so we just continue. There
-                                //       may be a bug somewhere else that causes this though, so be careful.
-                                match e.code() {
-                                    Some(code) => {
-                                        if code == "InvalidAssociationID.NotFound" {
-                                            print_cln!("Because association not found, skipping disassociation. There might be a bug somewhere that caused this! Be careful!");
-
-                                            // Just continue to next association
-                                            continue;
-                                        }
-
-                                        return Err(Box::new(e));
-                                    }
-                                    None => {
-                                        return Err(Box::new(e));
-                                    }
-                                };
-                            },
-                        };
-                        print_cln!("Sent disassociate route table, got: {:#?}", disassoc_out);
-                    }
+                    },
+                )
+                .await;
+                if report.is_complete() {
+                    shutdown::untrack(&volume_id);
                 }
+                report
             }
-        }
+            .boxed(),
+        ));
     } else {
-        print_cln!("No route tables to disassociate.");
+        debug!(target: CLEANUP_TARGET, "No shared volume to delete.");
     }
 
-    // Delete the route tables
-    if let Some(route_table_ids) = cleanup_items.route_table_ids.clone() {
-        for rt_id in route_table_ids {
-            print_cln!("Deleting route table: {:#?}", rt_id);
+    if let Some(name) = cleanup_items.placement_group {
+        steps.push((
+            teardown::Kind::PlacementGroups,
+            async move {
+                let report = cleanup_step(
+                    &format!("placement group {}", name),
+                    &format!("DeletePlacementGroup {}", name),
+                    &[&name],
+                    || {
+                        aws_client
+                            .delete_placement_group()
+                            .dry_run(dry_run::enabled())
+                            .group_name(&name)
+                            .send()
+                    },
+                )
+                .await;
+                if report.is_complete() {
+                    shutdown::untrack(&name);
+                }
+                report
+            }
+            .boxed(),
+        ));
+    }
 
-            let del_rt_out = aws_client
-                .delete_route_table()
-                .route_table_id(rt_id)
-                .send()
-                .await?;
-            print_cln!("Sent delete route table, got: {:#?}", del_rt_out);
-        }
+    let mut report = teardown::run(steps).await;
+    teardown::verify(aws_client, &inventory, &mut report).await;
+    if report.is_complete() {
+        info!(target: CLEANUP_TARGET, "Clean-up complete for cluster: {}", report);
     } else {
-        print_cln!("No route tables to delete.");
+        error!(target: CLEANUP_TARGET, "Clean-up incomplete for cluster: {}", report);
     }
+    report.into_result().map(|_| ())
+}
 
-    // Delete the VPC
-    if let Some(vpc_id) = cleanup_items.vpc_id.clone() {
-        print_cln!("Deleting VPC: {:#?}", vpc_id);
+/// Delete a volume that is no longer attached to anything.
+pub async fn delete_volume(
+    aws_client: &aws_sdk_ec2::Client,
+    volume_id: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!(target: CLEANUP_TARGET, "Deleting volume: {}", volume_id);
+    let delete = aws_client
+        .delete_volume()
+        .dry_run(dry_run::enabled())
+        .volume_id(volume_id)
+        .send();
+    dry_run::send(&format!("DeleteVolume {}", volume_id), &[volume_id], delete).await?;
+    shutdown::untrack(volume_id);
+    Ok(())
+}
 
-        let del_vpc_out = aws_client.delete_vpc().vpc_id(vpc_id).send().await?;
-        print_cln!("Sent delete VPC, got: {:#?}", del_vpc_out);
-    } else {
-        print_cln!("No VPC to delete.");
+/// Record what a VPC being created consists of so far, and roll it back if an interrupt has come in.
+///
+/// Called between the steps of `create_vpc`, so an interrupt stops it from creating anything more.
+async fn vpc_checkpoint(
+    aws_client: &aws_sdk_ec2::Client,
+    cleanup_items: &VpcCleanup,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(vpc_id) = &cleanup_items.vpc_id {
+        shutdown::track(vpc_id, Tracked::Vpc(cleanup_items.clone()));
     }
 
-    print_cln!("Clean-up complete for VPC: {:#?}", cleanup_items.vpc_id);
-
-    // Return success
+    if shutdown::requested() {
+        info!(target: CLEANUP_TARGET, "Interrupted, rolling back the VPC created so far...");
+        cleanup_vpc(aws_client, cleanup_items.clone()).await?;
+        return Err("Interrupted".into());
+    }
     Ok(())
 }
 
@@ -940,24 +3027,76 @@ pub async fn cleanup_vpc(
 //     Ok(())
 // }
 
+/// The `index`th /64 of a VPC's /56 IPv6 block (the size Amazon-provided blocks always have).
+pub fn subnet_ipv6_cidr(vpc_cidr: &str, index: u8) -> Result<String, Box<dyn std::error::Error>> {
+    let addr = match vpc_cidr.split_once('/') {
+        Some((addr, "56")) => addr,
+        _ => return Err(format!("Expected a /56 IPv6 block, got {:?}", vpc_cidr).into()),
+    };
+    // The first 56 bits end half-way through the fourth group; the subnet index fills the rest of it
+    let mut segments = addr.parse::<std::net::Ipv6Addr>()?.segments();
+    segments[3] = (segments[3] & 0xff00) | index as u16;
+    segments[4..].fill(0);
+    Ok(format!("{}/64", std::net::Ipv6Addr::from(segments)))
+}
+
+/// Poll until the Amazon-provided IPv6 block requested for a VPC is associated, and return it.
+async fn wait_for_vpc_ipv6_cidr(
+    aws_client: &Client,
+    vpc_id: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_secs(60);
+
+    loop {
+        let out = aws_client.describe_vpcs().vpc_ids(vpc_id).send().await?;
+        let associated = out
+            .vpcs()
+            .iter()
+            .flat_map(|v| v.ipv6_cidr_block_association_set())
+            .find(|a| {
+                a.ipv6_cidr_block_state().and_then(|s| s.state())
+                    == Some(&types::VpcCidrBlockStateCode::Associated)
+            })
+            .and_then(|a| a.ipv6_cidr_block());
+        if let Some(cidr) = associated {
+            return Ok(cidr.to_string());
+        }
+
+        if tokio::time::Instant::now() >= deadline {
+            return Err(format!("Timed out waiting for the IPv6 block of VPC {}", vpc_id).into());
+        }
+        debug!("Waiting for the IPv6 block of VPC {}...", vpc_id);
+        shutdown::sleep(tokio::time::Duration::from_secs(2)).await?;
+    }
+}
+
 /// Create a VPC.
+///
+/// If `expires_at` is given, every resource is tagged with it (see `EXPIRES_AT_TAG`).
 /// 
 /// # Returns
 /// * The ID of the created VPC as a `String` and a `VpcCleanup` struct that can be used to nuke the VPC, or errors.
-pub async fn create_vpc(aws_client: &Client, vpc_name: &str, project_tag: &str) -> Result<(String, VpcCleanup), Box<dyn std::error::Error>> {
-
+pub async fn create_vpc(
+    aws_client: &Client,
+    vpc_name: &str,
+    project_tag: &str,
+    expires_at: Option<&str>,
+) -> Result<(String, VpcCleanup), Box<dyn std::error::Error>> {
     // Create the struct that can be used to nuke the VPC
     let mut vpc_cleanup_items = VpcCleanup {
         vpc_id: None,
         igw_id: None,
         subnet_ids: None,
+        subnet_azs: None,
         route_table_ids: None,
         security_group_ids: None,
     };
 
     // Create a VPC
-    let vpc = aws_client
+    shutdown::check()?;
+    let create_vpc = aws_client
         .create_vpc()
+        .dry_run(dry_run::enabled())
         .cidr_block("10.0.0.0/16")
         .amazon_provided_ipv6_cidr_block(true)
         .ipv6_cidr_block_network_border_group("us-west-2")
@@ -976,20 +3115,61 @@ pub async fn create_vpc(aws_client: &Client, vpc_name: &str, project_tag: &str)
                         .value(project_tag)
                         .build(),
                 )
+                .expiry_tag(expires_at)
                 .build(),
         )
-        .send()
-        .await?
-        .vpc
-        .unwrap();
+        .send();
+    let vpc_id = match dry_run::send(
+        "CreateVpc 10.0.0.0/16 with an Amazon-provided IPv6 block",
+        &[],
+        create_vpc,
+    )
+    .await?
+    {
+        Some(out) => out
+            .vpc
+            .and_then(|vpc| vpc.vpc_id)
+            .ok_or("No VPC ID was returned in the response")?,
+        None => dry_run::placeholder("vpc"),
+    };
 
     // Print the VPC ID
-    let vpc_id = vpc.vpc_id.unwrap();
     vpc_cleanup_items.vpc_id = Some(vpc_id.clone());
-    println!("[DEBUG] Created VPC with ID: {:#?}", vpc_id);
+    debug!("Created VPC {}", vpc_id);
+    vpc_checkpoint(aws_client, &vpc_cleanup_items).await?;
+
+    // Wait for the VPC's IPv6 block, which each subnet gets a /64 of
+    let vpc_ipv6_cidr = if dry_run::enabled() {
+        "2001:db8::/56".to_string()
+    } else {
+        match wait_for_vpc_ipv6_cidr(aws_client, &vpc_id).await {
+            Ok(cidr) => cidr,
+            Err(e) => {
+                error!("No IPv6 block was associated with the VPC: {}", e);
+                return Err(roll_back_vpc(aws_client, &vpc_cleanup_items, e).await);
+            }
+        }
+    };
+    debug!("VPC {} has IPv6 block {}", vpc_id, vpc_ipv6_cidr);
 
     // Create a subnet for each availability zone
-    let azs = vec!["us-west-2a", "us-west-2b", "us-west-2c"];
+    let azs = ["us-west-2a", "us-west-2b", "us-west-2c"];
+
+    let mut subnet_ipv6_cidrs = Vec::new();
+    for i in 0..azs.len() {
+        subnet_ipv6_cidrs.push(subnet_ipv6_cidr(&vpc_ipv6_cidr, i.try_into()?)?);
+    }
+    let subnet_steps: Vec<String> = azs
+        .iter()
+        .enumerate()
+        .map(|(i, az)| {
+            format!(
+                "CreateSubnet 10.0.{}.0/24 and {} in {}",
+                i, subnet_ipv6_cidrs[i], az
+            )
+        })
+        .collect();
+    let vpc_ids = [vpc_id.as_str()];
 
     let mut subnet_futures = Vec::new();
     vpc_cleanup_items.subnet_ids = Some(Vec::new());
@@ -998,86 +3178,85 @@ pub async fn create_vpc(aws_client: &Client, vpc_name: &str, project_tag: &str)
         let ipv4_cider_block = format!("10.0.{}.0/24", i);
 
         // Create a subnet in the AZ
-        subnet_futures.push(
-            aws_client
-                .create_subnet()
-                .tag_specifications(
-                    types::TagSpecification::builder()
-                        .resource_type(types::ResourceType::Subnet)
-                        .tags(
-                            types::Tag::builder()
-                                .key("Name")
-                                .value(format!("Autocreated Subnet for {} in {}", vpc_name, az))
-                                .build(),
-                        )
-                        .tags(
-                            types::Tag::builder()
-                                .key("project")
-                                .value(project_tag)
-                                .build(),
-                        )
-                        .build(),
-                )
-                .availability_zone(az)
-                .cidr_block(ipv4_cider_block.clone())
-                .vpc_id(vpc_id.clone())
-                .send(),
-        );
+        let create_subnet = aws_client
+            .create_subnet()
+            .dry_run(dry_run::enabled())
+            .tag_specifications(
+                types::TagSpecification::builder()
+                    .resource_type(types::ResourceType::Subnet)
+                    .tags(
+                        types::Tag::builder()
+                            .key("Name")
+                            .value(format!("Autocreated Subnet for {} in {}", vpc_name, az))
+                            .build(),
+                    )
+                    .tags(
+                        types::Tag::builder()
+                            .key("project")
+                            .value(project_tag)
+                            .build(),
+                    )
+                    .expiry_tag(expires_at)
+                    .build(),
+            )
+            .availability_zone(az)
+            .cidr_block(ipv4_cider_block.clone())
+            .ipv6_cidr_block(subnet_ipv6_cidrs[i].clone())
+            .vpc_id(vpc_id.clone())
+            .send();
+        subnet_futures.push(dry_run::send(&subnet_steps[i], &vpc_ids, create_subnet));
     }
-    println!("[DEBUG] Sent all subnet creation requests.");
+    debug!("Sent all subnet creation requests.");
 
     // Wait for all subnets to be created
     let subnet_results = futures::future::join_all(subnet_futures).await;
     let mut subnets = Vec::new();
+    let mut subnet_error: Option<Box<dyn std::error::Error>> = None;
 
-    for result in subnet_results.iter() {
+    for (result, az) in subnet_results.into_iter().zip(azs) {
         match result {
-            Ok(create_subnet_output) => {
-                println!("Subnet creation request: {:#?}", create_subnet_output);
-                match create_subnet_output.subnet.clone() {
-                    Some(subnet) => {
-                        subnets.push(subnet);
+            Ok(None) => {
+                subnets.push((dry_run::placeholder("subnet"), az.to_string()));
+            }
+            Ok(Some(create_subnet_output)) => {
+                match create_subnet_output
+                    .subnet
+                    .and_then(|subnet| subnet.subnet_id)
+                {
+                    Some(subnet_id) => {
+                        subnets.push((subnet_id, az.to_string()));
                     }
                     None => {
-                        println!("[ERROR] No subnet was returned in the response!");
-
-                        // Clean up
-                        cleanup_vpc(&aws_client, vpc_cleanup_items.clone()).await?;
-
-                        panic!("[ERROR] No subnet was returned in the response!");
+                        error!("No subnet was returned in the response!");
+                        subnet_error.get_or_insert_with(|| {
+                            format!("No subnet was returned for {}", az).into()
+                        });
                     }
                 }
             }
             Err(e) => {
-                println!("[ERROR] Subnet creation failed: {:#?}", e);
-
-                // Clean up
-                cleanup_vpc(&aws_client, vpc_cleanup_items.clone()).await?;
-
-                panic!("[ERROR] Subnet creation failed: {:#?}", e);
+                error!("Subnet creation failed: {}", e);
+                subnet_error.get_or_insert_with(|| e.into());
             }
         };
     }
-    println!("Created subnets: {:#?}", subnets);
-    vpc_cleanup_items.subnet_ids = Some(
-        subnets
-            .iter()
-            .map(|s| s.subnet_id.clone().unwrap())
-            .collect(),
-    );
-    println!(
-        "[DEBUG] Created subnets: {:#?}",
-        subnets
-            .iter()
-            .map(|s| s.subnet_id.clone().unwrap())
-            .collect::<Vec<String>>()
+    vpc_cleanup_items.subnet_ids = Some(subnets.iter().map(|(id, _)| id.clone()).collect());
+    vpc_cleanup_items.subnet_azs = Some(subnets.iter().map(|(_, az)| az.clone()).collect());
+    if let Some(e) = subnet_error {
+        return Err(roll_back_vpc(aws_client, &vpc_cleanup_items, e).await);
+    }
+    debug!(
+        "Created subnets: {:?}",
+        vpc_cleanup_items.subnet_ids.as_deref().unwrap_or_default()
     );
+    vpc_checkpoint(aws_client, &vpc_cleanup_items).await?;
 
     // Create an internet gateway
     // Note: This is necessary to allow instances to communicate with the internet
     let igw = {
-        let result = aws_client
+        let create_igw = aws_client
             .create_internet_gateway()
+            .dry_run(dry_run::enabled())
             .tag_specifications(
                 types::TagSpecification::builder()
                     .resource_type(types::ResourceType::InternetGateway)
@@ -1093,51 +3272,55 @@ pub async fn create_vpc(aws_client: &Client, vpc_name: &str, project_tag: &str)
                             .value(project_tag)
                             .build(),
                     )
+                    .expiry_tag(expires_at)
                     .build(),
             )
-            .send()
-            .await;
+            .send();
 
-        match result {
-            Ok(val) => val.internet_gateway.unwrap(),
+        match dry_run::send("CreateInternetGateway", &[], create_igw).await {
+            Ok(Some(val)) => val.internet_gateway.and_then(|igw| igw.internet_gateway_id),
+            Ok(None) => Some(dry_run::placeholder("igw")),
             Err(e) => {
-                println!("[ERROR] Failed to create internet gateway: {:#?}", e);
-
-                // Clean up
-                cleanup_vpc(&aws_client, vpc_cleanup_items.clone()).await?;
-
-                panic!("[ERROR] Failed to create internet gateway: {:#?}", e);
+                error!("Failed to create internet gateway: {}", e);
+                return Err(roll_back_vpc(aws_client, &vpc_cleanup_items, e.into()).await);
             }
         }
     };
-    let igw_id = igw.internet_gateway_id.as_ref().unwrap();
+    let igw_id = match &igw {
+        Some(igw_id) => igw_id,
+        None => {
+            let e = "No internet gateway was returned in the response".into();
+            return Err(roll_back_vpc(aws_client, &vpc_cleanup_items, e).await);
+        }
+    };
     vpc_cleanup_items.igw_id = Some(igw_id.clone());
-    println!("[DEBUG] Created internet gateway: {:#?}", igw);
+    debug!("Created internet gateway {}", igw_id);
 
     // Attach the internet gateway to the VPC
-    let attach_igw_output = match aws_client
+    let attach_igw = aws_client
         .attach_internet_gateway()
+        .dry_run(dry_run::enabled())
         .internet_gateway_id(igw_id.clone())
         .vpc_id(vpc_id.clone())
-        .send()
-        .await
-    {
+        .send();
+    let step = format!("AttachInternetGateway {} to {}", igw_id, vpc_id);
+    let attach_igw_output = match dry_run::send(&step, &[igw_id, &vpc_id], attach_igw).await {
         Ok(val) => val,
         Err(e) => {
-            println!("[ERROR] Failed to attach internet gateway to VPC: {:#?}", e);
-
-            // Clean up
-            cleanup_vpc(&aws_client, vpc_cleanup_items.clone()).await?;
-
-            panic!("[ERROR] Failed to attach internet gateway to VPC: {:#?}", e);
+            error!("Failed to attach internet gateway to VPC: {}", e);
+            return Err(roll_back_vpc(aws_client, &vpc_cleanup_items, e.into()).await);
         }
     };
-    println!("Attached internet gateway to VPC: {:#?}", attach_igw_output);
+    if attach_igw_output.is_some() {
+        debug!("Attached internet gateway {} to VPC {}", igw_id, vpc_id);
+    }
+    vpc_checkpoint(aws_client, &vpc_cleanup_items).await?;
 
     // Create a route table
     let route_table = {
-        let result = aws_client
+        let create_route_table = aws_client
             .create_route_table()
+            .dry_run(dry_run::enabled())
             .tag_specifications(
                 types::TagSpecification::builder()
                     .resource_type(types::ResourceType::RouteTable)
@@ -1153,51 +3336,69 @@ pub async fn create_vpc(aws_client: &Client, vpc_name: &str, project_tag: &str)
                             .value(project_tag)
                             .build(),
                     )
+                    .expiry_tag(expires_at)
                     .build(),
             )
             .vpc_id(vpc_id.clone())
-            .send()
-            .await;
+            .send();
 
-        match result {
-            Ok(val) => val.route_table.unwrap(),
+        match dry_run::send(
+            &format!("CreateRouteTable in {}", vpc_id),
+            &vpc_ids,
+            create_route_table,
+        )
+        .await
+        {
+            Ok(Some(val)) => val.route_table.and_then(|rtb| rtb.route_table_id),
+            Ok(None) => Some(dry_run::placeholder("rtb")),
             Err(e) => {
-                println!("[ERROR] Failed to create route table: {:#?}", e);
-
-                // Clean up
-                cleanup_vpc(&aws_client, vpc_cleanup_items.clone()).await?;
-
-                panic!("[ERROR] Failed to create route table: {:#?}", e);
+                error!("Failed to create route table: {}", e);
+                return Err(roll_back_vpc(aws_client, &vpc_cleanup_items, e.into()).await);
             }
         }
     };
-    let route_table_id = route_table.route_table_id.as_ref().unwrap().clone();
+    let route_table_id = match route_table {
+        Some(route_table_id) => route_table_id,
+        None => {
+            let e = "No route table was returned in the response".into();
+            return Err(roll_back_vpc(aws_client, &vpc_cleanup_items, e).await);
+        }
+    };
     vpc_cleanup_items.route_table_ids = Some(vec![route_table_id.clone()]);
-    println!("[DEBUG] Created route table: {:#?}", route_table_id);
+    debug!("Created route table {}", route_table_id);
 
     // Add routes to the route table
     {
-        let mut futures = Vec::new();
-
         // Submit 'add route' requests
-
-        futures.push(
-            aws_client
-                .create_route()
-                .destination_cidr_block("0.0.0.0/0")
-                .gateway_id(igw_id.clone())
-                .route_table_id(route_table_id.clone())
-                .send(),
-        );
-
-        futures.push(
-            aws_client
-                .create_route()
-                .destination_ipv6_cidr_block("::/0")
-                .gateway_id(igw_id.clone())
-                .route_table_id(route_table_id.clone())
-                .send(),
-        );
+        let steps = [
+            format!("CreateRoute 0.0.0.0/0 via {} in {}", igw_id, route_table_id),
+            format!("CreateRoute ::/0 via {} in {}", igw_id, route_table_id),
+        ];
+        let ids = [igw_id.as_str(), route_table_id.as_str()];
+        let futures = vec![
+            dry_run::send(
+                &steps[0],
+                &ids,
+                aws_client
+                    .create_route()
+                    .dry_run(dry_run::enabled())
+                    .destination_cidr_block("0.0.0.0/0")
+                    .gateway_id(igw_id.clone())
+                    .route_table_id(route_table_id.clone())
+                    .send(),
+            ),
+            dry_run::send(
+                &steps[1],
+                &ids,
+                aws_client
+                    .create_route()
+                    .dry_run(dry_run::enabled())
+                    .destination_ipv6_cidr_block("::/0")
+                    .gateway_id(igw_id.clone())
+                    .route_table_id(route_table_id.clone())
+                    .send(),
+            ),
+        ];
 
         // Wait for all routes to be created
         let results = futures::future::join_all(futures).await;
@@ -1205,46 +3406,33 @@ pub async fn create_vpc(aws_client: &Client, vpc_name: &str, project_tag: &str)
         // Handle the results
         for result in results {
             match result {
-                Ok(val) => {
-                    println!("Created route: {:#?}", val);
+                Ok(Some(_)) => {
+                    debug!("Created route in {}", route_table_id);
                 }
+                Ok(None) => {}
                 Err(e) => {
-                    println!("[ERROR] Failed to create route: {:#?}", e);
-
-                    // Clean up
-                    cleanup_vpc(&aws_client, vpc_cleanup_items.clone()).await?;
-
-                    panic!("[ERROR] Failed to create route: {:#?}", e);
+                    error!("Failed to create route: {}", e);
+                    return Err(roll_back_vpc(aws_client, &vpc_cleanup_items, e.into()).await);
                 }
             }
         }
     };
 
     // Associate the route table with each subnet
-    for subnet in &subnets {
-        let subnet_id = subnet.subnet_id().unwrap();
-
-        let assoc = match aws_client
+    for (subnet_id, _) in &subnets {
+        let associate = aws_client
             .associate_route_table()
-            .route_table_id(route_table.route_table_id.as_ref().unwrap())
+            .dry_run(dry_run::enabled())
+            .route_table_id(route_table_id.clone())
             .subnet_id(subnet_id)
-            .send()
-            .await
-        {
-            Ok(val) => val,
+            .send();
+        let step = format!("AssociateRouteTable {} with {}", route_table_id, subnet_id);
+        let assoc = match dry_run::send(&step, &[&route_table_id, subnet_id], associate).await {
+            Ok(Some(val)) => val,
+            Ok(None) => continue,
             Err(e) => {
-                println!(
-                    "[ERROR] Failed to associate route table with subnet: {:#?}",
-                    e
-                );
-
-                // Clean up
-                cleanup_vpc(&aws_client, vpc_cleanup_items.clone()).await?;
-
-                panic!(
-                    "[ERROR] Failed to associate route table with subnet: {:#?}",
-                    e
-                );
+                error!("Failed to associate route table with subnet: {}", e);
+                return Err(roll_back_vpc(aws_client, &vpc_cleanup_items, e.into()).await);
             }
         };
 
@@ -1252,25 +3440,27 @@ pub async fn create_vpc(aws_client: &Client, vpc_name: &str, project_tag: &str)
         // Note: This is to ensure that the route table is actually associated with the subnet
         match assoc.association_state() {
             Some(state) => {
-                println!("Associated route table with subnet: {:#?}", state);
+                debug!(
+                    "Associated route table with subnet {}: {:?}",
+                    subnet_id,
+                    state.state()
+                );
             }
             None => {
-                println!("[WARNING] No association state was returned in the response!");
-
-                // Clean up
-                cleanup_vpc(&aws_client, vpc_cleanup_items.clone()).await?;
-
-                panic!("[ERROR] No association state was returned in the response!");
+                warn!("No association state was returned in the response!");
+                let e = format!("No association state was returned for subnet {}", subnet_id);
+                return Err(roll_back_vpc(aws_client, &vpc_cleanup_items, e.into()).await);
             }
         }
 
-        println!("Associated route table with subnet: {:#?}", assoc);
     }
+    vpc_checkpoint(aws_client, &vpc_cleanup_items).await?;
 
     // Create security group
     let sg_name = "experimental-sdk-sg";
-    let create_sg_output = match aws_client
+    let create_sg = aws_client
         .create_security_group()
+        .dry_run(dry_run::enabled())
         .description("Experimental Autocreated Security Group")
         .group_name(sg_name)
         .tag_specifications(
@@ -1288,26 +3478,32 @@ pub async fn create_vpc(aws_client: &Client, vpc_name: &str, project_tag: &str)
                         .value(project_tag)
                         .build(),
                 )
+                .expiry_tag(expires_at)
                 .build(),
         )
         .vpc_id(vpc_id.clone())
-        .send()
-        .await
-    {
+        .send();
+    let step = format!("CreateSecurityGroup {} in {}", sg_name, vpc_id);
+    let create_sg_output = match dry_run::send(&step, &vpc_ids, create_sg).await {
         Ok(val) => val,
         Err(e) => {
-            println!("[ERROR] Failed to create security group: {:#?}", e);
-
-            // Clean up
-            cleanup_vpc(&aws_client, vpc_cleanup_items.clone()).await?;
-
-            panic!("[ERROR] Failed to create security group: {:#?}", e);
+            error!("Failed to create security group: {}", e);
+            return Err(roll_back_vpc(aws_client, &vpc_cleanup_items, e.into()).await);
         }
     };
-    println!("Created security group: {:#?}", create_sg_output);
-    let sg_id = create_sg_output.group_id.unwrap();
+    let sg_id = match create_sg_output {
+        Some(create_sg_output) => match create_sg_output.group_id {
+            Some(sg_id) => sg_id,
+            None => {
+                let e = "No security group ID was returned in the response".into();
+                return Err(roll_back_vpc(aws_client, &vpc_cleanup_items, e).await);
+            }
+        },
+        None => dry_run::placeholder("sg"),
+    };
     vpc_cleanup_items.security_group_ids = Some(vec![sg_id.clone()]);
-    println!("[DEBUG] Created security group: {:#?}", sg_id);
+    debug!("Created security group {}", sg_id);
+    vpc_checkpoint(aws_client, &vpc_cleanup_items).await?;
 
     // Add ingress/egress rules to the security group
     let sec_group_ingress_out = {
@@ -1358,46 +3554,60 @@ pub async fn create_vpc(aws_client: &Client, vpc_name: &str, project_tag: &str)
             .build();
 
         // Add SSH ingress rule
-        match aws_client
+        let authorize = aws_client
             .authorize_security_group_ingress()
+            .dry_run(dry_run::enabled())
             .group_id(sg_id.clone())
             .ip_permissions(ip_perm_ssh)
             .ip_permissions(intra_sg_all)
             // .ip_permissions(ip_perm_http)
             // .source_security_group_name(sg_id.clone())
-            .send()
-            .await
-        {
+            .send();
+        let step = format!(
+            "AuthorizeSecurityGroupIngress SSH and intra-group traffic on {}",
+            sg_id
+        );
+        match dry_run::send(&step, &[&sg_id], authorize).await {
             Ok(val) => val,
             Err(e) => {
-                println!(
-                    "[ERROR] Failed to add SSH ingress rules to security group: {:#?}",
-                    e
-                );
-
-                // Clean up
-                cleanup_vpc(&aws_client, vpc_cleanup_items.clone()).await?;
-
-                panic!(
-                    "[ERROR] Failed to add SSH ingress rules to security group: {:#?}",
-                    e
-                );
+                error!("Failed to add SSH ingress rules to security group: {}", e);
+                return Err(roll_back_vpc(aws_client, &vpc_cleanup_items, e.into()).await);
             }
         }
     };
-    println!(
-        "Added ingress rules to security group: {:#?}",
-        sec_group_ingress_out
-    );
+    if sec_group_ingress_out.is_some() {
+        debug!("Added ingress rules to security group {}", sg_id);
+    }
 
-    println!(
-        "🎉🎉🎉 {}Finished setting up the entire VPC! (ID: {:#?}){} 🎉🎉🎉",
-        color::Fg(color::Green),
-        vpc_id,
-        style::Reset
-    );
+    // New security groups only allow outbound IPv4, which leaves IPv6-only nodes with no way out
+    let authorize_egress = aws_client
+        .authorize_security_group_egress()
+        .dry_run(dry_run::enabled())
+        .group_id(sg_id.clone())
+        .ip_permissions(
+            types::IpPermission::builder()
+                .ip_protocol("-1")
+                .ipv6_ranges(
+                    types::Ipv6Range::builder()
+                        .cidr_ipv6("::/0")
+                        .description("Allow all outbound traffic (ipv6)")
+                        .build(),
+                )
+                .build(),
+        )
+        .send();
+    let step = format!("AuthorizeSecurityGroupEgress ::/0 on {}", sg_id);
+    if let Err(e) = dry_run::send(&step, &[&sg_id], authorize_egress).await {
+        error!(
+            "Failed to add the IPv6 egress rule to security group: {}",
+            e
+        );
+
+        return Err(roll_back_vpc(aws_client, &vpc_cleanup_items, e.into()).await);
+    }
 
-    println!("{}[IMPORTANT] 💣💣💣 Here is the info you'll need to nuke the VPC 💣💣💣: {:#?}{}", color::Fg(color::Magenta), vpc_cleanup_items, style::Reset);
+    info!(vpc_id, "Finished setting up the VPC");
+    debug!("Everything needed to nuke the VPC: {:?}", vpc_cleanup_items);
 
     // Return the VPC ID
     Ok((vpc_id, vpc_cleanup_items))
@@ -1407,12 +3617,19 @@ pub async fn create_vpc(aws_client: &Client, vpc_name: &str, project_tag: &str)
 pub async fn test_create_vpc() -> Result<(), Box<dyn std::error::Error>> {
     // Set up AWS environment and client
     let shared_config = aws_config::load_from_env().await;
-    let aws_client: aws_sdk_ec2::Client = aws_sdk_ec2::Client::new(&shared_config);
+    let aws_client: aws_sdk_ec2::Client = crate::retry::client(&shared_config);
+    shutdown::install_handler();
 
     println!("{}[TEST_CREATE_VPC] Attempting to create a VPC...{}", color::Fg(color::Yellow), style::Reset);
 
     // Create the VPC
-    let (vpc_id, vpc_cleanup_items) = create_vpc(&aws_client, "Experimental Autocreated VPC", "testing_sdk").await?;
+    let (vpc_id, vpc_cleanup_items) = create_vpc(
+        &aws_client,
+        "Experimental Autocreated VPC",
+        "testing_sdk",
+        None,
+    )
+    .await?;
 
     let time_delay = 15;
     println!("{}[TEST_CREATE_VPC] Success! Created VPC with ID: {:#?}{} Waiting {} second(s) before destroying...", color::Fg(color::Green), vpc_id, style::Reset, time_delay);
@@ -1428,3 +3645,147 @@ pub async fn test_create_vpc() -> Result<(), Box<dyn std::error::Error>> {
     // Return success
     Ok(())
 }
+
+#[test]
+fn test_subnet_ipv6_cidr() {
+    assert_eq!(
+        subnet_ipv6_cidr("2600:1f14:abc:de00::/56", 0).unwrap(),
+        "2600:1f14:abc:de00::/64"
+    );
+    assert_eq!(
+        subnet_ipv6_cidr("2600:1f14:abc:de00::/56", 2).unwrap(),
+        "2600:1f14:abc:de02::/64"
+    );
+    assert_eq!(
+        subnet_ipv6_cidr("2001:db8::/56", 255).unwrap(),
+        "2001:db8:0:ff::/64"
+    );
+    assert!(subnet_ipv6_cidr("2001:db8::/48", 0).is_err());
+}
+
+#[test]
+fn test_cleanup_report() {
+    assert!(already_gone(Some("InvalidSubnetID.NotFound")));
+    assert!(already_gone(Some("InvalidAssociationID.NotFound")));
+    assert!(already_gone(Some("Gateway.NotAttached")));
+    assert!(already_gone(Some("InvalidPlacementGroup.Unknown")));
+    assert!(!already_gone(Some("DependencyViolation")));
+    assert!(!already_gone(None));
+
+    let mut report = CleanupReport {
+        deleted: vec!["subnet subnet-1".to_string()],
+        already_gone: vec!["internet gateway igw-1".to_string()],
+        failed: vec![],
+        survivors: vec![],
+    };
+    assert!(report.clone().into_result().is_ok());
+
+    report
+        .failed
+        .push(("VPC vpc-1".to_string(), "DependencyViolation".to_string()));
+    let e = report.into_result().unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "1 deleted, 1 already gone, 1 failed, 0 still there; VPC vpc-1: DependencyViolation"
+    );
+    assert!(e.downcast_ref::<CleanupReport>().is_some());
+
+    let survived = CleanupReport {
+        survivors: vec!["instance i-1 (running)".to_string()],
+        ..Default::default()
+    };
+    assert!(!survived.is_complete());
+    assert_eq!(
+        survived.to_string(),
+        "0 deleted, 0 already gone, 0 failed, 1 still there; instance i-1 (running) is still there"
+    );
+
+    let mut combined = CleanupReport::default();
+    combined.add_error("VPC", &survived);
+    combined.add_error("cluster", &*Box::<dyn std::error::Error>::from("timed out"));
+    assert_eq!(combined.survivors, survived.survivors);
+    assert_eq!(
+        combined.failed,
+        vec![("cluster".to_string(), "timed out".to_string())]
+    );
+}
+
+#[test]
+fn test_launch_batches() {
+    let instance_template = InstanceTemplate {
+        availability_zone: "us-west-2a",
+        ami_image_id: "ami-1",
+        instance_type: types::InstanceType::G52xlarge,
+        fallback_instance_types: Vec::new(),
+        subnet_id: "subnet-1",
+        security_group_id: "sg-1",
+        num_ifaces: 1,
+        use_efa: false,
+        user_data: None,
+        project_tag: "nccl",
+        expires_at: None,
+        shutdown_on_expiry: false,
+        ip_mode: IpMode::Ipv4,
+        public_ip: PublicIp::All,
+        role: NodeRole::Head,
+        node_group: None,
+        placement_group: None,
+    };
+    let group = |name, count| NodeGroup {
+        name,
+        count,
+        instance_type: types::InstanceType::P4d24xlarge,
+        fallback_instance_types: Vec::new(),
+        num_ifaces: 4,
+        use_efa: false,
+        user_data: None,
+    };
+    let mut template = ClusterTemplate {
+        cluster_name: "nccl",
+        num_instances: 5,
+        instance_template,
+        attach_shared_ebs: false,
+        shared_ebs_volume_size: None,
+        project_tag: "nccl",
+        ttl: None,
+        shutdown_on_expiry: false,
+        elastic_ip: false,
+        node_groups: Vec::new(),
+        launch_policy: LaunchPolicy::Atomic,
+        placement_group: true,
+    };
+
+    assert_eq!(template.batches(&[1, 2, 3, 4]), vec![vec![1, 2, 3, 4]]);
+    assert_eq!(template.batches(&[1, 3, 4]), vec![vec![1], vec![3, 4]]);
+
+    template.node_groups = vec![group("head", 2), group("gpu", 3)];
+    assert_eq!(
+        template.batches(&[1, 2, 3, 4]),
+        vec![vec![1], vec![2, 3, 4]]
+    );
+
+    assert!(!template.launches_in_one_request());
+    template.node_groups.truncate(1);
+    template.node_groups[0].count = 5;
+    assert!(template.launches_in_one_request());
+    assert!(!template.head_elastic_ip());
+    template.instance_template.ip_mode = IpMode::DualStack;
+    template.instance_template.public_ip = PublicIp::Head;
+    assert!(template.head_elastic_ip());
+    template.launch_policy = LaunchPolicy::All;
+    assert!(!template.launches_in_one_request());
+    assert!(!template.head_elastic_ip());
+
+    assert_eq!(LaunchPolicy::Atomic.required(8), 8);
+    assert_eq!(LaunchPolicy::AtLeast(6).required(8), 6);
+    assert_eq!(LaunchPolicy::BestEffort.required(8), 1);
+}
+
+#[test]
+fn test_is_capacity_error() {
+    assert!(is_capacity_error(Some("InsufficientInstanceCapacity")));
+    assert!(is_capacity_error(Some("Unsupported")));
+    assert!(is_capacity_error(Some("VcpuLimitExceeded")));
+    assert!(!is_capacity_error(Some("InstanceLimitExceeded")));
+    assert!(!is_capacity_error(None));
+}
@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::watch;
+use tracing::{error, warn};
+
+use crate::sdk_wrapper::{self, CleanupReport, VpcCleanup};
+
+/// A resource that exists in AWS because of this process and has not been torn down yet.
+#[derive(Debug, Clone)]
+pub enum Tracked {
+    Instance(String),
+    Volume(String),
+    /// An Elastic IP, by allocation ID.
+    ElasticIp(String),
+    /// A placement group, by name.
+    PlacementGroup(String),
+    Vpc(VpcCleanup),
+}
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static TRACKED: Mutex<BTreeMap<String, Tracked>> = Mutex::new(BTreeMap::new());
+static NOTIFY: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+fn notifier() -> &'static watch::Sender<bool> {
+    NOTIFY.get_or_init(|| watch::channel(false).0)
+}
+
+/// Whether an interrupt has been received. Code creating resources checks this before every create
+/// call and rolls back instead of carrying on.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Resolve once an interrupt has been received.
+pub async fn wait() {
+    let mut rx = notifier().subscribe();
+    let _ = rx.wait_for(|v| *v).await;
+}
+
+/// Record a created resource under its ID, replacing whatever was recorded under that ID before.
+pub fn track(id: &str, resource: Tracked) {
+    TRACKED.lock().unwrap().insert(id.to_string(), resource);
+}
+
+/// Forget a resource, either because it was torn down or because it is meant to outlive us.
+pub fn untrack(id: &str) {
+    TRACKED.lock().unwrap().remove(id);
+}
+
+/// Everything recorded so far.
+pub fn tracked() -> Vec<Tracked> {
+    TRACKED.lock().unwrap().values().cloned().collect()
+}
+
+/// Listen for SIGINT (Ctrl-C) and SIGTERM.
+///
+/// On the first signal, further create calls are refused and callers tear down what they made (see
+/// `cleanup_tracked`). If nothing has been created yet there is nothing to wait for, so the process
+/// exits straight away. On the second signal, the process exits immediately after printing
+/// whatever is still up, so it can be cleaned up by hand.
+///
+/// Installing the handler more than once (e.g. from several tests) has no further effect.
+pub fn install_handler() {
+    if INSTALLED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let notify = notifier();
+
+    tokio::spawn(async move {
+        let mut sigterm =
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(sigterm) => Some(sigterm),
+                Err(e) => {
+                    warn!("Could not listen for SIGTERM: {}", e);
+                    None
+                }
+            };
+
+        loop {
+            tokio::select! {
+                res = tokio::signal::ctrl_c() => {
+                    if res.is_err() {
+                        return;
+                    }
+                }
+                Some(_) = async {
+                    match sigterm.as_mut() {
+                        Some(sigterm) => sigterm.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {}
+            }
+
+            let remaining = tracked();
+            if requested() || remaining.is_empty() {
+                print_remaining(&remaining);
+                std::process::exit(130);
+            }
+
+            warn!(
+                "Interrupted! Cleaning up {} resource(s); interrupt again to exit immediately.",
+                remaining.len()
+            );
+            REQUESTED.store(true, Ordering::SeqCst);
+            let _ = notify.send(true);
+        }
+    });
+}
+
+fn print_remaining(remaining: &[Tracked]) {
+    if remaining.is_empty() {
+        return;
+    }
+
+    error!(
+        "Exiting with resources still up, clean these up by hand: {:?}",
+        remaining
+    );
+}
+
+/// Return an error if an interrupt has been received, so no further resources are created.
+pub fn check() -> Result<(), Box<dyn std::error::Error>> {
+    if requested() {
+        return Err("Interrupted".into());
+    }
+    Ok(())
+}
+
+/// Sleep between the polls of a long wait. An interrupt arriving meanwhile cuts the sleep short
+/// with an error, so the caller stops waiting and rolls back. While rolling back (an interrupt
+/// has already come in) it is a plain sleep, which a second interrupt ends by exiting.
+pub async fn sleep(duration: tokio::time::Duration) -> Result<(), Box<dyn std::error::Error>> {
+    if requested() {
+        tokio::time::sleep(duration).await;
+        return Ok(());
+    }
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => Ok(()),
+        _ = wait() => Err("Interrupted".into()),
+    }
+}
+
+/// Tear down everything still recorded: instances first, then Elastic IPs, volumes, placement
+/// groups and VPCs. Every stage is attempted even if an earlier one fails; whatever is still
+/// recorded afterwards is printed, and the failures come back as one `CleanupReport` error.
+pub async fn cleanup_tracked(
+    aws_client: &aws_sdk_ec2::Client,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let remaining = tracked();
+
+    let instance_ids: Vec<String> = remaining
+        .iter()
+        .filter_map(|t| match t {
+            Tracked::Instance(id) => Some(id.clone()),
+            _ => None,
+        })
+        .collect();
+    let volume_ids: Vec<String> = remaining
+        .iter()
+        .filter_map(|t| match t {
+            Tracked::Volume(id) => Some(id.clone()),
+            _ => None,
+        })
+        .collect();
+    let elastic_ips: Vec<String> = remaining
+        .iter()
+        .filter_map(|t| match t {
+            Tracked::ElasticIp(id) => Some(id.clone()),
+            _ => None,
+        })
+        .collect();
+    let placement_groups: Vec<String> = remaining
+        .iter()
+        .filter_map(|t| match t {
+            Tracked::PlacementGroup(name) => Some(name.clone()),
+            _ => None,
+        })
+        .collect();
+    let vpcs: Vec<VpcCleanup> = remaining
+        .into_iter()
+        .filter_map(|t| match t {
+            Tracked::Vpc(vpc) => Some(vpc),
+            _ => None,
+        })
+        .collect();
+
+    let mut report = CleanupReport::default();
+    if let Err(e) = sdk_wrapper::cleanup_cluster(
+        aws_client,
+        sdk_wrapper::ClusterCleanup {
+            instance_ids,
+            volume_id: None,
+            elastic_ip: None,
+            placement_group: None,
+            instance_types: BTreeMap::new(),
+        },
+    )
+    .await
+    {
+        report.add_error("instances", e.as_ref());
+    }
+    for allocation_id in elastic_ips {
+        if let Err(e) = sdk_wrapper::release_elastic_ip(aws_client, &allocation_id).await {
+            report.add_error(&format!("Elastic IP {}", allocation_id), e.as_ref());
+        }
+    }
+    for volume_id in volume_ids {
+        if let Err(e) = sdk_wrapper::delete_volume(aws_client, &volume_id).await {
+            report.add_error(&format!("volume {}", volume_id), e.as_ref());
+        }
+    }
+    for name in placement_groups {
+        if let Err(e) = sdk_wrapper::cleanup_cluster(
+            aws_client,
+            sdk_wrapper::ClusterCleanup {
+                placement_group: Some(name.clone()),
+                ..Default::default()
+            },
+        )
+        .await
+        {
+            report.add_error(&format!("placement group {}", name), e.as_ref());
+        }
+    }
+    for vpc in vpcs {
+        let vpc_id = vpc.vpc_id().unwrap_or_default().to_string();
+        if let Err(e) = sdk_wrapper::cleanup_vpc(aws_client, vpc).await {
+            report.add_error(&format!("VPC {}", vpc_id), e.as_ref());
+        }
+    }
+
+    print_remaining(&tracked());
+    report.into_result().map(|_| ())
+}
+
+#[test]
+fn test_track_and_untrack() {
+    track(
+        "i-test-track",
+        Tracked::Instance("i-test-track".to_string()),
+    );
+    assert!(tracked()
+        .iter()
+        .any(|t| matches!(t, Tracked::Instance(id) if id == "i-test-track")));
+
+    untrack("i-test-track");
+    assert!(!tracked()
+        .iter()
+        .any(|t| matches!(t, Tracked::Instance(id) if id == "i-test-track")));
+}
+
+#[tokio::test]
+async fn test_sleep_without_interrupt() {
+    assert!(sleep(tokio::time::Duration::from_millis(1)).await.is_ok());
+}
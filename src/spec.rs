@@ -0,0 +1,610 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::job::JobSpec;
+use crate::sdk_wrapper::{
+    ClusterTemplate, InstanceTemplate, IpMode, LaunchPolicy, NodeGroup, NodeRole, PublicIp,
+};
+
+/// A cluster described in a TOML spec file (the `[cluster]` table).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterSpec {
+    pub name: String,
+    /// Value of the `project` tag put on every resource; defaults to the cluster name.
+    #[serde(default)]
+    pub project_tag: Option<String>,
+    /// Number of nodes; optional with node groups, where it has to match their total if given.
+    #[serde(default)]
+    pub num_instances: Option<u64>,
+    pub availability_zone: String,
+    pub ami_image_id: String,
+    /// Instance type of every node; not allowed with node groups, which each have their own.
+    #[serde(default)]
+    pub instance_type: Option<String>,
+    /// Instance types to fall back on, in order, when there is no capacity for `instance_type`.
+    #[serde(default)]
+    pub fallback_instance_types: Vec<String>,
+    #[serde(default = "default_num_ifaces")]
+    pub num_ifaces: u64,
+    /// Not supported yet; a spec setting it is rejected.
+    #[serde(default)]
+    pub use_efa: bool,
+    /// User-data templates, relative to the spec file (defaults to the built-in `user_data.sh`).
+    #[serde(default)]
+    pub user_data: Vec<PathBuf>,
+    /// Size in GiB of a volume shared between all nodes; no shared volume if unset.
+    #[serde(default)]
+    pub shared_ebs_volume_size: Option<u64>,
+    /// Minutes after creation at which the cluster's resources may be reaped; no expiry if unset.
+    #[serde(default)]
+    pub ttl_minutes: Option<u64>,
+    /// Also have each node shut itself down (and so terminate) once the TTL is up.
+    #[serde(default)]
+    pub shutdown_on_expiry: bool,
+    /// `ipv4`, `dual-stack` (the default) or `ipv6-only` (no public IPv4 addresses).
+    #[serde(default)]
+    pub ip_mode: IpMode,
+    /// `head` (the default), to give only the head node a public IPv4 address, or `all`. Workers
+    /// without one reach the internet over IPv6 only, so `head` can't be combined with `ipv4`.
+    #[serde(default)]
+    pub public_ip: PublicIp,
+    /// Give the head node an Elastic IP, which survives stop/start.
+    #[serde(default)]
+    pub elastic_ip: bool,
+    /// Groups of nodes with their own instance type, interfaces and user data (the
+    /// `[[cluster.node_groups]]` tables). The head node is the first node of the first group.
+    #[serde(default)]
+    pub node_groups: Vec<NodeGroupSpec>,
+    /// How many nodes have to launch for the cluster to be kept: `"all"` (the default, each node
+    /// launched on its own), `"atomic"` (every node in one request, all or none, with an Elastic IP
+    /// for a head node that is the only one with a public IPv4 address; with several node groups,
+    /// the head node on its own first and then each group's workers in one request),
+    /// `{ at-least = <n> }` or `"best-effort"` (as long as the head node launches).
+    #[serde(default)]
+    pub launch_policy: LaunchPolicy,
+    /// Launch the nodes into a cluster placement group, for low-latency networking between them.
+    #[serde(default)]
+    pub placement_group: bool,
+}
+
+/// A group of nodes of a cluster (a `[[cluster.node_groups]]` table).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeGroupSpec {
+    /// Put in the `role` tag of the group's nodes.
+    pub name: String,
+    pub count: u64,
+    pub instance_type: String,
+    /// Instance types to fall back on, in order, when there is no capacity for `instance_type`.
+    #[serde(default)]
+    pub fallback_instance_types: Vec<String>,
+    #[serde(default = "default_num_ifaces")]
+    pub num_ifaces: u64,
+    /// Not supported yet; a spec setting it is rejected.
+    #[serde(default)]
+    pub use_efa: bool,
+    /// User-data templates, relative to the spec file (defaults to the cluster's).
+    #[serde(default)]
+    pub user_data: Vec<PathBuf>,
+}
+
+fn default_num_ifaces() -> u64 {
+    1
+}
+
+impl ClusterSpec {
+    pub fn project_tag(&self) -> &str {
+        self.project_tag.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn ttl(&self) -> Option<tokio::time::Duration> {
+        self.ttl_minutes
+            .map(|minutes| tokio::time::Duration::from_secs(minutes * 60))
+    }
+
+    /// Whether the head node gets an Elastic IP, which the atomic policy can add on its own (see
+    /// `ClusterTemplate::head_elastic_ip`).
+    pub fn head_elastic_ip(&self) -> bool {
+        self.cluster_template("", "", None, &[]).head_elastic_ip()
+    }
+
+    /// Check what a spec can't express with types alone.
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.validate_nodes()?;
+        self.validate_launch_policy()?;
+        // Without a public IPv4 address or a NAT gateway, IPv6 is the workers' only way out
+        if self.public_ip == PublicIp::Head && !self.ip_mode.ipv6() {
+            return Err(
+                "public_ip = \"head\" needs ip_mode = \"dual-stack\" or \"ipv6-only\", or the \
+                 workers have no way out to the internet"
+                    .into(),
+            );
+        }
+        // Launching with EFAs isn't implemented, so catch it before anything is created
+        if self.use_efa || self.node_groups.iter().any(|g| g.use_efa) {
+            return Err("use_efa isn't supported yet".into());
+        }
+        Ok(())
+    }
+
+    /// Check that the nodes are given either by `num_instances` and `instance_type` or by node
+    /// groups.
+    fn validate_nodes(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.node_groups.is_empty() {
+            if self.num_instances.is_none() || self.instance_type.is_none() {
+                return Err(
+                    "A cluster needs num_instances and instance_type, or node groups".into(),
+                );
+            }
+            return Ok(());
+        }
+
+        if self.instance_type.is_some() || !self.fallback_instance_types.is_empty() {
+            return Err(
+                "instance_type can't be combined with node groups, give it per group".into(),
+            );
+        }
+        if let Some(group) = self.node_groups.iter().find(|g| g.count < 1) {
+            return Err(format!("Node group {} needs at least 1 node", group.name).into());
+        }
+        let total: u64 = self.node_groups.iter().map(|g| g.count).sum();
+        match self.num_instances {
+            Some(n) if n != total => Err(format!(
+                "num_instances is {}, but the node groups add up to {}",
+                n, total
+            )
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that the launch policy can be met by the cluster's nodes.
+    fn validate_launch_policy(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.launch_policy {
+            LaunchPolicy::AtLeast(min_nodes) if min_nodes < 1 || min_nodes > self.node_count() => {
+                Err(format!(
+                    "launch_policy needs at least {} node(s), but the cluster has {}",
+                    min_nodes,
+                    self.node_count()
+                )
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Number of nodes in the cluster.
+    pub fn node_count(&self) -> u64 {
+        if self.node_groups.is_empty() {
+            self.num_instances.unwrap_or(0)
+        } else {
+            self.node_groups.iter().map(|g| g.count).sum()
+        }
+    }
+
+    /// Instance type of node `node_index`, if the cluster has that node.
+    pub fn node_instance_type(&self, node_index: u64) -> Option<&str> {
+        if self.node_groups.is_empty() {
+            if node_index >= self.node_count() {
+                return None;
+            }
+            return self.instance_type.as_deref();
+        }
+        self.node_group_of(node_index)
+            .map(|group| group.instance_type.as_str())
+    }
+
+    /// Every instance type node `node_index` may have, in order of preference.
+    pub fn acceptable_instance_types(&self, node_index: u64) -> Vec<&str> {
+        let Some(preferred) = self.node_instance_type(node_index) else {
+            return Vec::new();
+        };
+        let fallbacks = match self.node_group_of(node_index) {
+            Some(group) => &group.fallback_instance_types,
+            None => &self.fallback_instance_types,
+        };
+        std::iter::once(preferred)
+            .chain(fallbacks.iter().map(String::as_str))
+            .collect()
+    }
+
+    fn node_group_of(&self, node_index: u64) -> Option<&NodeGroupSpec> {
+        let mut first = 0;
+        for group in &self.node_groups {
+            if node_index < first + group.count {
+                return Some(group);
+            }
+            first += group.count;
+        }
+        None
+    }
+
+    /// How many of the cluster's nodes may end up as each list of instance types (see
+    /// `acceptable_instance_types`), for quota checks and cost estimates.
+    pub fn instance_counts(&self) -> Vec<(Vec<String>, u64)> {
+        let node_indices: Vec<u64> = (0..self.node_count()).collect();
+        self.instance_counts_of(&node_indices)
+    }
+
+    /// Like `instance_counts`, for just the given nodes.
+    pub fn instance_counts_of(&self, node_indices: &[u64]) -> Vec<(Vec<String>, u64)> {
+        let mut counts: Vec<(Vec<String>, u64)> = Vec::new();
+        for &i in node_indices {
+            let instance_types = self.acceptable_instance_types(i);
+            match counts.iter_mut().find(|(t, _)| *t == instance_types) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((instance_types.iter().map(|t| t.to_string()).collect(), 1)),
+            }
+        }
+        counts
+    }
+
+    /// Load the user data of each node group that has its own, in group order.
+    pub fn load_group_user_data(&self) -> Result<Vec<Option<String>>, Box<dyn std::error::Error>> {
+        let mut loaded = Vec::new();
+        for group in &self.node_groups {
+            loaded.push(if group.user_data.is_empty() {
+                None
+            } else {
+                Some(crate::user_data::combine_parts(
+                    &crate::user_data::load_templates(&group.user_data)?,
+                ))
+            });
+        }
+        Ok(loaded)
+    }
+
+    /// Build the cluster template for this spec, using the subnet and security group of the VPC
+    /// created for it and the (unrendered) user data, plus that of the node groups which have their
+    /// own (see `load_group_user_data`).
+    pub fn cluster_template<'a>(
+        &'a self,
+        subnet_id: &'a str,
+        security_group_id: &'a str,
+        user_data: Option<&'a str>,
+        group_user_data: &'a [Option<String>],
+    ) -> ClusterTemplate<'a> {
+        let first_type = self.node_instance_type(0).unwrap_or_default();
+        ClusterTemplate {
+            cluster_name: &self.name,
+            num_instances: self.node_count(),
+            instance_template: InstanceTemplate {
+                availability_zone: &self.availability_zone,
+                ami_image_id: &self.ami_image_id,
+                instance_type: aws_sdk_ec2::types::InstanceType::from(first_type),
+                fallback_instance_types: self
+                    .fallback_instance_types
+                    .iter()
+                    .map(|t| aws_sdk_ec2::types::InstanceType::from(t.as_str()))
+                    .collect(),
+                subnet_id,
+                security_group_id,
+                num_ifaces: self.num_ifaces,
+                use_efa: self.use_efa,
+                user_data,
+                project_tag: self.project_tag(),
+                expires_at: None,
+                shutdown_on_expiry: self.shutdown_on_expiry,
+                ip_mode: self.ip_mode,
+                public_ip: self.public_ip,
+                role: NodeRole::Head,
+                node_group: None,
+                placement_group: None,
+            },
+            attach_shared_ebs: self.shared_ebs_volume_size.is_some(),
+            shared_ebs_volume_size: self.shared_ebs_volume_size,
+            project_tag: self.project_tag(),
+            ttl: self.ttl(),
+            shutdown_on_expiry: self.shutdown_on_expiry,
+            elastic_ip: self.elastic_ip,
+            node_groups: self
+                .node_groups
+                .iter()
+                .enumerate()
+                .map(|(i, group)| NodeGroup {
+                    name: &group.name,
+                    count: group.count,
+                    instance_type: aws_sdk_ec2::types::InstanceType::from(
+                        group.instance_type.as_str(),
+                    ),
+                    fallback_instance_types: group
+                        .fallback_instance_types
+                        .iter()
+                        .map(|t| aws_sdk_ec2::types::InstanceType::from(t.as_str()))
+                        .collect(),
+                    num_ifaces: group.num_ifaces,
+                    use_efa: group.use_efa,
+                    user_data: group_user_data.get(i).and_then(|u| u.as_deref()),
+                })
+                .collect(),
+            launch_policy: self.launch_policy,
+            placement_group: self.placement_group,
+        }
+    }
+}
+
+/// A spec file for `run`: a cluster plus the job to run on it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunSpec {
+    pub cluster: ClusterSpec,
+    pub job: JobSpec,
+}
+
+/// A spec file for `plan` and `apply`, which only look at the cluster (a `[job]` table is ignored).
+#[derive(Debug, Clone, Deserialize)]
+struct ClusterFile {
+    cluster: ClusterSpec,
+}
+
+/// Resolve relative user-data paths against `base_dir`.
+fn resolve_user_data(cluster: &mut ClusterSpec, base_dir: &Path) {
+    let group_paths = cluster
+        .node_groups
+        .iter_mut()
+        .flat_map(|g| g.user_data.iter_mut());
+    for path in cluster.user_data.iter_mut().chain(group_paths) {
+        if path.is_relative() {
+            *path = base_dir.join(&path);
+        }
+    }
+}
+
+/// Parse a spec from TOML text. Relative user-data paths are resolved against `base_dir`.
+pub fn parse_run_spec(text: &str, base_dir: &Path) -> Result<RunSpec, Box<dyn std::error::Error>> {
+    let mut spec: RunSpec = toml::from_str(text)?;
+    spec.cluster.validate()?;
+    resolve_user_data(&mut spec.cluster, base_dir);
+    Ok(spec)
+}
+
+/// Parse just the cluster from a spec's TOML text, resolving user-data paths like `parse_run_spec`.
+pub fn parse_cluster_spec(
+    text: &str,
+    base_dir: &Path,
+) -> Result<ClusterSpec, Box<dyn std::error::Error>> {
+    let mut file: ClusterFile = toml::from_str(text)?;
+    file.cluster.validate()?;
+    resolve_user_data(&mut file.cluster, base_dir);
+    Ok(file.cluster)
+}
+
+fn read_spec(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(text),
+        Err(err) => Err(format!("Failed to read spec file {:?}: {}", path, err).into()),
+    }
+}
+
+/// Load a spec file for `run`.
+pub fn load_run_spec(path: &Path) -> Result<RunSpec, Box<dyn std::error::Error>> {
+    parse_run_spec(&read_spec(path)?, path.parent().unwrap_or(Path::new(".")))
+}
+
+/// Load the cluster from a spec file, for `plan` and `apply`.
+pub fn load_cluster_spec(path: &Path) -> Result<ClusterSpec, Box<dyn std::error::Error>> {
+    parse_cluster_spec(&read_spec(path)?, path.parent().unwrap_or(Path::new(".")))
+}
+
+#[test]
+fn test_parse_run_spec() {
+    let text = r#"
+        [cluster]
+        name = "nccl-allreduce"
+        num_instances = 2
+        availability_zone = "us-west-2a"
+        ami_image_id = "ami-0c57248507328e2de"
+        instance_type = "g5.2xlarge"
+        user_data = ["user_data.sh"]
+
+        [job]
+        command = "./run_allreduce.sh"
+        results = ["~/results"]
+    "#;
+    let spec = parse_run_spec(text, Path::new("/specs")).unwrap();
+
+    assert_eq!(spec.cluster.project_tag(), "nccl-allreduce");
+    assert_eq!(spec.cluster.num_ifaces, 1);
+    assert_eq!(
+        spec.cluster.user_data,
+        vec![PathBuf::from("/specs/user_data.sh")]
+    );
+
+    let template = spec.cluster.cluster_template("subnet-1", "sg-1", None, &[]);
+    assert_eq!(
+        template.instance_template.instance_type.as_str(),
+        "g5.2xlarge"
+    );
+    assert!(!template.attach_shared_ebs);
+    assert_eq!(template.ttl, None);
+    assert_eq!(template.instance_template.ip_mode, IpMode::DualStack);
+    assert_eq!(template.instance_template.public_ip, PublicIp::Head);
+}
+
+#[test]
+fn test_parse_run_spec_rejects_unknown_fields() {
+    let text = r#"
+        [cluster]
+        name = "x"
+        num_instance = 2
+    "#;
+
+    assert!(parse_run_spec(text, Path::new(".")).is_err());
+}
+
+#[test]
+fn test_parse_head_only_public_ip() {
+    let text = r#"
+        [cluster]
+        name = "x"
+        num_instances = 4
+        availability_zone = "us-west-2a"
+        ami_image_id = "ami-0c57248507328e2de"
+        instance_type = "g5.2xlarge"
+        public_ip = "head"
+        elastic_ip = true
+
+        [job]
+        command = "hostname"
+    "#;
+    let spec = parse_run_spec(text, Path::new(".")).unwrap();
+    let template = spec.cluster.cluster_template("subnet-1", "sg-1", None, &[]);
+
+    assert!(template.elastic_ip);
+    let head = template.instance_template.clone();
+    assert!(head.has_public_ipv4());
+    let worker = InstanceTemplate {
+        role: NodeRole::Worker,
+        ..head
+    };
+    assert!(!worker.has_public_ipv4());
+
+    let ipv4_only = text.replace("elastic_ip = true", "ip_mode = \"ipv4\"");
+    assert!(parse_run_spec(&ipv4_only, Path::new(".")).is_err());
+    let all_public = ipv4_only.replace("public_ip = \"head\"", "public_ip = \"all\"");
+    assert!(parse_run_spec(&all_public, Path::new(".")).is_ok());
+}
+
+#[test]
+fn test_parse_node_groups() {
+    let text = r#"
+        [cluster]
+        name = "mixed"
+        availability_zone = "us-west-2a"
+        ami_image_id = "ami-0c57248507328e2de"
+        user_data = ["user_data.sh"]
+
+        [[cluster.node_groups]]
+        name = "head"
+        count = 1
+        instance_type = "c6i.xlarge"
+
+        [[cluster.node_groups]]
+        name = "gpu"
+        count = 8
+        instance_type = "p4d.24xlarge"
+        fallback_instance_types = ["p5.48xlarge"]
+        num_ifaces = 4
+        user_data = ["gpu.sh"]
+
+        [job]
+        command = "hostname"
+    "#;
+    let spec = parse_run_spec(text, Path::new("/specs")).unwrap();
+    let cluster = &spec.cluster;
+
+    assert_eq!(cluster.node_count(), 9);
+    assert_eq!(cluster.node_instance_type(0), Some("c6i.xlarge"));
+    assert_eq!(cluster.node_instance_type(8), Some("p4d.24xlarge"));
+    assert_eq!(cluster.node_instance_type(9), None);
+    assert_eq!(cluster.acceptable_instance_types(0), vec!["c6i.xlarge"]);
+    assert_eq!(
+        cluster.acceptable_instance_types(8),
+        vec!["p4d.24xlarge", "p5.48xlarge"]
+    );
+    assert_eq!(
+        cluster.instance_counts(),
+        vec![
+            (vec!["c6i.xlarge".to_string()], 1),
+            (
+                vec!["p4d.24xlarge".to_string(), "p5.48xlarge".to_string()],
+                8
+            )
+        ]
+    );
+    assert_eq!(
+        cluster.instance_counts_of(&[0, 3]),
+        vec![
+            (vec!["c6i.xlarge".to_string()], 1),
+            (
+                vec!["p4d.24xlarge".to_string(), "p5.48xlarge".to_string()],
+                1
+            )
+        ]
+    );
+    assert_eq!(
+        cluster.node_groups[1].user_data,
+        vec![PathBuf::from("/specs/gpu.sh")]
+    );
+
+    let group_user_data = vec![None, Some("#!/bin/bash\necho gpu".to_string())];
+    let template =
+        cluster.cluster_template("subnet-1", "sg-1", Some("#!/bin/bash"), &group_user_data);
+    assert_eq!(template.num_instances, 9);
+    let head = template.node_template(0);
+    assert_eq!(head.instance_type.as_str(), "c6i.xlarge");
+    assert_eq!((head.role, head.role_tag()), (NodeRole::Head, "head"));
+    assert_eq!(head.user_data, Some("#!/bin/bash"));
+    let worker = template.node_template(5);
+    assert_eq!(worker.instance_type.as_str(), "p4d.24xlarge");
+    assert_eq!(
+        worker.instance_types(),
+        vec![
+            &aws_sdk_ec2::types::InstanceType::from("p4d.24xlarge"),
+            &aws_sdk_ec2::types::InstanceType::from("p5.48xlarge")
+        ]
+    );
+    assert_eq!((worker.role, worker.role_tag()), (NodeRole::Worker, "gpu"));
+    assert_eq!(worker.num_ifaces, 4);
+    assert_eq!(worker.user_data, Some("#!/bin/bash\necho gpu"));
+
+    let both = text.replace(
+        "availability_zone",
+        "instance_type = \"g5.2xlarge\"\n        availability_zone",
+    );
+    assert!(parse_run_spec(&both, Path::new(".")).is_err());
+    let efa = text.replace("num_ifaces = 4", "num_ifaces = 4\n        use_efa = true");
+    assert!(parse_run_spec(&efa, Path::new(".")).is_err());
+    let wrong_total = text.replace(
+        "availability_zone",
+        "num_instances = 4\n        availability_zone",
+    );
+    assert!(parse_run_spec(&wrong_total, Path::new(".")).is_err());
+}
+
+#[test]
+fn test_parse_launch_policy() {
+    let spec = |policy: &str| {
+        parse_cluster_spec(
+            &format!(
+                r#"
+                [cluster]
+                name = "x"
+                num_instances = 8
+                availability_zone = "us-west-2a"
+                ami_image_id = "ami-0c57248507328e2de"
+                instance_type = "p4d.24xlarge"
+                placement_group = true
+                {}
+                "#,
+                policy
+            ),
+            Path::new("."),
+        )
+    };
+
+    assert_eq!(spec("").unwrap().launch_policy, LaunchPolicy::All);
+    let atomic = spec("launch_policy = \"atomic\"").unwrap();
+    assert_eq!(atomic.launch_policy, LaunchPolicy::Atomic);
+    let template = atomic.cluster_template("subnet-1", "sg-1", None, &[]);
+    assert!(template.placement_group);
+    assert!(template.launches_in_one_request());
+    assert!(atomic.head_elastic_ip());
+    assert!(!spec("").unwrap().head_elastic_ip());
+    assert_eq!(
+        spec("launch_policy = { at-least = 6 }")
+            .unwrap()
+            .launch_policy,
+        LaunchPolicy::AtLeast(6)
+    );
+    assert_eq!(
+        spec("launch_policy = \"best-effort\"")
+            .unwrap()
+            .launch_policy,
+        LaunchPolicy::BestEffort
+    );
+    assert!(spec("launch_policy = { at-least = 9 }").is_err());
+    assert!(spec("launch_policy = \"most\"").is_err());
+}
@@ -0,0 +1,271 @@
+use aws_sdk_ec2::Client;
+use serde::Serialize;
+use std::path::Path;
+use tracing::warn;
+
+use crate::dashboard::{self, NodeStatus};
+use crate::output::SCHEMA_VERSION;
+use crate::plan;
+use crate::pricing::{self, PriceTable};
+
+/// One node of a cluster in the output of `status` and `describe`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NodeView {
+    /// SSH alias of the node in the generated files, e.g. `node0`.
+    pub node: String,
+    pub instance_id: String,
+    pub instance_type: String,
+    pub state: String,
+    pub availability_zone: String,
+    pub private_ip: Option<String>,
+    pub public_ip: Option<String>,
+    pub ipv6: Option<String>,
+    pub status_checks: String,
+    pub uptime_hours: Option<f64>,
+    pub hourly_cost: Option<f64>,
+    pub cost_so_far: Option<f64>,
+}
+
+impl NodeView {
+    fn new(position: usize, node: &NodeStatus) -> Self {
+        NodeView {
+            node: format!("node{}", position),
+            instance_id: node.instance_id.clone(),
+            instance_type: node.instance_type.clone(),
+            state: node.state.clone(),
+            availability_zone: node.availability_zone.clone(),
+            private_ip: node.private_ip.clone(),
+            public_ip: node.public_ip.clone(),
+            ipv6: node.ipv6.clone(),
+            status_checks: node.checks.clone(),
+            uptime_hours: node.uptime_hours,
+            hourly_cost: node.hourly,
+            cost_so_far: node.cost(),
+        }
+    }
+}
+
+/// Output of `status`: the nodes of a cluster.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClusterStatus {
+    pub schema_version: u32,
+    pub cluster: String,
+    pub nodes: Vec<NodeView>,
+    /// On-demand cost per hour of the running nodes.
+    pub hourly_cost: f64,
+    pub cost_so_far: f64,
+    #[serde(skip)]
+    described: Vec<NodeStatus>,
+}
+
+impl ClusterStatus {
+    pub fn new(cluster: &str, nodes: Vec<NodeStatus>) -> Self {
+        ClusterStatus {
+            schema_version: SCHEMA_VERSION,
+            cluster: cluster.to_string(),
+            nodes: nodes
+                .iter()
+                .enumerate()
+                .map(|(i, node)| NodeView::new(i, node))
+                .collect(),
+            hourly_cost: nodes
+                .iter()
+                .filter(|n| n.state == "running")
+                .filter_map(|n| n.hourly)
+                .sum(),
+            cost_so_far: nodes.iter().filter_map(NodeStatus::cost).sum(),
+            described: nodes,
+        }
+    }
+
+    pub fn text(&self) -> Vec<String> {
+        dashboard::render_lines(&self.cluster, &self.described)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SubnetView {
+    pub subnet_id: String,
+    pub availability_zone: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VpcView {
+    pub vpc_id: String,
+    pub subnets: Vec<SubnetView>,
+    pub security_group_id: Option<String>,
+    /// Ingress rules of the security group, e.g. `tcp 22 from 0.0.0.0/0`.
+    pub ingress: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct VolumeView {
+    pub volume_id: String,
+    pub size_gib: u64,
+    pub availability_zone: String,
+}
+
+/// Output of `describe`: the nodes of a cluster plus its networking, volumes and recorded state.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClusterDescription {
+    #[serde(flatten)]
+    pub status: ClusterStatus,
+    pub vpcs: Vec<VpcView>,
+    pub volumes: Vec<VolumeView>,
+    /// Path of the state file written by `apply`, if there is one.
+    pub state_file: Option<String>,
+}
+
+impl ClusterDescription {
+    pub fn text(&self) -> Vec<String> {
+        let mut lines = self.status.text();
+        for vpc in &self.vpcs {
+            lines.push(String::new());
+            lines.push(format!("VPC {}", vpc.vpc_id));
+            for subnet in &vpc.subnets {
+                lines.push(format!(
+                    "  subnet {} in {}",
+                    subnet.subnet_id, subnet.availability_zone
+                ));
+            }
+            if let Some(group_id) = &vpc.security_group_id {
+                lines.push(format!("  security group {}", group_id));
+            }
+            for rule in &vpc.ingress {
+                lines.push(format!("    allow {}", rule));
+            }
+        }
+        if !self.volumes.is_empty() {
+            lines.push(String::new());
+        }
+        for volume in &self.volumes {
+            lines.push(format!(
+                "Volume {}: {} GiB in {}",
+                volume.volume_id, volume.size_gib, volume.availability_zone
+            ));
+        }
+        if let Some(state_file) = &self.state_file {
+            lines.push(String::new());
+            lines.push(format!("State recorded in {}", state_file));
+        }
+        lines
+    }
+}
+
+/// Load the prices of the nodes' instance types in the client's region, or `None` (with a warning)
+/// if they can't be had, so costs are left out rather than failing the whole command.
+pub async fn try_load_prices(aws_client: &Client, nodes: &[NodeStatus]) -> Option<PriceTable> {
+    let types: Vec<&str> = nodes.iter().map(|n| n.instance_type.as_str()).collect();
+    let loaded = match pricing::client_region(aws_client) {
+        Ok(region) => pricing::load_price_table(&region, &types).await,
+        Err(e) => Err(e),
+    };
+    match loaded {
+        Ok(table) => Some(table),
+        Err(e) => {
+            warn!("Could not load prices, costs won't be shown: {}", e);
+            None
+        }
+    }
+}
+
+/// Look up the nodes of a cluster, for `status`.
+pub async fn cluster_status(
+    aws_client: &Client,
+    project_tag: &str,
+) -> Result<ClusterStatus, Box<dyn std::error::Error>> {
+    let mut nodes = dashboard::describe_node_status(aws_client, project_tag).await?;
+    let prices = try_load_prices(aws_client, &nodes).await;
+    dashboard::set_prices(&mut nodes, prices.as_ref());
+    Ok(ClusterStatus::new(project_tag, nodes))
+}
+
+/// Look up everything that exists for a cluster, for `describe`.
+pub async fn describe_cluster(
+    aws_client: &Client,
+    project_tag: &str,
+    out_dir: &Path,
+) -> Result<ClusterDescription, Box<dyn std::error::Error>> {
+    let status = cluster_status(aws_client, project_tag).await?;
+    let live = plan::live_state(aws_client, project_tag).await?;
+    let state_file = plan::state_path(out_dir, project_tag);
+
+    Ok(ClusterDescription {
+        status,
+        vpcs: live
+            .vpcs
+            .into_iter()
+            .map(|vpc| VpcView {
+                vpc_id: vpc.vpc_id,
+                subnets: vpc
+                    .subnets
+                    .into_iter()
+                    .map(|(subnet_id, availability_zone)| SubnetView {
+                        subnet_id,
+                        availability_zone,
+                    })
+                    .collect(),
+                security_group_id: vpc.security_group.as_ref().map(|(id, _)| id.clone()),
+                ingress: vpc
+                    .security_group
+                    .iter()
+                    .flat_map(|(_, rules)| rules.iter().map(|r| r.describe()))
+                    .collect(),
+            })
+            .collect(),
+        volumes: live
+            .volumes
+            .into_iter()
+            .map(|volume| VolumeView {
+                volume_id: volume.volume_id,
+                size_gib: volume.size_gib,
+                availability_zone: volume.availability_zone,
+            })
+            .collect(),
+        state_file: state_file
+            .exists()
+            .then(|| state_file.display().to_string()),
+    })
+}
+
+#[test]
+fn test_cluster_status_schema() {
+    let status = ClusterStatus::new(
+        "test",
+        vec![NodeStatus {
+            index: Some(0),
+            instance_id: "i-0".to_string(),
+            instance_type: "g5.2xlarge".to_string(),
+            state: "running".to_string(),
+            availability_zone: "us-west-2a".to_string(),
+            private_ip: Some("10.0.0.4".to_string()),
+            public_ip: None,
+            ipv6: None,
+            checks: "ok/ok".to_string(),
+            uptime_hours: Some(2.0),
+            hourly: Some(1.5),
+        }],
+    );
+    let json = serde_json::to_value(&status).unwrap();
+
+    assert_eq!(json["schema_version"], SCHEMA_VERSION);
+    assert_eq!(json["cluster"], "test");
+    assert_eq!(json["cost_so_far"], 3.0);
+    assert_eq!(
+        json["nodes"][0],
+        serde_json::json!({
+            "node": "node0",
+            "instance_id": "i-0",
+            "instance_type": "g5.2xlarge",
+            "state": "running",
+            "availability_zone": "us-west-2a",
+            "private_ip": "10.0.0.4",
+            "public_ip": null,
+            "ipv6": null,
+            "status_checks": "ok/ok",
+            "uptime_hours": 2.0,
+            "hourly_cost": 1.5,
+            "cost_so_far": 3.0,
+        })
+    );
+}
@@ -0,0 +1,449 @@
+use aws_sdk_ec2::types::{Filter, InstanceStateName, PlacementGroupState, VolumeState};
+use aws_sdk_ec2::Client;
+use futures::future::BoxFuture;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::BTreeSet;
+use tracing::{debug, warn};
+
+use crate::dry_run;
+use crate::logging::CLEANUP_TARGET;
+use crate::sdk_wrapper::CleanupReport;
+
+/// How many deletions of one kind run at once.
+pub const PARALLEL_DELETES: usize = 8;
+
+/// Kinds of resources a teardown deletes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    Instances,
+    NatGateways,
+    VpcEndpoints,
+    ElasticIps,
+    NetworkInterfaces,
+    InternetGateway,
+    RouteTableAssociations,
+    Subnets,
+    RouteTables,
+    SecurityGroups,
+    Volumes,
+    PlacementGroups,
+    Vpc,
+}
+
+impl Kind {
+    /// The kinds that have to be gone before this one can be deleted.
+    pub fn depends_on(self) -> &'static [Kind] {
+        use Kind::*;
+        match self {
+            Instances | NatGateways | VpcEndpoints | RouteTableAssociations => &[],
+            // Their addresses are only disassociated once what they were on is gone
+            ElasticIps => &[Instances, NatGateways],
+            NetworkInterfaces => &[Instances, NatGateways, VpcEndpoints],
+            // Detaching fails while public addresses are still mapped in the VPC
+            InternetGateway => &[Instances, NatGateways, ElasticIps],
+            Subnets => &[Instances, NatGateways, VpcEndpoints, NetworkInterfaces],
+            RouteTables => &[RouteTableAssociations],
+            SecurityGroups => &[Instances, VpcEndpoints, NetworkInterfaces],
+            Volumes | PlacementGroups => &[Instances],
+            Vpc => &[
+                Instances,
+                NatGateways,
+                VpcEndpoints,
+                NetworkInterfaces,
+                InternetGateway,
+                Subnets,
+                RouteTables,
+                SecurityGroups,
+            ],
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Instances => "instances",
+            Kind::NatGateways => "NAT gateways",
+            Kind::VpcEndpoints => "VPC endpoints",
+            Kind::ElasticIps => "Elastic IPs",
+            Kind::NetworkInterfaces => "network interfaces",
+            Kind::InternetGateway => "internet gateway",
+            Kind::RouteTableAssociations => "route table associations",
+            Kind::Subnets => "subnets",
+            Kind::RouteTables => "route tables",
+            Kind::SecurityGroups => "security groups",
+            Kind::Volumes => "volumes",
+            Kind::PlacementGroups => "placement groups",
+            Kind::Vpc => "VPC",
+        }
+    }
+}
+
+/// The deletions of one kind of resource in a teardown, as one future.
+pub type Step<'a> = (Kind, BoxFuture<'a, CleanupReport>);
+
+/// Run `delete` on each item, at most `PARALLEL_DELETES` at once, and merge what they report.
+pub async fn each<T, F, Fut>(items: Vec<T>, delete: F) -> CleanupReport
+where
+    F: Fn(T) -> Fut,
+    Fut: std::future::Future<Output = CleanupReport>,
+{
+    let reports: Vec<CleanupReport> = futures::stream::iter(items)
+        .map(delete)
+        .buffer_unordered(PARALLEL_DELETES)
+        .collect()
+        .await;
+    let mut report = CleanupReport::default();
+    for r in reports {
+        report.extend(r);
+    }
+    report
+}
+
+/// Run the steps of a teardown, each as soon as the steps it depends on are done and independent
+/// ones at the same time.
+///
+/// A step whose dependencies left something behind is skipped (and reported as failed), since it
+/// could only fail after waiting out its dependency violations.
+pub async fn run(steps: Vec<Step<'_>>) -> CleanupReport {
+    let mut report = CleanupReport::default();
+    let mut pending = steps;
+    let mut running_kinds: Vec<Kind> = Vec::new();
+    let mut failed_kinds: Vec<Kind> = Vec::new();
+    let mut running = FuturesUnordered::new();
+
+    loop {
+        // Start (or skip) everything that no longer waits on a pending or running step
+        let unfinished: Vec<Kind> = pending
+            .iter()
+            .map(|(kind, _)| *kind)
+            .chain(running_kinds.iter().copied())
+            .collect();
+        let (ready, waiting): (Vec<Step>, Vec<Step>) = pending
+            .into_iter()
+            .partition(|(kind, _)| !kind.depends_on().iter().any(|d| unfinished.contains(d)));
+        pending = waiting;
+        for (kind, step) in ready {
+            match kind.depends_on().iter().find(|d| failed_kinds.contains(d)) {
+                Some(dependency) => {
+                    report.failed.push((
+                        kind.name().to_string(),
+                        format!("skipped, since not all {} are gone", dependency.name()),
+                    ));
+                    failed_kinds.push(kind);
+                }
+                None => {
+                    running_kinds.push(kind);
+                    running.push(async move { (kind, step.await) });
+                }
+            }
+        }
+
+        match running.next().await {
+            Some((kind, step_report)) => {
+                if let Some(i) = running_kinds.iter().position(|k| *k == kind) {
+                    running_kinds.remove(i);
+                }
+                if !step_report.is_complete() {
+                    failed_kinds.push(kind);
+                }
+                report.extend(step_report);
+            }
+            // Skipping steps can free others up without anything running
+            None if !pending.is_empty() => continue,
+            None => return report,
+        }
+    }
+}
+
+/// What a teardown should have left gone, to check once it is done.
+#[derive(Debug, Clone, Default)]
+pub struct Inventory {
+    pub instance_ids: Vec<String>,
+    pub volume_ids: Vec<String>,
+    /// Elastic IPs, by allocation ID.
+    pub allocation_ids: Vec<String>,
+    pub vpc_ids: Vec<String>,
+    pub subnet_ids: Vec<String>,
+    pub internet_gateway_ids: Vec<String>,
+    pub route_table_ids: Vec<String>,
+    pub security_group_ids: Vec<String>,
+    /// Placement groups, by name.
+    pub placement_groups: Vec<String>,
+    /// Also look for anything still tagged `project=<tag>`.
+    pub project_tag: Option<String>,
+}
+
+/// How many times `verify` looks before it believes something survived, since EC2 can keep
+/// describing a resource for a few seconds after deleting it.
+const VERIFY_ATTEMPTS: u32 = 3;
+
+/// The filters to look a kind of resource up by: its IDs (under the filter `id_name`), and the
+/// project tag. Either can be left out.
+fn lookups(id_name: &str, ids: &[String], project_tag: Option<&str>) -> Vec<Filter> {
+    let mut filters = Vec::new();
+    if !ids.is_empty() {
+        filters.push(
+            Filter::builder()
+                .name(id_name)
+                .set_values(Some(ids.to_vec()))
+                .build(),
+        );
+    }
+    if let Some(tag) = project_tag {
+        filters.push(Filter::builder().name("tag:project").values(tag).build());
+    }
+    filters
+}
+
+/// Describe everything in the inventory again and list what is still there, e.g.
+/// `instance i-0a1b (running)`. Terminated instances, and volumes and placement groups being deleted,
+/// count as gone.
+async fn survivors(
+    aws_client: &Client,
+    inventory: &Inventory,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let tag = inventory.project_tag.as_deref();
+    let mut survivors = BTreeSet::new();
+
+    for filter in lookups("instance-id", &inventory.instance_ids, tag) {
+        let out = aws_client
+            .describe_instances()
+            .filters(filter)
+            .send()
+            .await?;
+        for instance in out.reservations().iter().flat_map(|r| r.instances()) {
+            let state = instance.state().and_then(|s| s.name());
+            if state != Some(&InstanceStateName::Terminated) {
+                survivors.insert(format!(
+                    "instance {} ({})",
+                    instance.instance_id().unwrap_or_default(),
+                    state.map_or("unknown", |s| s.as_str())
+                ));
+            }
+        }
+    }
+    for filter in lookups("volume-id", &inventory.volume_ids, tag) {
+        let out = aws_client.describe_volumes().filters(filter).send().await?;
+        for volume in out.volumes() {
+            let state = volume.state();
+            if !matches!(
+                state,
+                Some(VolumeState::Deleted) | Some(VolumeState::Deleting)
+            ) {
+                survivors.insert(format!(
+                    "volume {} ({})",
+                    volume.volume_id().unwrap_or_default(),
+                    state.map_or("unknown", |s| s.as_str())
+                ));
+            }
+        }
+    }
+    for filter in lookups("allocation-id", &inventory.allocation_ids, tag) {
+        let out = aws_client
+            .describe_addresses()
+            .filters(filter)
+            .send()
+            .await?;
+        for address in out.addresses() {
+            survivors.insert(format!(
+                "Elastic IP {} ({})",
+                address.allocation_id().unwrap_or_default(),
+                address.public_ip().unwrap_or_default()
+            ));
+        }
+    }
+    for filter in lookups("group-name", &inventory.placement_groups, tag) {
+        let out = aws_client
+            .describe_placement_groups()
+            .filters(filter)
+            .send()
+            .await?;
+        for group in out.placement_groups() {
+            let state = group.state();
+            if !matches!(
+                state,
+                Some(PlacementGroupState::Deleted) | Some(PlacementGroupState::Deleting)
+            ) {
+                survivors.insert(format!(
+                    "placement group {} ({})",
+                    group.group_name().unwrap_or_default(),
+                    state.map_or("unknown", |s| s.as_str())
+                ));
+            }
+        }
+    }
+    for filter in lookups("vpc-id", &inventory.vpc_ids, tag) {
+        let out = aws_client.describe_vpcs().filters(filter).send().await?;
+        for vpc in out.vpcs() {
+            survivors.insert(format!("VPC {}", vpc.vpc_id().unwrap_or_default()));
+        }
+    }
+    for filter in lookups("subnet-id", &inventory.subnet_ids, None) {
+        let out = aws_client.describe_subnets().filters(filter).send().await?;
+        for subnet in out.subnets() {
+            survivors.insert(format!("subnet {}", subnet.subnet_id().unwrap_or_default()));
+        }
+    }
+    for filter in lookups("internet-gateway-id", &inventory.internet_gateway_ids, None) {
+        let out = aws_client
+            .describe_internet_gateways()
+            .filters(filter)
+            .send()
+            .await?;
+        for igw in out.internet_gateways() {
+            survivors.insert(format!(
+                "internet gateway {}",
+                igw.internet_gateway_id().unwrap_or_default()
+            ));
+        }
+    }
+    for filter in lookups("route-table-id", &inventory.route_table_ids, None) {
+        let out = aws_client
+            .describe_route_tables()
+            .filters(filter)
+            .send()
+            .await?;
+        for rt in out.route_tables() {
+            survivors.insert(format!(
+                "route table {}",
+                rt.route_table_id().unwrap_or_default()
+            ));
+        }
+    }
+    for filter in lookups("group-id", &inventory.security_group_ids, None) {
+        let out = aws_client
+            .describe_security_groups()
+            .filters(filter)
+            .send()
+            .await?;
+        for sg in out.security_groups() {
+            survivors.insert(format!(
+                "security group {}",
+                sg.group_id().unwrap_or_default()
+            ));
+        }
+    }
+
+    Ok(survivors.into_iter().collect())
+}
+
+/// Check that what a teardown deleted is really gone, adding whatever is still there to the
+/// report's survivors (which makes it incomplete). Nothing is checked in a dry run.
+pub async fn verify(aws_client: &Client, inventory: &Inventory, report: &mut CleanupReport) {
+    if dry_run::enabled() {
+        return;
+    }
+    for attempt in 1..=VERIFY_ATTEMPTS {
+        match survivors(aws_client, inventory).await {
+            Ok(left) if left.is_empty() => return,
+            Ok(left) if attempt == VERIFY_ATTEMPTS => {
+                for survivor in &left {
+                    warn!(target: CLEANUP_TARGET, "Still there after teardown: {}", survivor);
+                }
+                report.survivors.extend(left);
+                return;
+            }
+            Ok(left) => debug!(
+                target: CLEANUP_TARGET,
+                "{} resource(s) still described after teardown, looking again",
+                left.len()
+            ),
+            Err(e) => {
+                warn!(target: CLEANUP_TARGET, "Failed to verify teardown: {}", e);
+                report
+                    .failed
+                    .push(("verification".to_string(), e.to_string()));
+                return;
+            }
+        }
+        tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+    }
+}
+
+#[test]
+fn test_run_respects_dependencies() {
+    use futures::FutureExt;
+    use std::sync::{Arc, Mutex};
+
+    use Kind::*;
+    let all = [
+        Instances,
+        NatGateways,
+        VpcEndpoints,
+        ElasticIps,
+        NetworkInterfaces,
+        InternetGateway,
+        RouteTableAssociations,
+        Subnets,
+        RouteTables,
+        SecurityGroups,
+        Volumes,
+        PlacementGroups,
+        Vpc,
+    ];
+    for kind in all {
+        assert!(!kind.depends_on().contains(&kind));
+        for dependency in kind.depends_on() {
+            assert!(!dependency.depends_on().contains(&kind));
+        }
+    }
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let step = |kind: Kind, fail: bool| -> Step<'static> {
+        let order = order.clone();
+        (
+            kind,
+            async move {
+                order.lock().unwrap().push(kind);
+                let mut report = CleanupReport::default();
+                if fail {
+                    report
+                        .failed
+                        .push((kind.name().to_string(), "failed".to_string()));
+                } else {
+                    report.deleted.push(kind.name().to_string());
+                }
+                report
+            }
+            .boxed(),
+        )
+    };
+    let report = futures::executor::block_on(run(vec![
+        step(Kind::Vpc, false),
+        step(Kind::Subnets, false),
+        step(Kind::Instances, false),
+        step(Kind::SecurityGroups, false),
+        step(Kind::Volumes, true),
+    ]));
+
+    let order = order.lock().unwrap();
+    let position = |kind| order.iter().position(|k| *k == kind).unwrap();
+    assert!(position(Kind::Instances) < position(Kind::Subnets));
+    assert!(position(Kind::Instances) < position(Kind::SecurityGroups));
+    assert!(position(Kind::Subnets) < position(Kind::Vpc));
+    assert!(position(Kind::SecurityGroups) < position(Kind::Vpc));
+    assert_eq!(report.deleted.len(), 4);
+    assert_eq!(report.failed.len(), 1);
+    drop(order);
+
+    // A failed instance teardown skips what depends on it
+    let report = futures::executor::block_on(run(vec![
+        step(Kind::Instances, true),
+        step(Kind::Subnets, false),
+        step(Kind::Vpc, false),
+        step(Kind::RouteTableAssociations, false),
+    ]));
+    assert_eq!(report.deleted, vec!["route table associations"]);
+    assert_eq!(report.failed.len(), 3);
+}
+
+#[test]
+fn test_lookups() {
+    assert!(lookups("vpc-id", &[], None).is_empty());
+
+    let filters = lookups("vpc-id", &["vpc-1".to_string()], Some("exp"));
+    assert_eq!(filters.len(), 2);
+    assert_eq!(filters[0].name(), Some("vpc-id"));
+    assert_eq!(filters[0].values(), ["vpc-1"]);
+    assert_eq!(filters[1].name(), Some("tag:project"));
+    assert_eq!(filters[1].values(), ["exp"]);
+}
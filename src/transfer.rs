@@ -0,0 +1,455 @@
+use futures::stream::{self, StreamExt};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cluster_files::ClusterNode;
+use crate::remote::{self, NodeResult};
+
+/// SHA-256 checksums keyed by path relative to the transferred root (`""` for a single file).
+pub type Checksums = BTreeMap<String, String>;
+
+/// Hash a single local file.
+fn sha256_file(path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let mut hasher = Sha256::new();
+    let mut file = fs::File::open(path)?;
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Recursively hash every regular file under `dir`, keyed by its path relative to `root`.
+fn sha256_dir(
+    root: &Path,
+    dir: &Path,
+    out: &mut Checksums,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            sha256_dir(root, &path, out)?;
+        } else if path.is_file() {
+            let rel = path.strip_prefix(root)?.to_string_lossy().into_owned();
+            out.insert(rel, sha256_file(&path)?);
+        }
+    }
+    Ok(())
+}
+
+/// Compute the checksums of a local file or directory.
+pub fn local_checksums(path: &Path) -> Result<Checksums, Box<dyn std::error::Error>> {
+    let mut out = Checksums::new();
+    if path.is_dir() {
+        sha256_dir(path, path, &mut out)?;
+    } else {
+        out.insert(String::new(), sha256_file(path)?);
+    }
+    Ok(out)
+}
+
+/// Remote shell command printing `sha256sum` output for a file, or for every file in a directory.
+fn remote_checksum_command(remote_path: &str) -> String {
+    let quoted = remote::shell_quote_path(remote_path);
+    format!(
+        "if [ -d {0} ]; then cd {0} && find . -type f -exec sha256sum {{}} +; else sha256sum {0}; fi",
+        quoted
+    )
+}
+
+/// Parse `sha256sum` output into checksums.
+///
+/// For directories the `./` prefix that `find` adds is stripped; for a single file the path is
+/// replaced by `""` so it lines up with `local_checksums`.
+pub fn parse_sha256sum(output: &str, is_dir: bool) -> Checksums {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (hash, path) = line.split_once(char::is_whitespace)?;
+            let path = path.trim_start().trim_start_matches('*');
+            let key = if is_dir {
+                path.trim_start_matches("./").to_string()
+            } else {
+                String::new()
+            };
+            Some((key, hash.to_string()))
+        })
+        .collect()
+}
+
+/// Describe how two sets of checksums differ, or `None` if they match.
+pub fn compare_checksums(expected: &Checksums, actual: &Checksums) -> Option<String> {
+    let missing: Vec<&String> = expected
+        .keys()
+        .filter(|k| !actual.contains_key(*k))
+        .collect();
+    let mismatched: Vec<&String> = expected
+        .iter()
+        .filter(|(k, v)| actual.get(*k).is_some_and(|a| a != *v))
+        .map(|(k, _)| k)
+        .collect();
+
+    if missing.is_empty() && mismatched.is_empty() {
+        return None;
+    }
+
+    let name = |k: &&String| {
+        if k.is_empty() {
+            "file".to_string()
+        } else {
+            k.to_string()
+        }
+    };
+    let mut problems = Vec::new();
+    if !missing.is_empty() {
+        problems.push(format!(
+            "missing: {}",
+            missing.iter().map(name).collect::<Vec<_>>().join(", ")
+        ));
+    }
+    if !mismatched.is_empty() {
+        problems.push(format!(
+            "checksum mismatch: {}",
+            mismatched.iter().map(name).collect::<Vec<_>>().join(", ")
+        ));
+    }
+    Some(problems.join("; "))
+}
+
+/// Whether replacing `remote_path` would wipe out a home or root directory.
+fn is_protected_path(remote_path: &str) -> bool {
+    let trimmed = remote_path.trim_end_matches('/');
+    matches!(trimmed, "" | "~" | "." | "..") || trimmed.split('/').all(|c| c.is_empty() || c == ".")
+}
+
+/// Where a push of `local` to `remote_path` ends up: like `cp -r` (and `scp -r`), inside
+/// `remote_path` if that is an existing directory, otherwise at `remote_path` itself.
+pub fn push_target(remote_path: &str, local: &Path, remote_is_dir: bool) -> String {
+    match local.file_name() {
+        Some(name) if remote_is_dir => format!(
+            "{}/{}",
+            remote_path.trim_end_matches('/'),
+            name.to_string_lossy()
+        ),
+        _ => remote_path.to_string(),
+    }
+}
+
+/// Work out where a push lands on a node (see `push_target`). With `replace`, whatever is at
+/// `remote_path` is deleted first instead, so the push takes its place.
+async fn prepare_push_target(
+    ssh_config: &Path,
+    alias: &str,
+    remote_path: &str,
+    local: &Path,
+    replace: bool,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let quoted = remote::shell_quote_path(remote_path);
+    let command = if replace {
+        vec![format!("rm -rf {}", quoted)]
+    } else {
+        vec![format!("test -d {}", quoted)]
+    };
+    let (code, _) =
+        remote::run_captured(alias, "ssh", &remote::ssh_args(ssh_config, alias, &command)).await?;
+    match (replace, code) {
+        (true, Some(0)) => Ok(remote_path.to_string()),
+        (false, Some(0)) => Ok(push_target(remote_path, local, true)),
+        (false, Some(1)) => Ok(push_target(remote_path, local, false)),
+        (true, code) => Err(format!("could not remove {} (exit {:?})", remote_path, code).into()),
+        (false, code) => Err(format!("could not look at {} (exit {:?})", remote_path, code).into()),
+    }
+}
+
+/// The result for a node that was given up on before anything was copied to it.
+fn not_copied(node: &ClusterNode, error: String) -> NodeResult {
+    NodeResult {
+        index: node.index,
+        node: node.alias(),
+        exit_code: None,
+        error: Some(error),
+    }
+}
+
+/// Fetch the checksums of a path on a node.
+async fn remote_checksums(
+    ssh_config: &Path,
+    alias: &str,
+    remote_path: &str,
+    is_dir: bool,
+) -> Result<Checksums, Box<dyn std::error::Error>> {
+    let command = vec![remote_checksum_command(remote_path)];
+    let (code, stdout) =
+        remote::run_captured(alias, "ssh", &remote::ssh_args(ssh_config, alias, &command)).await?;
+    if code != Some(0) {
+        return Err(format!("could not checksum {} (exit {:?})", remote_path, code).into());
+    }
+    Ok(parse_sha256sum(&stdout, is_dir))
+}
+
+/// Build a `NodeResult` from a copy exit code and (if the copy worked) a checksum comparison.
+async fn verify(
+    node: &ClusterNode,
+    copy_exit: Option<i32>,
+    expected: impl std::future::Future<Output = Result<Checksums, Box<dyn std::error::Error>>>,
+    actual: impl std::future::Future<Output = Result<Checksums, Box<dyn std::error::Error>>>,
+) -> NodeResult {
+    let mut result = NodeResult {
+        index: node.index,
+        node: node.alias(),
+        exit_code: copy_exit,
+        error: None,
+    };
+    if copy_exit != Some(0) {
+        return result;
+    }
+
+    result.error = match (expected.await, actual.await) {
+        (Ok(expected), Ok(actual)) => compare_checksums(&expected, &actual),
+        (Err(e), _) | (_, Err(e)) => Some(e.to_string()),
+    };
+    result
+}
+
+/// `scp` arguments common to every copy.
+fn scp_args(ssh_config: &Path) -> Vec<String> {
+    vec![
+        "-F".to_string(),
+        ssh_config.display().to_string(),
+        "-o".to_string(),
+        "BatchMode=yes".to_string(),
+        "-r".to_string(),
+        "-q".to_string(),
+    ]
+}
+
+/// Copy a local file or directory to the same path on every given node, verifying checksums.
+///
+/// Like `cp -r`, if `remote_path` is an existing directory on a node the copy goes inside it. With
+/// `replace`, whatever is at `remote_path` is deleted first instead (home and root directories
+/// excepted), so that a directory pushed again replaces its earlier copy.
+///
+/// With `fan_out`, the data is uploaded once to `node0` and then copied from there to every other
+/// node over the cluster network using SSH agent forwarding (so your key must be loaded in
+/// `ssh-agent`). This keeps large clusters from each pulling the full upload over your uplink.
+///
+/// # Returns
+/// * One result per node, in node order.
+pub async fn push(
+    ssh_config: &Path,
+    nodes: &[ClusterNode],
+    local: &Path,
+    remote_path: &str,
+    parallel: usize,
+    fan_out: bool,
+    replace: bool,
+) -> Result<Vec<NodeResult>, Box<dyn std::error::Error>> {
+    let expected = local_checksums(local)?;
+    let is_dir = local.is_dir();
+    if replace && is_protected_path(remote_path) {
+        return Err(format!(
+            "Refusing to replace {}, push to a subdirectory instead",
+            remote_path
+        )
+        .into());
+    }
+    let local_str = local.display().to_string();
+
+    // With fan-out, the head node is the only one we upload to directly
+    let head = nodes.iter().find(|n| n.index == 0);
+    let (direct, relayed): (Vec<&ClusterNode>, Vec<&ClusterNode>) = match (fan_out, head) {
+        (true, Some(_)) => nodes.iter().partition(|n| n.index == 0),
+        (true, None) => return Err("Fan-out needs node 0 to be part of the selection".into()),
+        (false, _) => (nodes.iter().collect(), Vec::new()),
+    };
+
+    let direct_results: Vec<(NodeResult, String)> = stream::iter(direct.into_iter().map(|node| {
+        let expected = expected.clone();
+        let local_str = local_str.clone();
+        async move {
+            let alias = node.alias();
+            let target =
+                match prepare_push_target(ssh_config, &alias, remote_path, local, replace).await {
+                    Ok(target) => target,
+                    Err(e) => return (not_copied(node, e.to_string()), String::new()),
+                };
+            let mut args = scp_args(ssh_config);
+            args.push(local_str);
+            args.push(format!("{}:{}", alias, target));
+            let code = remote::run_prefixed(&alias, "scp", &args)
+                .await
+                .unwrap_or(None);
+
+            let result = verify(
+                node,
+                code,
+                async { Ok(expected) },
+                remote_checksums(ssh_config, &alias, &target, is_dir),
+            )
+            .await;
+            (result, target)
+        }
+    }))
+    .buffer_unordered(parallel.max(1))
+    .collect()
+    .await;
+
+    // Relay from the head node to the rest, but only if the head got a good copy
+    let head_target = direct_results
+        .iter()
+        .find(|(r, _)| r.index == 0 && r.success())
+        .map(|(_, target)| target.clone());
+    let mut results: Vec<NodeResult> = direct_results.into_iter().map(|(r, _)| r).collect();
+    let relay_results: Vec<NodeResult> = stream::iter(relayed.into_iter().map(|node| {
+        let expected = expected.clone();
+        let head_target = head_target.clone();
+        async move {
+            let Some(head_target) = head_target else {
+                return not_copied(node, "skipped, upload to node0 failed".to_string());
+            };
+            let alias = node.alias();
+            let target =
+                match prepare_push_target(ssh_config, &alias, remote_path, local, replace).await {
+                    Ok(target) => target,
+                    Err(e) => return not_copied(node, e.to_string()),
+                };
+
+            // The source is expanded by node0's shell, the destination by `scp` on the far side
+            let relay = format!(
+                "scp -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null -o LogLevel=ERROR -r -q {} {}:{}",
+                remote::shell_quote_path(&head_target),
+                node.private_ip,
+                remote::shell_quote(&target)
+            );
+            let mut args = vec!["-A".to_string()];
+            args.extend(remote::ssh_args(ssh_config, "node0", &[relay]));
+            let code = remote::run_prefixed(&alias, "ssh", &args).await.unwrap_or(None);
+
+            verify(node, code, async { Ok(expected) }, remote_checksums(ssh_config, &alias, &target, is_dir)).await
+        }
+    }))
+    .buffer_unordered(parallel.max(1))
+    .collect()
+    .await;
+    results.extend(relay_results);
+
+    results.sort_by_key(|r| r.index);
+    Ok(results)
+}
+
+/// Where `pull` puts the copy of `remote_path` from a given node.
+pub fn pull_destination(local_dir: &Path, node: &ClusterNode, remote_path: &str) -> PathBuf {
+    let name = Path::new(remote_path.trim_end_matches('/'))
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_else(|| "root".into());
+    local_dir.join(node.alias()).join(name)
+}
+
+/// Copy a file or directory from every given node into `<local_dir>/<nodeN>/`, verifying checksums.
+///
+/// # Returns
+/// * One result per node, in node order.
+pub async fn pull(
+    ssh_config: &Path,
+    nodes: &[ClusterNode],
+    remote_path: &str,
+    local_dir: &Path,
+    parallel: usize,
+) -> Result<Vec<NodeResult>, Box<dyn std::error::Error>> {
+    let mut results: Vec<NodeResult> = stream::iter(nodes.iter().map(|node| async move {
+        let alias = node.alias();
+        let dest = pull_destination(local_dir, node, remote_path);
+        // Like on push, `scp -r` would nest a directory inside an earlier copy of it
+        let cleared = if dest.is_dir() {
+            fs::remove_dir_all(&dest)
+        } else {
+            Ok(())
+        };
+        if let Some(parent) = dest.parent() {
+            if let Err(e) = cleared.and_then(|_| fs::create_dir_all(parent)) {
+                return NodeResult {
+                    index: node.index,
+                    node: alias,
+                    exit_code: None,
+                    error: Some(e.to_string()),
+                };
+            }
+        }
+
+        let mut args = scp_args(ssh_config);
+        args.push(format!("{}:{}", alias, remote_path));
+        args.push(dest.display().to_string());
+        let code = remote::run_prefixed(&alias, "scp", &args)
+            .await
+            .unwrap_or(None);
+
+        let is_dir = dest.is_dir();
+        verify(
+            node,
+            code,
+            remote_checksums(ssh_config, &alias, remote_path, is_dir),
+            async { local_checksums(&dest) },
+        )
+        .await
+    }))
+    .buffer_unordered(parallel.max(1))
+    .collect()
+    .await;
+
+    results.sort_by_key(|r| r.index);
+    Ok(results)
+}
+
+#[test]
+fn test_parse_sha256sum_and_compare() {
+    let dir_output = "aaa  ./bin/bench\nbbb  ./config.toml\n";
+    let actual = parse_sha256sum(dir_output, true);
+    assert_eq!(actual.get("bin/bench").map(String::as_str), Some("aaa"));
+
+    let mut expected = Checksums::new();
+    expected.insert("bin/bench".to_string(), "aaa".to_string());
+    expected.insert("config.toml".to_string(), "ccc".to_string());
+    expected.insert("extra".to_string(), "ddd".to_string());
+    assert_eq!(
+        compare_checksums(&expected, &actual).unwrap(),
+        "missing: extra; checksum mismatch: config.toml"
+    );
+
+    let file = parse_sha256sum("eee  /opt/bench\n", false);
+    assert_eq!(compare_checksums(&file, &file), None);
+    assert_eq!(file.get("").map(String::as_str), Some("eee"));
+}
+
+#[test]
+fn test_remote_checksum_command_expands_home() {
+    assert!(remote_checksum_command("~/results").starts_with("if [ -d \"$HOME\"/'results' ]"));
+    assert!(is_protected_path("~/"));
+    assert!(is_protected_path("/"));
+    assert!(!is_protected_path("~/results"));
+}
+
+#[test]
+fn test_push_target() {
+    let local = Path::new("./bench/");
+
+    assert_eq!(push_target("~/work", local, false), "~/work");
+    assert_eq!(push_target("~/work/", local, true), "~/work/bench");
+    assert_eq!(push_target("/", local, true), "/bench");
+}
+
+#[test]
+fn test_local_checksums_of_file_and_dir() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(format!("aws_manager_transfer_{}", std::process::id()));
+    fs::create_dir_all(dir.join("sub"))?;
+    fs::write(dir.join("sub").join("a.txt"), "hello")?;
+
+    let of_dir = local_checksums(&dir)?;
+    let of_file = local_checksums(&dir.join("sub").join("a.txt"))?;
+    fs::remove_dir_all(&dir)?;
+
+    let hello = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    assert_eq!(of_dir.get("sub/a.txt").map(String::as_str), Some(hello));
+    assert_eq!(of_file.get("").map(String::as_str), Some(hello));
+
+    Ok(())
+}
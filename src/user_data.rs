@@ -0,0 +1,320 @@
+use base64::prelude::*;
+use regex::{Captures, Regex};
+use std::fs;
+use std::path::Path;
+
+/// The user-data script shipped with the tool, used when no template is given on the command line.
+pub const DEFAULT_TEMPLATE: &str = include_str!("user_data.sh");
+
+/// EC2 rejects user data larger than this, measured before base64 encoding.
+pub const MAX_USER_DATA_BYTES: usize = 16 * 1024;
+
+/// Instance metadata holding the position of an instance among those its request launched.
+const LAUNCH_INDEX_URL: &str = "http://169.254.169.254/latest/meta-data/ami-launch-index";
+
+/// Instance metadata holding the ID of the reservation (i.e. the request) that launched an instance.
+const RESERVATION_ID_URL: &str = "http://169.254.169.254/latest/meta-data/reservation-id";
+
+/// Boundary used between the parts of a cloud-init multi-part MIME document.
+const MIME_BOUNDARY: &str = "==AWS_MANAGER_USER_DATA_BOUNDARY==";
+
+/// Per-node values substituted into a user-data template.
+///
+/// Templates reference these as `{{ node_index }}`, `{{ cluster_size }}`, `{{ cluster_name }}`,
+/// `{{ head_node_ip }}` and `{{ shared_volume_device }}`. Optional values render as an empty
+/// string when unset (e.g. `head_node_ip` on the head node itself).
+#[derive(Debug, Clone)]
+pub struct NodeVars<'a> {
+    pub node_index: u64,
+    /// The user data is shared by several nodes that one request launches, starting at
+    /// `node_index`. `{{ node_index }}` then renders as a shell expression that adds the node's
+    /// launch index from the instance metadata, so it only works in shell scripts. If the request
+    /// launches the head node too (`node_index` is 0), `{{ head_node_ip }}` likewise renders as a
+    /// lookup of the private IP of launch index 0 in the node's reservation, which takes the AWS
+    /// CLI and permission to describe instances.
+    pub batch: bool,
+    /// The number of nodes the cluster was planned with. It is rendered before the workers launch,
+    /// so with a launch policy that lets some of them fail it can be more than actually came up.
+    pub cluster_size: u64,
+    pub cluster_name: &'a str,
+    pub head_node_ip: Option<&'a str>,
+    pub shared_volume_device: Option<&'a str>,
+}
+
+impl NodeVars<'_> {
+    fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "node_index" if self.batch => Some(format!(
+                "$(( {} + $(curl -s {}) ))",
+                self.node_index, LAUNCH_INDEX_URL
+            )),
+            "node_index" => Some(self.node_index.to_string()),
+            "cluster_size" => Some(self.cluster_size.to_string()),
+            "cluster_name" => Some(self.cluster_name.to_string()),
+            "head_node_ip" if self.batch && self.node_index == 0 => Some(format!(
+                "$(if [ \"$(curl -s {})\" != 0 ]; then aws ec2 describe-instances --filters \
+                 \"Name=reservation-id,Values=$(curl -s {})\" --query \
+                 'Reservations[].Instances[?AmiLaunchIndex==`0`].PrivateIpAddress' --output text; fi)",
+                LAUNCH_INDEX_URL, RESERVATION_ID_URL
+            )),
+            "head_node_ip" => Some(self.head_node_ip.unwrap_or_default().to_string()),
+            "shared_volume_device" => {
+                Some(self.shared_volume_device.unwrap_or_default().to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Load user-data templates from disk, falling back to the built-in script if none are given.
+pub fn load_templates(
+    paths: &[impl AsRef<Path>],
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    if paths.is_empty() {
+        return Ok(vec![DEFAULT_TEMPLATE.to_string()]);
+    }
+
+    let mut templates = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        match fs::read_to_string(path) {
+            Ok(data) => templates.push(data),
+            Err(err) => {
+                return Err(
+                    format!("Failed to read user data file at path {:?}: {}", path, err).into(),
+                )
+            }
+        }
+    }
+
+    Ok(templates)
+}
+
+/// Substitute `{{ variable }}` placeholders in a template.
+///
+/// Shell `${VAR}` syntax is left alone, so scripts can use both. Unknown variables are an error
+/// rather than being silently rendered empty.
+pub fn render(template: &str, vars: &NodeVars) -> Result<String, Box<dyn std::error::Error>> {
+    let re = Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}")?;
+
+    let mut unknown = Vec::new();
+    let rendered = re.replace_all(template, |caps: &Captures| match vars.get(&caps[1]) {
+        Some(value) => value,
+        None => {
+            unknown.push(caps[1].to_string());
+            String::new()
+        }
+    });
+
+    if !unknown.is_empty() {
+        return Err(format!(
+            "Unknown user data template variable(s): {}",
+            unknown.join(", ")
+        )
+        .into());
+    }
+
+    Ok(rendered.into_owned())
+}
+
+/// Guess the cloud-init content type of a part from its first line.
+fn content_type(part: &str) -> &'static str {
+    let first_line = part.lines().next().unwrap_or_default();
+
+    if first_line.starts_with("#cloud-config") {
+        "text/cloud-config"
+    } else if first_line.starts_with("#cloud-boothook") {
+        "text/cloud-boothook"
+    } else if first_line.starts_with("#include") {
+        "text/x-include-url"
+    } else if first_line.starts_with("#part-handler") {
+        "text/part-handler"
+    } else {
+        "text/x-shellscript"
+    }
+}
+
+/// Combine several rendered parts into a cloud-init multi-part MIME document.
+///
+/// A single part is returned unchanged, since cloud-init handles plain scripts and cloud-configs
+/// directly.
+pub fn combine_parts(parts: &[String]) -> String {
+    if parts.len() == 1 {
+        return parts[0].clone();
+    }
+
+    let mut out = format!(
+        "Content-Type: multipart/mixed; boundary=\"{}\"\nMIME-Version: 1.0\n",
+        MIME_BOUNDARY
+    );
+    for part in parts {
+        out.push_str(&mime_part(part));
+    }
+    out.push_str(&format!("\n--{}--\n", MIME_BOUNDARY));
+
+    out
+}
+
+/// One part of a multi-part MIME document, including its leading boundary.
+fn mime_part(part: &str) -> String {
+    format!(
+        "\n--{}\nContent-Type: {}; charset=\"us-ascii\"\nMIME-Version: 1.0\nContent-Transfer-Encoding: 7bit\n\n{}\n",
+        MIME_BOUNDARY,
+        content_type(part),
+        part
+    )
+}
+
+/// Add a part to rendered user data, which may already be a multi-part document from
+/// `combine_parts`.
+pub fn add_part(user_data: &str, part: &str) -> String {
+    let closing = format!("\n--{}--\n", MIME_BOUNDARY);
+    match user_data.strip_suffix(&closing) {
+        Some(body) if user_data.starts_with("Content-Type: multipart/mixed;") => {
+            format!("{}{}{}", body, mime_part(part), closing)
+        }
+        _ => combine_parts(&[user_data.to_string(), part.to_string()]),
+    }
+}
+
+/// A script that shuts the instance down after the given number of minutes.
+///
+/// Instances launched with an expiry terminate on shutdown, so this makes them clean up after
+/// themselves even if nothing reaps them.
+pub fn shutdown_timer(minutes: u64) -> String {
+    format!("#!/bin/bash\nshutdown -h +{}\n", minutes)
+}
+
+/// Render every template for one node and combine them into the final user data.
+pub fn render_for_node(
+    templates: &[String],
+    vars: &NodeVars,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let parts = templates
+        .iter()
+        .map(|t| render(t, vars))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(combine_parts(&parts))
+}
+
+/// Check the size limit and base64-encode user data for `run_instances`.
+///
+/// This is the only place user data gets encoded; everything upstream passes plain text.
+pub fn encode(user_data: &str) -> Result<String, Box<dyn std::error::Error>> {
+    if user_data.len() > MAX_USER_DATA_BYTES {
+        return Err(format!(
+            "User data is {} bytes, which is over the EC2 limit of {} bytes",
+            user_data.len(),
+            MAX_USER_DATA_BYTES
+        )
+        .into());
+    }
+
+    Ok(BASE64_STANDARD.encode(user_data.as_bytes()))
+}
+
+#[cfg(test)]
+fn test_vars() -> NodeVars<'static> {
+    NodeVars {
+        node_index: 2,
+        batch: false,
+        cluster_size: 4,
+        cluster_name: "nccl-test",
+        head_node_ip: Some("10.0.0.4"),
+        shared_volume_device: None,
+    }
+}
+
+#[test]
+fn test_render_substitutes_vars_and_leaves_shell_vars() {
+    let template = "NODE={{ node_index }}/{{cluster_size}} HEAD={{ head_node_ip }} DEV={{ shared_volume_device }} ${HOME}";
+    let rendered = render(template, &test_vars()).unwrap();
+
+    assert_eq!(rendered, "NODE=2/4 HEAD=10.0.0.4 DEV= ${HOME}");
+
+    let batch = NodeVars {
+        batch: true,
+        ..test_vars()
+    };
+    assert_eq!(
+        render("NODE=\"{{ node_index }}\"", &batch).unwrap(),
+        "NODE=\"$(( 2 + $(curl -s http://169.254.169.254/latest/meta-data/ami-launch-index) ))\""
+    );
+    assert_eq!(
+        render("HEAD={{ head_node_ip }}", &batch).unwrap(),
+        "HEAD=10.0.0.4"
+    );
+
+    let with_head = NodeVars {
+        node_index: 0,
+        head_node_ip: None,
+        ..batch
+    };
+    assert_eq!(
+        render("HEAD=\"{{ head_node_ip }}\"", &with_head).unwrap(),
+        "HEAD=\"$(if [ \"$(curl -s http://169.254.169.254/latest/meta-data/ami-launch-index)\" != 0 ]; \
+         then aws ec2 describe-instances --filters \"Name=reservation-id,Values=$(curl -s \
+         http://169.254.169.254/latest/meta-data/reservation-id)\" --query \
+         'Reservations[].Instances[?AmiLaunchIndex==`0`].PrivateIpAddress' --output text; fi)\""
+    );
+}
+
+#[test]
+fn test_default_template_renders() {
+    let rendered = render(DEFAULT_TEMPLATE, &test_vars()).unwrap();
+
+    assert!(rendered.contains("PROJECT_TAG=\"nccl-test\""));
+    assert!(!rendered.contains("{{"));
+}
+
+#[test]
+fn test_render_rejects_unknown_vars() {
+    let err = render("{{ nope }}", &test_vars()).unwrap_err();
+
+    assert!(err.to_string().contains("nope"));
+}
+
+#[test]
+fn test_combine_parts_builds_multipart_mime() {
+    let single = combine_parts(&["#!/bin/bash\necho hi".to_string()]);
+    assert_eq!(single, "#!/bin/bash\necho hi");
+
+    let multi = combine_parts(&[
+        "#cloud-config\npackages: [jq]".to_string(),
+        "#!/bin/bash\necho hi".to_string(),
+    ]);
+    assert!(multi.starts_with("Content-Type: multipart/mixed;"));
+    assert!(multi.contains("Content-Type: text/cloud-config;"));
+    assert!(multi.contains("Content-Type: text/x-shellscript;"));
+    assert!(multi.ends_with(&format!("--{}--\n", MIME_BOUNDARY)));
+}
+
+#[test]
+fn test_add_part_extends_multipart_documents() {
+    let timer = shutdown_timer(90);
+    assert_eq!(timer, "#!/bin/bash\nshutdown -h +90\n");
+
+    let single = add_part("#cloud-config\npackages: [jq]", &timer);
+    assert_eq!(
+        single,
+        combine_parts(&["#cloud-config\npackages: [jq]".to_string(), timer.clone()])
+    );
+
+    let parts = vec![
+        "#cloud-config\npackages: [jq]".to_string(),
+        "#!/bin/bash\necho hi".to_string(),
+    ];
+    let mut all = parts.clone();
+    all.push(timer.clone());
+    assert_eq!(
+        add_part(&combine_parts(&parts), &timer),
+        combine_parts(&all)
+    );
+}
+
+#[test]
+fn test_encode_checks_size_and_encodes_once() {
+    assert_eq!(encode("echo hi").unwrap(), "ZWNobyBoaQ==");
+    assert!(encode(&"x".repeat(MAX_USER_DATA_BYTES + 1)).is_err());
+}
@@ -1,7 +1,14 @@
 #!/usr/bin/env bash
 
 # Configure Script
-PROJECT_TAG="liuyao-msccl-experiments-sc24"
+# Note: The double-brace placeholders below are filled in per node by aws_manager before launch
+PROJECT_TAG="{{ cluster_name }}"
+NODE_INDEX="{{ node_index }}"
+CLUSTER_SIZE="{{ cluster_size }}"
+HEAD_NODE_IP="{{ head_node_ip }}"
+SHARED_VOLUME_DEVICE="{{ shared_volume_device }}"
+echo "[INFO] Node ${NODE_INDEX} of ${CLUSTER_SIZE} in cluster ${PROJECT_TAG} (head node: ${HEAD_NODE_IP:-self}, shared volume: ${SHARED_VOLUME_DEVICE:-none})"
+
 HOSTS_FILE_PATH="/home/ec2-user/hostfile"
 IP_FILE_PATH="/home/ec2-user/ip-addresses.txt"
 